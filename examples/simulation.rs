@@ -0,0 +1,165 @@
+use bevy::{
+    log::{Level, LogPlugin},
+    prelude::*,
+};
+use bevy_htnp::{
+    planning::{goals::Goal, plan_data::TimeSlicedTreeGen},
+    prelude::*,
+};
+
+fn main() {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        // chaining the systems means the agent progresses through its plan as fast as possible
+        HtnPlanningPlugin::new().orchestrate(OrchestrateFor::FasterResponse),
+        LogPlugin {
+            level: Level::DEBUG,
+            filter: "info,bevy_htnp=debug".into(),
+            ..Default::default()
+        },
+    ))
+    .add_systems(
+        Update,
+        (system_open_door, system_goto_b, system_pickup_item)
+            .after(system_handle_agent_state_changes),
+    );
+
+    // same problem space as `basic_htnp`, except now the tasks actually change the world:
+    // - Agent must pick up item
+    // - Agent is in room A
+    // - Item is in room B
+    // - Door A/B is closed
+    let world = WorldState::new()
+        .add("room", "A")
+        .add("door_open", false)
+        .add("item_picked_up", false)
+        .build();
+
+    let mut task_registry = TaskRegistry::new();
+    task_registry.task::<OpenDoor, _>(
+        "open_door",
+        Requirements::new().req_equals("door_open", false).build(),
+        WorldState::new().add("door_open", true).build(),
+        1.,
+    );
+    task_registry.task::<GotoB, _>(
+        "goto_b",
+        Requirements::new()
+            .req_equals("room", "A")
+            .req_equals("door_open", true)
+            .build(),
+        WorldState::new().add("room", "B").build(),
+        1.,
+    );
+    task_registry.task::<PickupItem, _>(
+        "pickup_item",
+        Requirements::new()
+            .req_equals("room", "B")
+            .req_equals("item_picked_up", false)
+            .build(),
+        WorldState::new().add("item_picked_up", true).build(),
+        1.,
+    );
+    app.insert_resource(task_registry);
+
+    let tasks = vec![
+        Task::primitive("open_door"),
+        Task::primitive("goto_b"),
+        Task::primitive("pickup_item"),
+    ];
+    let goal = Goal::new(
+        "Pick up item",
+        Requirements::new()
+            .req_equals("item_picked_up", true)
+            .build(),
+        1.0,
+    );
+
+    let mut agent = HtnAgent::new();
+    for t in tasks.iter() {
+        agent.add_task(t.clone());
+    }
+    agent.add_goal(goal.name.clone(), goal.requires.clone(), goal.utility);
+
+    let entity = app
+        .world_mut()
+        .spawn((
+            agent,
+            HtnAgentWorld(world),
+            TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]),
+        ))
+        .id();
+
+    const MAX_FRAMES: u32 = 30;
+    for frame in 0..MAX_FRAMES {
+        app.update();
+        let agent_world = &app
+            .world()
+            .get::<HtnAgentWorld>(entity)
+            .expect("Agent should always have a world")
+            .0;
+        if goal.requires.validate(agent_world) {
+            info!("Goal '{}' achieved on frame {}", goal.name, frame);
+            break;
+        }
+    }
+
+    let agent_world = &app.world().get::<HtnAgentWorld>(entity).unwrap().0;
+    assert_eq!(agent_world.get("door_open"), Some(true.into()));
+    assert_eq!(agent_world.get("room"), Some("B".into()));
+    assert_eq!(agent_world.get("item_picked_up"), Some(true.into()));
+    info!("Final world: {:#?}", agent_world);
+}
+
+#[derive(Component, Default)]
+struct OpenDoor;
+
+#[derive(Component, Default)]
+struct GotoB;
+
+#[derive(Component, Default)]
+struct PickupItem;
+
+/// Each task system applies its effect to the agent's world and then reports success so the next step of the plan can begin
+fn system_open_door(
+    mut query: Query<(Entity, &mut HtnAgentWorld, &HtnAgentState), With<OpenDoor>>,
+    mut command: Commands,
+) {
+    for (e, mut world, state) in query.iter_mut() {
+        if *state != HtnAgentState::Running {
+            continue;
+        }
+        info!("{} opens the door", e);
+        world.0.insert("door_open", true);
+        command.entity(e).insert(HtnAgentState::Success);
+    }
+}
+
+fn system_goto_b(
+    mut query: Query<(Entity, &mut HtnAgentWorld, &HtnAgentState), With<GotoB>>,
+    mut command: Commands,
+) {
+    for (e, mut world, state) in query.iter_mut() {
+        if *state != HtnAgentState::Running {
+            continue;
+        }
+        info!("{} walks into room B", e);
+        world.0.insert("room", "B");
+        command.entity(e).insert(HtnAgentState::Success);
+    }
+}
+
+fn system_pickup_item(
+    mut query: Query<(Entity, &mut HtnAgentWorld, &HtnAgentState), With<PickupItem>>,
+    mut command: Commands,
+) {
+    for (e, mut world, state) in query.iter_mut() {
+        if *state != HtnAgentState::Running {
+            continue;
+        }
+        info!("{} picks up the item", e);
+        world.0.insert("item_picked_up", true);
+        command.entity(e).insert(HtnAgentState::Success);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::HashSet,
+};
+use serde::Deserialize;
+
+use crate::{
+    data::{Requirements, UniqueName, Variant, WorldState},
+    planning::{
+        goals::Goal,
+        providers::{next_provider_version, GoalProvider, TaskProvider},
+    },
+    tasks::{Task, TaskRegistry},
+};
+
+/// Loads [`HtnDomainAsset`]s from `.htn.ron` files and registers their tasks in the [`TaskRegistry`] as they load, handing agents with a [`HtnDomainProvider`] their tasks and goals.
+/// Requires Bevy's `AssetPlugin`, so it is not part of [`HtnPlanningPlugin`](crate::prelude::HtnPlanningPlugin)
+pub struct HtnAssetPlugin;
+
+impl Plugin for HtnAssetPlugin {
+    fn build(&self, app: &mut App) {
+        use bevy_trait_query::RegisterExt;
+        app.init_asset::<HtnDomainAsset>()
+            .register_asset_loader(HtnDomainLoader)
+            .register_component_as::<dyn TaskProvider, HtnDomainProvider>()
+            .register_component_as::<dyn GoalProvider, HtnDomainProvider>()
+            .add_systems(Update, system_register_loaded_domains);
+    }
+}
+
+/// The marker component for every task defined in an [`HtnDomainAsset`]. As data tasks all share it, task systems should check [`HtnAgentCurrentTask`](crate::execution::HtnAgentCurrentTask) to tell them apart
+#[derive(Component, Default, Debug, Clone, Copy)]
+pub struct DataTask;
+
+/// A world value as written in RON: `true`, `2.5`, or `"B"`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ValueDefinition {
+    Bool(bool),
+    Number(f32),
+    String(String),
+}
+
+impl From<ValueDefinition> for Variant {
+    fn from(value: ValueDefinition) -> Self {
+        match value {
+            ValueDefinition::Bool(b) => b.into(),
+            ValueDefinition::Number(n) => n.into(),
+            ValueDefinition::String(s) => Variant::interned(s),
+        }
+    }
+}
+
+/// A precondition as written in RON, such as `Equals("A")` or `AtLeast(2.0)`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum ConditionDefinition {
+    Equals(ValueDefinition),
+    Greater(ValueDefinition),
+    Less(ValueDefinition),
+    AtLeast(f32),
+    Has,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TaskDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub preconditions: BTreeMap<String, ConditionDefinition>,
+    #[serde(default)]
+    pub postconditions: BTreeMap<String, ValueDefinition>,
+    pub cost: f32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct GoalDefinition {
+    pub name: String,
+    pub requires: BTreeMap<String, ConditionDefinition>,
+    pub utility: f32,
+}
+
+/// Tasks and goals defined as data. Tasks are registered with the [`DataTask`] marker
+#[derive(Asset, TypePath, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct HtnDomainAsset {
+    #[serde(default)]
+    pub tasks: Vec<TaskDefinition>,
+    #[serde(default)]
+    pub goals: Vec<GoalDefinition>,
+}
+
+impl HtnDomainAsset {
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, HtnDomainLoadError> {
+        Ok(ron::de::from_bytes(bytes)?)
+    }
+
+    /// Registers every task, replacing any registered tasks of the same name
+    pub fn register_tasks(&self, registry: &mut TaskRegistry) {
+        for task in self.tasks.iter() {
+            let mut postconditions = WorldState::new();
+            for (key, value) in task.postconditions.iter() {
+                postconditions.insert(UniqueName::from_string(key.clone()), value.clone());
+            }
+            registry.task::<DataTask, _>(
+                task.name.clone(),
+                requirements(&task.preconditions),
+                postconditions,
+                task.cost,
+            );
+        }
+    }
+
+    /// The defined tasks, for handing to agents
+    pub fn primitive_tasks(&self) -> Vec<Task> {
+        self.tasks
+            .iter()
+            .map(|task| Task::primitive(task.name.clone()))
+            .collect()
+    }
+
+    pub fn goals(&self) -> Vec<Goal> {
+        self.goals
+            .iter()
+            .map(|goal| {
+                Goal::new(
+                    goal.name.clone(),
+                    requirements(&goal.requires),
+                    goal.utility,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Provides the tasks and goals of an [`HtnDomainAsset`] to an agent. They are filled in by [`system_register_loaded_domains`] once the asset loads, and again whenever it is modified
+#[derive(Component)]
+pub struct HtnDomainProvider {
+    pub handle: Handle<HtnDomainAsset>,
+    tasks: Vec<Task>,
+    goals: Vec<Goal>,
+    version: u64,
+}
+
+impl HtnDomainProvider {
+    pub fn new(handle: Handle<HtnDomainAsset>) -> Self {
+        Self {
+            handle,
+            tasks: Vec::new(),
+            goals: Vec::new(),
+            version: next_provider_version(),
+        }
+    }
+
+    fn fill(&mut self, domain: &HtnDomainAsset) {
+        self.tasks = domain.primitive_tasks();
+        self.goals = domain.goals();
+        self.version = next_provider_version();
+    }
+}
+
+impl TaskProvider for HtnDomainProvider {
+    fn tasks(&self) -> Vec<Task> {
+        self.tasks.clone()
+    }
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl GoalProvider for HtnDomainProvider {
+    fn goals(&self) -> Vec<Goal> {
+        self.goals.clone()
+    }
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+fn requirements(conditions: &BTreeMap<String, ConditionDefinition>) -> Requirements {
+    let mut requirements = Requirements::new();
+    for (key, condition) in conditions.iter() {
+        let key = UniqueName::from_string(key.clone());
+        match condition.clone() {
+            ConditionDefinition::Equals(value) => requirements.req_equals(key, value),
+            ConditionDefinition::Greater(value) => requirements.req_greater(key, value),
+            ConditionDefinition::Less(value) => requirements.req_less(key, value),
+            ConditionDefinition::AtLeast(amount) => requirements.req_at_least(key, amount),
+            ConditionDefinition::Has => requirements.req_has(key),
+        };
+    }
+    requirements
+}
+
+#[derive(Debug)]
+pub enum HtnDomainLoadError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl Display for HtnDomainLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HtnDomainLoadError::Io(e) => write!(f, "failed to read HTN domain: {e}"),
+            HtnDomainLoadError::Ron(e) => write!(f, "failed to parse HTN domain: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for HtnDomainLoadError {}
+
+impl From<std::io::Error> for HtnDomainLoadError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ron::error::SpannedError> for HtnDomainLoadError {
+    fn from(value: ron::error::SpannedError) -> Self {
+        Self::Ron(value)
+    }
+}
+
+#[derive(Default)]
+pub struct HtnDomainLoader;
+
+impl AssetLoader for HtnDomainLoader {
+    type Asset = HtnDomainAsset;
+    type Settings = ();
+    type Error = HtnDomainLoadError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        HtnDomainAsset::from_ron(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["htn.ron"]
+    }
+}
+
+/// Registers the tasks of each [`HtnDomainAsset`] once it loads, and again whenever it is modified, filling in every [`HtnDomainProvider`] of the asset.
+/// Providers added after their asset has loaded are filled in straight away
+pub fn system_register_loaded_domains(
+    mut events: EventReader<AssetEvent<HtnDomainAsset>>,
+    assets: Res<Assets<HtnDomainAsset>>,
+    mut registry: ResMut<TaskRegistry>,
+    mut providers: Query<&mut HtnDomainProvider>,
+) {
+    let mut loaded = HashSet::new();
+    for event in events.read() {
+        let (AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id }) = event
+        else {
+            continue;
+        };
+        if let Some(domain) = assets.get(*id) {
+            domain.register_tasks(&mut registry);
+            loaded.insert(*id);
+        }
+    }
+    for mut provider in providers.iter_mut() {
+        let id = provider.handle.id();
+        if !provider.is_added() && !loaded.contains(&id) {
+            continue;
+        }
+        if let Some(domain) = assets.get(id) {
+            provider.fill(domain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planning::plan_data::plan_batch;
+
+    const ROOMS: &str = r#"(
+        tasks: [
+            (
+                name: "open_door",
+                preconditions: { "door_open": Equals(false) },
+                postconditions: { "door_open": true },
+                cost: 1.0,
+            ),
+            (
+                name: "goto_b",
+                preconditions: { "room": Equals("A"), "door_open": Equals(true) },
+                postconditions: { "room": "B" },
+                cost: 1,
+            ),
+            (
+                name: "pickup_item",
+                preconditions: { "room": Equals("B"), "item_picked_up": Equals(false) },
+                postconditions: { "item_picked_up": true },
+                cost: 1.0,
+            ),
+        ],
+        goals: [
+            (name: "Pick up item", requires: { "item_picked_up": Equals(true) }, utility: 1.0),
+        ],
+    )"#;
+
+    #[test]
+    fn room_domain_from_ron() {
+        let domain = HtnDomainAsset::from_ron(ROOMS.as_bytes()).unwrap();
+        assert_eq!(domain.tasks.len(), 3);
+        assert_eq!(domain.tasks[1].cost, 1.0);
+
+        let mut registry = TaskRegistry::new();
+        domain.register_tasks(&mut registry);
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .add("item_picked_up", false)
+            .build();
+        let goal = domain.goals().remove(0);
+        let plans = plan_batch(
+            &registry,
+            &[(world, domain.primitive_tasks(), goal)],
+            Some(8),
+        );
+        assert_eq!(
+            plans[0].as_ref().unwrap().execution_order(),
+            vec!["open_door", "goto_b", "pickup_item"]
+        );
+
+        assert!(HtnDomainAsset::from_ron(b"(tasks: [(name: \"oops\")])").is_err());
+    }
+
+    #[test]
+    fn domain_loaded_through_asset_server() {
+        use crate::planning::{
+            system_collect_agent_goals_from_providers, system_collect_agent_tasks_from_providers,
+            HtnAgent,
+        };
+        use bevy::asset::io::{
+            memory::{Dir, MemoryAssetReader},
+            AssetSource,
+        };
+        use std::{path::Path, time::Duration};
+
+        let dir = Dir::default();
+        dir.insert_asset_text(Path::new("rooms.htn.ron"), ROOMS);
+        let mut app = App::new();
+        app.register_asset_source(
+            "memory",
+            AssetSource::build()
+                .with_reader(move || Box::new(MemoryAssetReader { root: dir.clone() })),
+        );
+        app.add_plugins((MinimalPlugins, AssetPlugin::default(), HtnAssetPlugin));
+        app.init_resource::<TaskRegistry>();
+        app.add_systems(
+            Update,
+            (
+                system_collect_agent_tasks_from_providers,
+                system_collect_agent_goals_from_providers,
+            )
+                .after(system_register_loaded_domains),
+        );
+        let handle = app
+            .world()
+            .resource::<AssetServer>()
+            .load("memory://rooms.htn.ron");
+        let agent = app
+            .world_mut()
+            .spawn((HtnAgent::default(), HtnDomainProvider::new(handle)))
+            .id();
+
+        for _ in 0..200 {
+            app.update();
+            if !app.world().get::<HtnAgent>(agent).unwrap().goals.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let registry = app.world().resource::<TaskRegistry>();
+        assert!(registry.get_named(&"goto_b".to_string()).is_some());
+        let agent = app.world().get::<HtnAgent>(agent).unwrap();
+        assert_eq!(
+            agent.available_tasks,
+            ["goto_b", "open_door", "pickup_item"].map(Task::primitive)
+        );
+        assert_eq!(agent.goals.len(), 1);
+        assert_eq!(agent.goals[0].name, "Pick up item");
+    }
+}
@@ -1,61 +1,350 @@
 use bevy::prelude::*;
+
 use std::{
+    cell::Cell,
     cmp::Ordering,
     collections::HashMap,
-    sync::{Arc, LazyLock, Mutex},
+    hash::{Hash, Hasher},
+    ops::BitAnd,
+    sync::{Arc, LazyLock, Mutex, MutexGuard},
     time::Duration,
 };
 
 pub(crate) fn plugin(app: &mut App) {
     app.insert_resource(HtnSettings::default());
+    #[cfg(feature = "bevy_reflect")]
+    app.register_type::<UniqueName>()
+        .register_type::<Variant>()
+        .register_type::<WorldState>()
+        .register_type::<MergeStrategy>()
+        .register_type::<Predicate>()
+        .register_type::<Requirements>()
+        .register_type::<HtnSettings>();
 }
 
 type UniqueNameStorage = &'static str;
 pub static UNIQUE_NAME_REGISTRY: LazyLock<Mutex<HashMap<String, Arc<UniqueNameStorage>>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
+/// Locks the name registry, recovering from poisoning. A panic while the lock was held can't leave the map half-updated, so it is always safe to keep using
+fn lock_unique_names() -> MutexGuard<'static, HashMap<String, Arc<UniqueNameStorage>>> {
+    UNIQUE_NAME_REGISTRY.lock().unwrap_or_else(|poisoned| {
+        UNIQUE_NAME_REGISTRY.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Names registered as enum values, indexed by their id. See [`Variant::register_enum`]
+static ENUM_REGISTRY: LazyLock<Mutex<Vec<String>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+fn lock_enum_names() -> MutexGuard<'static, Vec<String>> {
+    ENUM_REGISTRY.lock().unwrap_or_else(|poisoned| {
+        ENUM_REGISTRY.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+thread_local! {
+    /// The tolerance used by [`Predicate::Equals`] when comparing numbers. Set from [`HtnSettings::number_epsilon`] while planning and by every system which validates requirements
+    static NUMBER_EPSILON: Cell<f32> = const { Cell::new(0.0) };
+}
+
+/// Runs `f` with [`Predicate::Equals`] treating numbers within `epsilon` of each other as equal. An epsilon of 0.0 means exact comparison
+pub fn with_number_epsilon<R>(epsilon: f32, f: impl FnOnce() -> R) -> R {
+    let _guard = NumberEpsilonGuard::new(epsilon);
+    f()
+}
+
+/// Sets the tolerance [`Predicate::Equals`] compares numbers with on this thread, restoring the previous one when dropped (even by a panic)
+#[must_use]
+pub struct NumberEpsilonGuard {
+    previous: f32,
+}
+
+impl NumberEpsilonGuard {
+    pub fn new(epsilon: f32) -> Self {
+        Self {
+            previous: NUMBER_EPSILON.replace(epsilon),
+        }
+    }
+
+    /// Uses [`HtnSettings::number_epsilon`], or exact comparison when there are no settings
+    pub fn from_settings(settings: Option<&HtnSettings>) -> Self {
+        Self::new(settings.map_or(0.0, |s| s.number_epsilon))
+    }
+}
+
+impl Drop for NumberEpsilonGuard {
+    fn drop(&mut self) {
+        NUMBER_EPSILON.set(self.previous);
+    }
+}
+
 #[derive(Clone, PartialEq, PartialOrd, Debug, Eq, Hash)]
+// the interned Arc can't be reflected field-by-field, so names are reflected as opaque values
+#[cfg_attr(
+    feature = "bevy_reflect",
+    derive(Reflect),
+    reflect_value(Debug, PartialEq, Hash)
+)]
 pub struct UniqueName(Arc<UniqueNameStorage>);
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "bevy_reflect", derive(Reflect))]
 pub enum Variant {
     Bool(bool),
     String(UniqueName),
     Number(f32),
+    /// A value registered with [`Variant::register_enum`]. Compared by id, so unlike strings these are cheap to compare and misspelled names are caught when looked up
+    Enum(u32),
 }
 
 #[derive(Default, Clone, Debug, PartialEq, Resource)]
+#[cfg_attr(feature = "bevy_reflect", derive(Reflect), reflect(Resource))]
 /// For an HTN, a context is simply a collection of known 'predicate's.
 pub struct WorldState {
     entries: HashMap<UniqueName, Variant>,
+    /// How entries are merged when this world is appended onto another. Entries not listed here overwrite
+    merge_strategies: HashMap<UniqueName, MergeStrategy>,
+    /// When entries expire, measured as time elapsed since startup (see [`Time::elapsed`]). Entries not listed here never expire
+    expiries: HashMap<UniqueName, Duration>,
+}
+
+/// The type of a [`Variant`], without its value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VariantKind {
+    Bool,
+    String,
+    Number,
+    Enum,
+}
+
+/// The expected [`VariantKind`] of world keys, used to catch values of the wrong type being written (e.g. `add("hunger", true)` when hunger is a number).
+/// Keys without an expectation accept any value. See [`WorldState::insert_checked`]
+#[derive(Resource, Default, Clone, Debug)]
+pub struct WorldSchema {
+    kinds: HashMap<UniqueName, VariantKind>,
+}
+
+impl WorldSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn expect(&mut self, key: impl Into<UniqueName>, kind: VariantKind) -> &mut Self {
+        self.kinds.insert(key.into(), kind);
+        self
+    }
+
+    pub fn build(&mut self) -> Self {
+        self.clone()
+    }
+
+    pub fn kind_of(&self, key: &UniqueName) -> Option<VariantKind> {
+        self.kinds.get(key).copied()
+    }
+
+    pub fn check(&self, key: &UniqueName, value: &Variant) -> Result<(), SchemaMismatch> {
+        match self.kind_of(key) {
+            Some(expected) if expected != value.kind() => Err(SchemaMismatch {
+                key: key.clone(),
+                expected,
+                actual: value.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every entry of the world that doesn't match the schema
+    pub fn validate(&self, world: &WorldState) -> Vec<SchemaMismatch> {
+        world
+            .entries
+            .iter()
+            .filter_map(|(key, value)| self.check(key, value).err())
+            .collect()
+    }
+}
+
+/// A value written to the world which doesn't match the type the [`WorldSchema`] expects for its key
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaMismatch {
+    pub key: UniqueName,
+    pub expected: VariantKind,
+    pub actual: Variant,
+}
+
+impl std::fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "World key '{}' expects a {:?} but was given {:?}",
+            self.key.as_str(),
+            self.expected,
+            self.actual
+        )
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "bevy_reflect", derive(Reflect))]
+/// How a single entry is applied when one world is appended onto another, such as when applying a task's postconditions
+pub enum MergeStrategy {
+    /// the value replaces any existing value
+    #[default]
+    Overwrite,
+    /// the value is added to an existing `Number` value, allowing for effects that increment or decrement counters. Missing entries are treated as `0.0`
+    SumNumbers,
+}
+
+/// How the cost of each step is folded into the total cost of a plan, see [`HtnSettings::cost_aggregation`]
+#[derive(Default, Clone, Copy, Debug)]
+pub enum CostAgg {
+    /// the plan costs the total of its steps
+    #[default]
+    Sum,
+    /// the plan costs as much as its most expensive step, favouring plans without a bottleneck
+    Max,
+    /// called with the cost so far and the cost of the next step, returning the new cost so far. The first step is folded onto `0.0`
+    Custom(fn(f32, f32) -> f32),
+}
+
+/// Custom aggregations are all considered equal, for the same reason as [`HtnSettings`]' comparator
+impl PartialEq for CostAgg {
+    fn eq(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl CostAgg {
+    pub fn aggregate(&self, total: f32, step: f32) -> f32 {
+        match self {
+            CostAgg::Sum => total + step,
+            CostAgg::Max => total.max(step),
+            CostAgg::Custom(f) => f(total, step),
+        }
+    }
 }
 
 #[derive(Default, Clone, Debug, PartialEq)]
+// `std::cmp::Ordering` isn't reflectable, so predicates are reflected as opaque values
+#[cfg_attr(
+    feature = "bevy_reflect",
+    derive(Reflect),
+    reflect_value(Debug, PartialEq)
+)]
 pub enum Predicate {
     #[default]
     HasEntry,
     Equals(Variant),
     /// uses partialeq (or totaleq for Number) to compare. Returns true if the comparison Ordering matches the stored Ordering
     Order(Ordering, Variant),
+    /// Like `Equals`, but values of different types are coerced before comparing (see [`Variant::coerced_eq`]). Must be opted into explicitly.
+    EqualsCoerced(Variant),
+    /// The value must be a number no less than this, within [`HtnSettings::number_epsilon`]. Pairs with [`WorldState::consume_amount`] for capacities and other spendable resources
+    AtLeast(f32),
 }
 
 #[derive(Default, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "bevy_reflect", derive(Reflect))]
 pub struct Requirements {
     entries: HashMap<UniqueName, Predicate>,
 }
 
-#[derive(Default, Clone, Debug, PartialEq, Resource)]
+#[derive(Clone, Debug, PartialEq)]
+/// The reason a single predicate of a [`Requirements`] was not met by a world
+pub enum PredicateFailure {
+    /// The world has no entry for the required key
+    MissingKey,
+    /// The world has an entry for the key, but the value does not satisfy the predicate
+    Mismatch {
+        expected: Predicate,
+        actual: Variant,
+    },
+}
+
+#[derive(Default, Clone, Debug, Resource)]
+#[cfg_attr(feature = "bevy_reflect", derive(Reflect), reflect(Resource))]
 pub struct HtnSettings {
     pub frame_processing_limit: Option<Duration>,
     pub node_branch_limit: Option<u32>,
     pub disable_priority_sort: Option<bool>,
+    /// Orders agents for plan extraction by their [`HtnAgentPlanningPriority`](crate::planning::HtnAgentPlanningPriority), which is 0.0 for agents without one.
+    /// Defaults to the highest priority first
+    #[cfg_attr(feature = "bevy_reflect", reflect(ignore))]
+    pub priority_comparator: Option<fn(f32, f32) -> Ordering>,
+    /// The maximum number of agents that can be given a new plan each frame. Agents are given plans in priority order
+    pub plan_extraction_limit: Option<u32>,
+    /// When set, each plan node's cost is increased by `weight * unmet goal requirements`, biasing the search towards branches that progress the goal
+    pub goal_distance_weight: Option<f32>,
+    /// Caps the number of open nodes in a search, evicting the most expensive ones when exceeded. This bounds memory use at the cost of possibly missing plans
+    pub max_open_nodes: Option<usize>,
+    /// Numbers within this distance of each other are considered equal by [`Predicate::Equals`] while planning. Defaults to 0.0 for exact comparison
+    pub number_epsilon: f32,
+    /// When enabled, plan steps whose effects are already present in the world when they come up are skipped as if they had succeeded instantly
+    pub skip_satisfied_tasks: Option<bool>,
+    /// When enabled, a new plan is rejected before it starts if its first task's preconditions no longer hold, as the world may have changed since the plan was made
+    pub validate_plan_start: Option<bool>,
+    /// When set, random goal choices are made with an [`HtnAgentRng`](crate::planning::goals::HtnAgentRng) seeded from this and the agent's entity, so they are reproducible per agent
+    pub rng_seed: Option<u64>,
+    /// Plans never pass through a world that meets any of these, such as one where `on_fire` is true. Only the world after each step is checked, so the steps inside a macro may still pass through them
+    pub forbidden_states: Vec<Requirements>,
+    /// When set, an agent abandons its plan once its next goal's utility exceeds that of the goal being executed by more than this margin, see [`system_preempt_plans`](crate::planning::goals::system_preempt_plans)
+    pub preempt_margin: Option<f32>,
+    /// How step costs are combined into a plan's cost. Defaults to [`CostAgg::Sum`]
+    #[cfg_attr(feature = "bevy_reflect", reflect(ignore))]
+    pub cost_aggregation: CostAgg,
+    /// When enabled, plans with fewer steps win whenever costs tie, and the search expands shallower nodes first so short plans are found early.
+    /// Suits domains where most tasks cost the same
+    pub prefer_shorter_plans: Option<bool>,
+    /// When enabled and a search finds no plan for a goal, the path to the node closest to meeting it (fewest unmet requirements) is stored as a partial plan instead, see [`Plan::partial`](crate::planning::plan_data::Plan::partial).
+    /// Only nodes that get closer to the goal than the starting world count. A goal with a fallback goal has the fallback searched instead
+    pub partial_plans: Option<bool>,
+}
+
+/// Function pointers can't be compared reliably, as identical functions may be merged and one function may have several addresses.
+/// So `priority_comparator` is only compared by whether it is set, and likewise [`CostAgg::Custom`]
+impl PartialEq for HtnSettings {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            frame_processing_limit,
+            node_branch_limit,
+            disable_priority_sort,
+            priority_comparator,
+            plan_extraction_limit,
+            goal_distance_weight,
+            max_open_nodes,
+            number_epsilon,
+            skip_satisfied_tasks,
+            validate_plan_start,
+            rng_seed,
+            forbidden_states,
+            preempt_margin,
+            cost_aggregation,
+            prefer_shorter_plans,
+            partial_plans,
+        } = self;
+        *frame_processing_limit == other.frame_processing_limit
+            && *node_branch_limit == other.node_branch_limit
+            && *disable_priority_sort == other.disable_priority_sort
+            && priority_comparator.is_some() == other.priority_comparator.is_some()
+            && *plan_extraction_limit == other.plan_extraction_limit
+            && *goal_distance_weight == other.goal_distance_weight
+            && *max_open_nodes == other.max_open_nodes
+            && *number_epsilon == other.number_epsilon
+            && *skip_satisfied_tasks == other.skip_satisfied_tasks
+            && *validate_plan_start == other.validate_plan_start
+            && *rng_seed == other.rng_seed
+            && *forbidden_states == other.forbidden_states
+            && *preempt_margin == other.preempt_margin
+            && *cost_aggregation == other.cost_aggregation
+            && *prefer_shorter_plans == other.prefer_shorter_plans
+            && *partial_plans == other.partial_plans
+    }
 }
 
 impl UniqueName {
     pub fn new(string: &'static str) -> Self {
-        let mut lock = UNIQUE_NAME_REGISTRY
-            .lock()
-            .expect("Propagating mutex thread panic");
+        let mut lock = lock_unique_names();
 
         Self(
             lock.entry(string.into())
@@ -63,6 +352,81 @@ impl UniqueName {
                 .clone(),
         )
     }
+
+    /// Interns a batch of names up front, so later calls to [`UniqueName::new`] for them only need a lookup
+    pub fn prewarm(names: &[&'static str]) {
+        let mut lock = lock_unique_names();
+        for name in names {
+            lock.entry((*name).into()).or_insert(Arc::new(name));
+        }
+    }
+
+    /// Interns a name that is only known at runtime. Each distinct name is leaked once so it can live in the registry for the rest of the program
+    pub fn from_string(string: String) -> Self {
+        let mut lock = lock_unique_names();
+        if let Some(existing) = lock.get(&string) {
+            return Self(existing.clone());
+        }
+        let leaked: &'static str = Box::leak(string.clone().into_boxed_str());
+        Self(lock.entry(string).or_insert(Arc::new(leaked)).clone())
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        *self.0
+    }
+}
+
+impl Variant {
+    /// Loose equality across variant types. Same-typed values compare as normal, otherwise:
+    /// - `Bool` and `Number`: `false` is `0.0` and `true` is `1.0`
+    /// - `Bool` and `String`: the string must be `"true"` or `"false"`
+    /// - `Number` and `String`: the string is parsed as a number
+    /// - `Enum` and `String`: the string must be the enum value's registered name
+    pub fn coerced_eq(&self, other: &Variant) -> bool {
+        match (self, other) {
+            (Variant::Bool(b), Variant::Number(n)) | (Variant::Number(n), Variant::Bool(b)) => {
+                *n == if *b { 1.0 } else { 0.0 }
+            }
+            (Variant::Bool(b), Variant::String(s)) | (Variant::String(s), Variant::Bool(b)) => {
+                s.as_str().parse::<bool>().is_ok_and(|parsed| parsed == *b)
+            }
+            (Variant::Number(n), Variant::String(s)) | (Variant::String(s), Variant::Number(n)) => {
+                s.as_str().parse::<f32>().is_ok_and(|parsed| parsed == *n)
+            }
+            (Variant::Enum(id), Variant::String(s)) | (Variant::String(s), Variant::Enum(id)) => {
+                lock_enum_names().get(*id as usize).map(String::as_str) == Some(s.as_str())
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Registers a name as an enum value, returning it. Ids are assigned in registration order, and registering a name again returns the existing value
+    pub fn register_enum(name: &str) -> Variant {
+        let mut names = lock_enum_names();
+        if let Some(id) = names.iter().position(|n| n == name) {
+            return Variant::Enum(id as u32);
+        }
+        names.push(name.to_string());
+        Variant::Enum((names.len() - 1) as u32)
+    }
+
+    /// Looks up a registered enum value by name. Unregistered names (such as typos) are reported and return `None`
+    pub fn enum_named(name: &str) -> Option<Variant> {
+        let names = lock_enum_names();
+        let Some(id) = names.iter().position(|n| n == name) else {
+            warn!("'{}' is not a registered enum value", name);
+            return None;
+        };
+        Some(Variant::Enum(id as u32))
+    }
+
+    /// The registered name of an enum value
+    pub fn enum_name(&self) -> Option<String> {
+        let Variant::Enum(id) = self else {
+            return None;
+        };
+        lock_enum_names().get(*id as usize).cloned()
+    }
 }
 
 impl WorldState {
@@ -79,20 +443,147 @@ impl WorldState {
         self.clone()
     }
 
+    /// Like [`WorldState::insert`], but checks the value against the schema first (if there is one), refusing to write a value of the wrong type.
+    /// Pass `Option<Res<WorldSchema>>` straight through with `.as_deref()`, so the check costs nothing when no schema is installed
+    pub fn insert_checked(
+        &mut self,
+        key: impl Into<UniqueName>,
+        value: impl Into<Variant>,
+        schema: Option<&WorldSchema>,
+    ) -> Result<Option<Variant>, SchemaMismatch> {
+        let key = key.into();
+        let value = value.into();
+        if let Some(schema) = schema {
+            if let Err(mismatch) = schema.check(&key, &value) {
+                warn!("{}", mismatch);
+                return Err(mismatch);
+            }
+        }
+        Ok(self.insert(key, value))
+    }
+
     pub fn insert(
         &mut self,
         key: impl Into<UniqueName>,
         value: impl Into<Variant>,
     ) -> Option<Variant> {
-        self.entries.insert(key.into(), value.into())
+        let key = key.into();
+        self.merge_strategies.remove(&key);
+        self.expiries.remove(&key);
+        self.entries.insert(key, value.into())
+    }
+
+    /// Inserts an entry which is removed by [`system_expire_world_entries`](crate::execution::system_expire_world_entries) once `expires_at` (as in [`Time::elapsed`]) has passed.
+    /// Inserting the key again without an expiry makes it permanent
+    pub fn insert_expiring(
+        &mut self,
+        key: impl Into<UniqueName>,
+        value: impl Into<Variant>,
+        expires_at: Duration,
+    ) -> Option<Variant> {
+        let key = key.into();
+        let previous = self.insert(key.clone(), value);
+        self.expiries.insert(key, expires_at);
+        previous
+    }
+
+    pub fn expiry(&self, key: impl Into<UniqueName>) -> Option<Duration> {
+        self.expiries.get(&key.into()).copied()
+    }
+
+    /// Whether any entry has expired by `now`
+    pub fn has_expired(&self, now: Duration) -> bool {
+        self.expiries.values().any(|at| *at <= now)
+    }
+
+    /// Removes every entry that has expired by `now`, returning their keys
+    pub fn remove_expired(&mut self, now: Duration) -> Vec<UniqueName> {
+        let expired: Vec<UniqueName> = self
+            .expiries
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired.iter() {
+            self.erase(key.clone());
+        }
+        expired
+    }
+
+    /// Adds an entry that is merged with the given strategy when this world is appended onto another
+    pub fn add_merged(
+        &mut self,
+        key: impl Into<UniqueName>,
+        value: impl Into<Variant>,
+        strategy: MergeStrategy,
+    ) -> &mut Self {
+        let key = key.into();
+        self.entries.insert(key.clone(), value.into());
+        self.merge_strategies.insert(key, strategy);
+        self
+    }
+
+    /// An effect that increases a number by `amount` when appended onto another world
+    pub fn increase(&mut self, key: impl Into<UniqueName>, amount: f32) -> &mut Self {
+        self.add_merged(key, amount, MergeStrategy::SumNumbers)
+    }
+
+    /// An effect that decreases a number by `amount` when appended onto another world
+    pub fn subtract(&mut self, key: impl Into<UniqueName>, amount: f32) -> &mut Self {
+        self.add_merged(key, -amount, MergeStrategy::SumNumbers)
+    }
+
+    /// An effect that uses up `amount` of a resource. Combined with a [`Requirements::req_at_least`] precondition for the same amount,
+    /// the planner will never spend more of the resource than there is
+    pub fn consume_amount(&mut self, key: impl Into<UniqueName>, amount: f32) -> &mut Self {
+        self.subtract(key, amount)
+    }
+
+    pub fn merge_strategy(&self, key: impl Into<UniqueName>) -> MergeStrategy {
+        self.merge_strategies
+            .get(&key.into())
+            .copied()
+            .unwrap_or_default()
     }
 
     pub fn erase(&mut self, key: impl Into<UniqueName>) {
-        self.entries.remove(&key.into());
+        let key = key.into();
+        self.merge_strategies.remove(&key);
+        self.expiries.remove(&key);
+        self.entries.remove(&key);
     }
 
     pub fn clear(&mut self) {
         self.entries.clear();
+        self.merge_strategies.clear();
+        self.expiries.clear();
+    }
+
+    /// Moves the entry (along with its merge strategy and expiry) from one key to another, replacing anything already at `to`.
+    /// Returns false, leaving the world untouched, if there is no entry at `from`
+    pub fn rename_key(&mut self, from: impl Into<UniqueName>, to: impl Into<UniqueName>) -> bool {
+        let (from, to) = (from.into(), to.into());
+        let Some(value) = self.entries.remove(&from) else {
+            return false;
+        };
+        let strategy = self.merge_strategies.remove(&from);
+        let expiry = self.expiries.remove(&from);
+        self.insert(to.clone(), value);
+        if let Some(strategy) = strategy {
+            self.merge_strategies.insert(to.clone(), strategy);
+        }
+        if let Some(expiry) = expiry {
+            self.expiries.insert(to, expiry);
+        }
+        true
+    }
+
+    /// Applies each rename in order with [`WorldState::rename_key`], such as when loading a save made with older key names. Returns how many keys were renamed
+    pub fn migrate<K: Into<UniqueName> + Clone>(&mut self, renames: &[(K, K)]) -> usize {
+        renames
+            .iter()
+            .filter(|(from, to)| self.rename_key(from.clone(), to.clone()))
+            .count()
     }
 
     /// ensure that the other world's set of truths is a subset of this World's truths.
@@ -109,14 +600,65 @@ impl WorldState {
         true
     }
 
+    pub fn keys(&self) -> impl Iterator<Item = &UniqueName> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every key starting with `prefix` (e.g. all `carrying_*` keys), sorted by name
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<UniqueName> {
+        let mut keys: Vec<UniqueName> = self
+            .entries
+            .keys()
+            .filter(|k| k.as_str().starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort_by_key(|k| k.as_str());
+        keys
+    }
+
+    /// Like [`WorldState::keys_with_prefix`], but includes each key's value
+    pub fn entries_with_prefix(&self, prefix: &str) -> Vec<(UniqueName, Variant)> {
+        self.keys_with_prefix(prefix)
+            .into_iter()
+            .map(|k| {
+                let value = self.entries[&k].clone();
+                (k, value)
+            })
+            .collect()
+    }
+
     pub fn get(&self, s: impl Into<UniqueName>) -> Option<Variant> {
         let value = self.entries.get(&s.into())?;
         Some(value.clone())
     }
 
+    /// Applies the entries of the other world onto this one, respecting the other world's [`MergeStrategy`] for each entry.
+    /// Each entry keeps the other world's expiry, so entries which don't expire there are made permanent here
     pub fn append(&mut self, other: &WorldState) {
         for (name, truth) in &other.entries {
-            self.entries.insert(name.clone(), truth.clone());
+            let merged = match (other.merge_strategy(name.clone()), truth) {
+                (MergeStrategy::SumNumbers, Variant::Number(delta)) => {
+                    match self.entries.get(name) {
+                        Some(Variant::Number(base)) => Variant::Number(base + delta),
+                        _ => Variant::Number(*delta),
+                    }
+                }
+                _ => truth.clone(),
+            };
+            self.merge_strategies.remove(name);
+            match other.expiries.get(name) {
+                Some(at) => self.expiries.insert(name.clone(), *at),
+                None => self.expiries.remove(name),
+            };
+            self.entries.insert(name.clone(), merged);
         }
     }
 
@@ -125,6 +667,76 @@ impl WorldState {
         n_world.append(other);
         n_world
     }
+
+    /// Creates a copy of this world with every key passed through `f`, keeping values and merge strategies
+    pub(crate) fn map_keys(&self, f: impl Fn(&UniqueName) -> UniqueName) -> WorldState {
+        WorldState {
+            entries: self
+                .entries
+                .iter()
+                .map(|(key, value)| (f(key), value.clone()))
+                .collect(),
+            merge_strategies: self
+                .merge_strategies
+                .iter()
+                .map(|(key, strategy)| (f(key), *strategy))
+                .collect(),
+            expiries: self
+                .expiries
+                .iter()
+                .map(|(key, at)| (f(key), *at))
+                .collect(),
+        }
+    }
+}
+
+/// A world made of stacked layers, where each layer overrides the keys of those below it. Lookups check the layers from the top down,
+/// so layers can be shared (e.g. the global world under each agent's world) without copying them as [`WorldState::concat`] would.
+/// Merge strategies are not applied between layers, the topmost value always wins
+#[derive(Default, Clone, Debug)]
+pub struct LayeredWorldState {
+    /// ordered from the base layer to the topmost overlay
+    pub layers: Vec<Arc<WorldState>>,
+}
+
+impl LayeredWorldState {
+    pub fn new(base: Arc<WorldState>) -> Self {
+        Self { layers: vec![base] }
+    }
+
+    /// Adds a layer on top, overriding every layer beneath it
+    pub fn with_layer(mut self, layer: Arc<WorldState>) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    pub fn get(&self, s: impl Into<UniqueName>) -> Option<Variant> {
+        let key = s.into();
+        self.layers
+            .iter()
+            .rev()
+            .find_map(|layer| layer.entries.get(&key))
+            .cloned()
+    }
+
+    /// Like [`WorldState::validate`], checking that the other world's entries are a subset of this world's resolved entries
+    pub fn validate(&self, other: &WorldState) -> bool {
+        other
+            .entries
+            .iter()
+            .all(|(name, truth)| self.get(name.clone()).as_ref() == Some(truth))
+    }
+
+    /// Copies the resolved entries into a single world
+    pub fn flatten(&self) -> WorldState {
+        let mut flat = WorldState::new();
+        for layer in self.layers.iter() {
+            for (key, value) in layer.entries.iter() {
+                flat.insert(key.clone(), value.clone());
+            }
+        }
+        flat
+    }
 }
 
 impl Requirements {
@@ -144,6 +756,27 @@ impl Requirements {
         true
     }
 
+    /// Lists every predicate that is not met by the world along with why it failed. An empty result means `validate` would return true.
+    pub fn explain(&self, world: &WorldState) -> Vec<(UniqueName, PredicateFailure)> {
+        let mut failures = Vec::new();
+        for (key, value) in self.entries.iter() {
+            let Some(var) = world.get(key.clone()) else {
+                failures.push((key.clone(), PredicateFailure::MissingKey));
+                continue;
+            };
+            if !value.validate(var.clone()) {
+                failures.push((
+                    key.clone(),
+                    PredicateFailure::Mismatch {
+                        expected: value.clone(),
+                        actual: var,
+                    },
+                ));
+            }
+        }
+        failures
+    }
+
     pub fn consume(&self, world: &WorldState) -> WorldState {
         let mut reduced_world = world.clone();
         for (key, value) in self.entries.iter() {
@@ -170,6 +803,22 @@ impl Requirements {
         reduced_req
     }
 
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &UniqueName> {
+        self.entries.keys()
+    }
+
+    pub fn get(&self, key: impl Into<UniqueName>) -> Option<&Predicate> {
+        self.entries.get(&key.into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
     pub fn req(
         &mut self,
         key: impl Into<UniqueName>,
@@ -188,6 +837,16 @@ impl Requirements {
         self
     }
 
+    /// Like `req_equals`, but allows values of a different type to match. See [`Variant::coerced_eq`] for the rules
+    pub fn req_equals_coerced(
+        &mut self,
+        key: impl Into<UniqueName>,
+        variant: impl Into<Variant>,
+    ) -> &mut Self {
+        self.req(key, Predicate::EqualsCoerced(variant.into()));
+        self
+    }
+
     pub fn req_greater(
         &mut self,
         key: impl Into<UniqueName>,
@@ -206,6 +865,12 @@ impl Requirements {
         self
     }
 
+    /// Requires a number to be greater than or equal to `amount`, such as a remaining capacity
+    pub fn req_at_least(&mut self, key: impl Into<UniqueName>, amount: f32) -> &mut Self {
+        self.req(key, Predicate::AtLeast(amount));
+        self
+    }
+
     pub fn req_has(&mut self, key: impl Into<UniqueName>) -> &mut Self {
         self.req(key.into(), Predicate::HasEntry);
         self
@@ -223,11 +888,76 @@ impl Requirements {
     pub fn build(&mut self) -> Self {
         self.clone()
     }
+
+    /// Creates a copy of these requirements with every key passed through `f`
+    pub(crate) fn map_keys(&self, f: impl Fn(&UniqueName) -> UniqueName) -> Requirements {
+        Requirements {
+            entries: self
+                .entries
+                .iter()
+                .map(|(key, predicate)| (f(key), predicate.clone()))
+                .collect(),
+        }
+    }
+
+    /// Combines two sets of requirements such that both must be met. When both contain a predicate for the same key, the predicate from `other` is kept
+    pub fn and(self, other: Requirements) -> Self {
+        let mut merged = self;
+        merged.append(&other);
+        merged
+    }
+}
+
+impl BitAnd for Requirements {
+    type Output = Requirements;
+
+    /// Equivalent to [`Requirements::and`]
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.and(rhs)
+    }
 }
 
+/// The keys which appeared more than once when building a world with [`WorldState::try_from_iter`], in the order they were repeated
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateKeys(pub Vec<UniqueName>);
+
+impl std::fmt::Display for DuplicateKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keys: Vec<&str> = self.0.iter().map(|k| k.as_str()).collect();
+        write!(f, "Duplicate world keys: {}", keys.join(", "))
+    }
+}
+
+impl WorldState {
+    /// A strict alternative to the `From` impl, which fails listing every duplicated key instead of warning and keeping the last value
+    pub fn try_from_iter<I, S>(value: I) -> Result<Self, DuplicateKeys>
+    where
+        I: IntoIterator<Item = (S, Variant)>,
+        S: Into<UniqueName>,
+    {
+        let mut map = HashMap::new();
+        let mut duplicates = Vec::new();
+        for (name, truth) in value {
+            let un: UniqueName = name.into();
+            if map.insert(un.clone(), truth).is_some() && !duplicates.contains(&un) {
+                duplicates.push(un);
+            }
+        }
+        if !duplicates.is_empty() {
+            return Err(DuplicateKeys(duplicates));
+        }
+        Ok(Self {
+            entries: map,
+            ..Default::default()
+        })
+    }
+}
+
+/// Accepts anything that iterates over `(key, value)` pairs, including iterators, arrays like `[("room", "A".into())]`, and `HashMap<&'static str, Variant>`.
+/// A single blanket impl is used as separate impls for arrays and maps would conflict with it
 impl<I, S> From<I> for WorldState
 where
-    I: Iterator<Item = (S, Variant)>,
+    I: IntoIterator<Item = (S, Variant)>,
     S: Into<UniqueName>,
 {
     fn from(value: I) -> Self {
@@ -239,7 +969,11 @@ where
                 warn!("Duplicate entries for key: {:?}", un);
             }
         }
-        Self { entries: map }
+        Self {
+            entries: map,
+            merge_strategies: HashMap::new(),
+            expiries: HashMap::new(),
+        }
     }
 }
 
@@ -247,7 +981,25 @@ impl Predicate {
     pub fn validate(&self, variant: Variant) -> bool {
         match self {
             Predicate::HasEntry => true,
+            Predicate::Equals(Variant::Number(expected)) => {
+                let Variant::Number(actual) = variant else {
+                    return false;
+                };
+                let epsilon = NUMBER_EPSILON.get();
+                if epsilon > 0.0 {
+                    (actual - expected).abs() <= epsilon
+                } else {
+                    actual == *expected
+                }
+            }
             Predicate::Equals(var) => variant == *var,
+            Predicate::EqualsCoerced(var) => variant.coerced_eq(var),
+            Predicate::AtLeast(minimum) => {
+                let Variant::Number(actual) = variant else {
+                    return false;
+                };
+                actual >= minimum - NUMBER_EPSILON.get()
+            }
             Predicate::Order(ord, var) => {
                 if let Variant::Number(num) = var {
                     if let Variant::Number(num2) = variant {
@@ -267,7 +1019,11 @@ impl From<Variant> for WorldState {
     fn from(value: Variant) -> Self {
         let mut map = HashMap::new();
         map.insert("value".into(), value);
-        Self { entries: map }
+        Self {
+            entries: map,
+            merge_strategies: HashMap::new(),
+            expiries: HashMap::new(),
+        }
     }
 }
 
@@ -296,6 +1052,104 @@ impl From<f32> for Variant {
     }
 }
 
+/// Numbers are stored as `f32`, so integers beyond 2^24 in magnitude lose precision
+impl From<i32> for Variant {
+    fn from(value: i32) -> Self {
+        Self::Number(value as f32)
+    }
+}
+
+/// Numbers are stored as `f32`, so integers beyond 2^24 in magnitude lose precision
+impl From<i64> for Variant {
+    fn from(value: i64) -> Self {
+        Self::Number(value as f32)
+    }
+}
+
+/// Numbers are stored as `f32`, so the value is rounded to the nearest `f32` (and values beyond its range become infinite)
+impl From<f64> for Variant {
+    fn from(value: f64) -> Self {
+        Self::Number(value as f32)
+    }
+}
+
+impl Variant {
+    /// A string value only known at runtime. The string is interned as a [`UniqueName`], which leaks each distinct string for the rest of the program,
+    /// so avoid this for values without a small fixed set of possibilities (e.g. formatted names or counters)
+    pub fn interned(value: String) -> Self {
+        Self::String(UniqueName::from_string(value))
+    }
+
+    pub fn kind(&self) -> VariantKind {
+        match self {
+            Variant::Bool(_) => VariantKind::Bool,
+            Variant::String(_) => VariantKind::String,
+            Variant::Number(_) => VariantKind::Number,
+            Variant::Enum(_) => VariantKind::Enum,
+        }
+    }
+
+    /// Converts any supported game value into a variant. See the individual `From` impls for the caveats of each type
+    pub fn from_value(value: impl Into<Variant>) -> Self {
+        value.into()
+    }
+}
+
+/// Numbers are hashed by their bits, with `-0.0` treated as `0.0` so that equal values hash the same
+impl Hash for Variant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Variant::Bool(b) => b.hash(state),
+            Variant::String(s) => s.hash(state),
+            Variant::Number(n) => (if *n == 0.0 { 0.0f32 } else { *n }).to_bits().hash(state),
+            Variant::Enum(id) => id.hash(state),
+        }
+    }
+}
+
+/// Only the entries are hashed, in key order, so worlds with the same entries hash the same regardless of how they were built
+impl Hash for WorldState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(key, _)| key.as_str());
+        entries.len().hash(state);
+        for (key, value) in entries {
+            key.hash(state);
+            value.hash(state);
+        }
+    }
+}
+
+/// Numbers are hashed the same way as in [`Variant`]
+impl Hash for Predicate {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Predicate::HasEntry => (),
+            Predicate::Equals(value) | Predicate::EqualsCoerced(value) => value.hash(state),
+            Predicate::Order(ordering, value) => {
+                ordering.hash(state);
+                value.hash(state);
+            }
+            Predicate::AtLeast(n) => (if *n == 0.0 { 0.0f32 } else { *n }).to_bits().hash(state),
+        }
+    }
+}
+
+/// Hashed in key order like [`WorldState`], so the same predicates hash the same regardless of how they were added
+impl Hash for Requirements {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(key, _)| key.as_str());
+        entries.len().hash(state);
+        for (key, predicate) in entries {
+            key.hash(state);
+            predicate.hash(state);
+        }
+    }
+}
+
 #[allow(clippy::from_over_into)]
 impl Into<Requirements> for WorldState {
     /// Converts the world state to a Requirements struct with all predicates being `Equals`.
@@ -325,6 +1179,168 @@ impl Default for Variant {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{planning::plan_data::Plan, tasks::TaskRegistry};
+
+    #[test]
+    fn append_carries_expiries() {
+        let mut world = WorldState::new();
+        world.insert_expiring("alert", true, Duration::from_secs(5));
+        world.insert("hungry", true);
+
+        let mut update = WorldState::new();
+        update.insert("alert", false);
+        update.insert_expiring("hungry", false, Duration::from_secs(3));
+        world.append(&update);
+        // entries take the expiry they had in the appended world, if any
+        assert_eq!(world.expiry("alert"), None);
+        assert_eq!(world.expiry("hungry"), Some(Duration::from_secs(3)));
+        assert_eq!(world.remove_expired(Duration::from_secs(4)).len(), 1);
+        assert_eq!(world.get("alert"), Some(false.into()));
+    }
+
+    #[test]
+    fn world_schema_checked_insert() {
+        let schema = WorldSchema::new()
+            .expect("hunger", VariantKind::Number)
+            .expect("room", VariantKind::String)
+            .build();
+        let mut world = WorldState::new();
+
+        assert_eq!(world.insert_checked("hunger", 0.5, Some(&schema)), Ok(None));
+        let mismatch = world
+            .insert_checked("hunger", true, Some(&schema))
+            .unwrap_err();
+        assert_eq!(mismatch.key, "hunger".into());
+        assert_eq!(mismatch.expected, VariantKind::Number);
+        assert_eq!(mismatch.actual, true.into());
+        // the bad value was never written
+        assert_eq!(world.get("hunger"), Some(0.5.into()));
+
+        // keys outside the schema, or no schema at all, accept anything
+        assert!(world.insert_checked("mood", "happy", Some(&schema)).is_ok());
+        assert!(world.insert_checked("room", 3.0, None).is_ok());
+        assert_eq!(schema.validate(&world).len(), 1);
+    }
+
+    #[cfg(feature = "bevy_reflect")]
+    #[test]
+    fn reflect_world_state() {
+        use bevy::reflect::ReflectRef;
+
+        let mut app = App::new();
+        plugin(&mut app);
+        let registry = app.world().resource::<AppTypeRegistry>().read();
+        assert!(registry.get(std::any::TypeId::of::<WorldState>()).is_some());
+        assert!(registry
+            .get(std::any::TypeId::of::<HtnSettings>())
+            .is_some());
+
+        let world = WorldState::new()
+            .add("door_open", true)
+            .add("room", "A")
+            .build();
+        let ReflectRef::Struct(reflected) = world.reflect_ref() else {
+            panic!("WorldState should reflect as a struct");
+        };
+        let ReflectRef::Map(entries) = reflected.field("entries").unwrap().reflect_ref() else {
+            panic!("entries should reflect as a map");
+        };
+        assert_eq!(entries.len(), 2);
+        let door = entries
+            .get(&UniqueName::new("door_open"))
+            .and_then(|v| v.downcast_ref::<Variant>());
+        assert_eq!(door, Some(&Variant::Bool(true)));
+
+        // the reflected world can be rebuilt into an identical one
+        let rebuilt = <WorldState as FromReflect>::from_reflect(world.as_reflect()).unwrap();
+        assert_eq!(rebuilt, world);
+    }
+
+    #[test]
+    fn unique_names_survive_poisoning() {
+        let result = std::thread::spawn(|| {
+            let _lock = UNIQUE_NAME_REGISTRY.lock().unwrap();
+            panic!("poisoning the name registry");
+        })
+        .join();
+        assert!(result.is_err());
+
+        let name = UniqueName::new("after_poisoning");
+        assert_eq!(name.as_str(), "after_poisoning");
+        let runtime = UniqueName::from_string(String::from("after_") + "poisoning");
+        assert_eq!(runtime, name);
+        assert!(!UNIQUE_NAME_REGISTRY.is_poisoned());
+    }
+
+    #[test]
+    fn variant_from_values() {
+        let name = String::from("room_") + "B";
+        let world = WorldState::new()
+            .add("bool", Variant::from_value(true))
+            .add("f32", Variant::from_value(1.5f32))
+            .add("i32", Variant::from_value(-3i32))
+            .add("i64", Variant::from_value(40_000i64))
+            .add("f64", Variant::from_value(0.25f64))
+            .add("string", Variant::interned(name))
+            .add("str", Variant::from_value("room_A"))
+            .build();
+
+        assert_eq!(world.get("bool"), Some(Variant::Bool(true)));
+        assert_eq!(world.get("f32"), Some(Variant::Number(1.5)));
+        assert_eq!(world.get("i32"), Some(Variant::Number(-3.0)));
+        assert_eq!(world.get("i64"), Some(Variant::Number(40_000.0)));
+        assert_eq!(world.get("f64"), Some(Variant::Number(0.25)));
+        assert_eq!(world.get("string"), Some("room_B".into()));
+        assert_eq!(world.get("str"), Some("room_A".into()));
+    }
+
+    #[test]
+    fn world_prefix_query() {
+        let world = WorldState::new()
+            .add("carrying_wood", true)
+            .add("carrying_stone", false)
+            .add("carrying_", 2.0)
+            .add("not_carrying_food", true)
+            .add("health", 50.0)
+            .build();
+
+        let keys: Vec<&str> = world
+            .keys_with_prefix("carrying_")
+            .iter()
+            .map(|k| k.as_str())
+            .collect();
+        assert_eq!(keys, vec!["carrying_", "carrying_stone", "carrying_wood"]);
+
+        let entries = world.entries_with_prefix("carrying_");
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1], ("carrying_stone".into(), false.into()));
+        assert_eq!(entries[2], ("carrying_wood".into(), true.into()));
+
+        assert!(world.keys_with_prefix("food").is_empty());
+        assert_eq!(world.keys_with_prefix("").len(), 5);
+    }
+
+    #[test]
+    fn number_epsilon_equality() {
+        let req = Requirements::new().req_equals("x", 1.0).build();
+        let at_epsilon = WorldState::new().add("x", 1.25).build();
+        let beyond_epsilon = WorldState::new().add("x", 1.5).build();
+
+        // exact by default
+        assert!(!req.validate(&at_epsilon));
+        assert!(req.validate(&WorldState::new().add("x", 1.0).build()));
+
+        with_number_epsilon(0.25, || {
+            assert!(req.validate(&at_epsilon));
+            assert!(!req.validate(&beyond_epsilon));
+            assert!(!req.validate(&WorldState::new().add("x", "1.0").build()));
+        });
+        // restored once the scope ends, even when it panics
+        assert!(!req.validate(&at_epsilon));
+        let panicked = std::panic::catch_unwind(|| with_number_epsilon(0.25, || panic!()));
+        assert!(panicked.is_err());
+        assert!(!req.validate(&at_epsilon));
+    }
 
     #[test]
     fn test_truth_equality() {
@@ -390,4 +1406,491 @@ mod tests {
         assert!(super_set.validate(&truths_invalid)); // ensure new concatenation is valid for both
         assert!(super_set.validate(&truths_valid)); // ensure new concatenation is valid for both
     }
+
+    #[test]
+    fn test_requirements_explain() {
+        let req = Requirements::new()
+            .req_equals("door_open", true)
+            .req_has("room")
+            .req_greater("health", 50.)
+            .build();
+
+        let world = WorldState::new()
+            .add("door_open", false)
+            .add("health", 75.)
+            .build();
+
+        let failures = req.explain(&world);
+        assert_eq!(failures.len(), 2);
+        assert!(failures.contains(&("room".into(), PredicateFailure::MissingKey)));
+        assert!(failures.contains(&(
+            "door_open".into(),
+            PredicateFailure::Mismatch {
+                expected: Predicate::Equals(true.into()),
+                actual: false.into(),
+            }
+        )));
+
+        let valid_world = world.concat(
+            &WorldState::new()
+                .add("door_open", true)
+                .add("room", "A")
+                .build(),
+        );
+        assert!(req.explain(&valid_world).is_empty());
+        assert!(req.validate(&valid_world));
+    }
+
+    #[test]
+    fn test_coerced_equality() {
+        // strict comparisons are unaffected
+        let strict = Requirements::new().req_equals("value", false).build();
+        assert!(!strict.validate(&WorldState::new().add("value", 0.).build()));
+
+        let coerced = |variant: Variant| {
+            Requirements::new()
+                .req_equals_coerced("value", variant)
+                .build()
+        };
+        let world = |variant: Variant| WorldState::new().add("value", variant).build();
+
+        // bool <-> number
+        assert!(coerced(false.into()).validate(&world(0.0.into())));
+        assert!(coerced(true.into()).validate(&world(1.0.into())));
+        assert!(!coerced(true.into()).validate(&world(0.0.into())));
+        assert!(coerced(0.0.into()).validate(&world(false.into())));
+        assert!(!coerced(2.0.into()).validate(&world(true.into())));
+
+        // bool <-> string
+        assert!(coerced(true.into()).validate(&world("true".into())));
+        assert!(coerced("false".into()).validate(&world(false.into())));
+        assert!(!coerced(true.into()).validate(&world("yes".into())));
+
+        // number <-> string
+        assert!(coerced(2.5.into()).validate(&world("2.5".into())));
+        assert!(coerced("10".into()).validate(&world(10.0.into())));
+        assert!(!coerced(2.5.into()).validate(&world("two".into())));
+
+        // same types
+        assert!(coerced("A".into()).validate(&world("A".into())));
+        assert!(!coerced("A".into()).validate(&world("B".into())));
+    }
+
+    #[test]
+    fn test_requirements_composition() {
+        let in_room = Requirements::new().req_equals("room", "B").build();
+        let has_item = Requirements::new()
+            .req_equals("item_picked_up", true)
+            .build();
+
+        // disjoint keys
+        let both = in_room.clone() & has_item.clone();
+        assert_eq!(both.len(), 2);
+        assert_eq!(both, in_room.clone().and(has_item.clone()));
+        assert!(both.validate(
+            &WorldState::new()
+                .add("room", "B")
+                .add("item_picked_up", true)
+                .build()
+        ));
+        assert!(!both.validate(&WorldState::new().add("room", "B").build()));
+
+        // overlapping keys keep the last predicate
+        let in_other_room = Requirements::new()
+            .req_equals("room", "A")
+            .req_has("door_open")
+            .build();
+        let merged = in_room & in_other_room;
+        assert_eq!(merged.len(), 2);
+        assert!(merged.validate(
+            &WorldState::new()
+                .add("room", "A")
+                .add("door_open", false)
+                .build()
+        ));
+        assert!(!merged.validate(
+            &WorldState::new()
+                .add("room", "B")
+                .add("door_open", false)
+                .build()
+        ));
+    }
+
+    #[test]
+    fn test_world_apply_plan() {
+        use crate::tasks::Task;
+
+        #[derive(Component, Default)]
+        struct TaskStub;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let plan = Plan {
+            // stored leaf-first
+            tasks: vec![Task::primitive("goto_b"), Task::primitive("open_door")].into(),
+            cost: 2.0,
+            ..Default::default()
+        };
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .add("item_picked_up", false)
+            .build();
+
+        let projected = world.apply_plan(&plan, &registry);
+        assert_eq!(projected.get("room"), Some("B".into()));
+        assert_eq!(projected.get("door_open"), Some(true.into()));
+        assert_eq!(projected.get("item_picked_up"), Some(false.into()));
+
+        // the original is untouched
+        assert_eq!(world.get("room"), Some("A".into()));
+        assert_eq!(world.get("door_open"), Some(false.into()));
+        assert_ne!(world, projected);
+    }
+
+    #[test]
+    fn apply_plan_most_likely_outcome() {
+        use crate::tasks::Task;
+
+        #[derive(Component, Default)]
+        struct TaskStub;
+
+        let mut registry = TaskRegistry::new();
+        registry.outcome_task::<TaskStub, _>(
+            "search_room",
+            Requirements::new().build(),
+            vec![
+                (0.2, WorldState::new().add("found_key", true).build()),
+                (0.8, WorldState::new().add("found_key", false).build()),
+            ],
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "attack",
+            Requirements::new().build(),
+            WorldState::new().add("enemy", "dead").build(),
+            1.,
+        );
+        registry.set_outcomes(
+            "attack",
+            vec![
+                (1.0, WorldState::new().add("enemy", "dead").build()),
+                (3.0, WorldState::new().add("enemy", "fled").build()),
+                (0.0, WorldState::new().add("enemy", "surrendered").build()),
+            ],
+        );
+        registry.outcome_task::<TaskStub, _>(
+            "loot",
+            Requirements::new().build(),
+            vec![
+                (0.9, WorldState::new().increase("gold", 5.).build()),
+                (0.1, WorldState::new().increase("gold", 50.).build()),
+            ],
+            1.,
+        );
+        let plan = Plan {
+            tasks: vec![
+                Task::primitive("attack"),
+                Task::primitive("search_room"),
+                Task::primitive("loot"),
+                Task::primitive("loot"),
+            ]
+            .into(),
+            cost: 4.0,
+            ..Default::default()
+        };
+        let world = WorldState::new()
+            .add("enemy", "alive")
+            .add("gold", 10.)
+            .build();
+
+        let likely = world.apply_plan_most_likely(&plan, &registry);
+        assert_eq!(likely.get("found_key"), Some(false.into()));
+        assert_eq!(likely.get("enemy"), Some("fled".into()));
+        // outcomes are merged like any other effect
+        assert_eq!(likely.get("gold"), Some(20.0.into()));
+        // the planner's view still uses the registered effects
+        let planned = world.apply_plan(&plan, &registry);
+        assert_eq!(planned.get("found_key"), Some(false.into()));
+        assert_eq!(planned.get("enemy"), Some("dead".into()));
+
+        registry.set_outcomes("attack", vec![]);
+        assert!(registry.most_likely_outcome("attack").is_none());
+
+        // a task without any likely outcome is still registered, with no effects
+        registry.outcome_task::<TaskStub, _>(
+            "pray",
+            Requirements::new().build(),
+            vec![(0.0, WorldState::new())],
+            1.,
+        );
+        let pray = registry
+            .get_named(&"pray".to_string())
+            .expect("outcome task should be registered");
+        assert!(pray.postconditions().is_empty());
+    }
+
+    #[test]
+    fn test_decrement_effects() {
+        use crate::planning::{goals::Goal, plan_data::TimeSlicedTreeGen};
+        use crate::tasks::Task;
+
+        #[derive(Component, Default)]
+        struct TaskStub;
+
+        let spend = WorldState::new().subtract("gold", 10.).build();
+        let world = WorldState::new().add("gold", 25.).build();
+        assert_eq!(world.concat(&spend).get("gold"), Some(15.0.into()));
+        assert_eq!(
+            world.concat(&spend).concat(&spend).get("gold"),
+            Some(5.0.into())
+        );
+        // the merged result is a plain value, so it overwrites when applied elsewhere
+        assert_eq!(
+            WorldState::new()
+                .add("gold", 100.)
+                .build()
+                .concat(&world.concat(&spend))
+                .get("gold"),
+            Some(15.0.into())
+        );
+        // missing numbers count as zero
+        assert_eq!(
+            WorldState::new().concat(&spend).get("gold"),
+            Some((-10.0).into())
+        );
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "spend",
+            Requirements::new().req_greater("gold", 0.).build(),
+            spend,
+            1.,
+        );
+
+        // three purchases are possible before running out of gold
+        let broke = Goal::new(
+            "Broke",
+            Requirements::new().req_less("gold", 0.).build(),
+            1.0,
+        );
+        let mut gen =
+            TimeSlicedTreeGen::new_initialized(vec![Task::primitive("spend")], vec![broke.clone()]);
+        gen.generate_to_completion(&registry, &world, Some(16));
+        let plan = gen.plans.get(&broke.name).expect("Failed to find a plan");
+        assert_eq!(plan.tasks.len(), 3);
+
+        // but not a fourth
+        let in_debt = Goal::new(
+            "In Debt",
+            Requirements::new().req_less("gold", -10.).build(),
+            1.0,
+        );
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("spend")],
+            vec![in_debt.clone()],
+        );
+        gen.generate_to_completion(&registry, &world, Some(16));
+        assert!(!gen.plans.contains_key(&in_debt.name));
+        assert_eq!(gen.stats.nodes_expanded, 3);
+    }
+
+    #[test]
+    fn layered_world_matches_flattened() {
+        const FACTS: [&str; 8] = [
+            "fact_0", "fact_1", "fact_2", "fact_3", "fact_4", "fact_5", "fact_6", "fact_7",
+        ];
+        let mut base = WorldState::new();
+        for (i, fact) in FACTS.into_iter().enumerate() {
+            base.insert(UniqueName::new(fact), i as f32);
+        }
+        let overlay = WorldState::new()
+            .add("fact_1", "overridden")
+            .add("agent_only", true)
+            .build();
+        let layered = LayeredWorldState::new(Arc::new(base)).with_layer(Arc::new(overlay));
+        let flat = layered.flatten();
+        assert_eq!(flat.len(), FACTS.len() + 1);
+
+        let keys: Vec<UniqueName> = flat
+            .keys()
+            .cloned()
+            .chain([UniqueName::new("missing")])
+            .collect();
+        for key in keys.iter() {
+            assert_eq!(layered.get(key.clone()), flat.get(key.clone()));
+        }
+        assert_eq!(layered.get("fact_1"), Some("overridden".into()));
+        assert_eq!(layered.get("fact_2"), Some(2.0.into()));
+
+        let subsets = [
+            WorldState::new()
+                .add("fact_1", "overridden")
+                .add("fact_5", 5.0)
+                .build(),
+            WorldState::new().add("fact_1", 1.0).build(),
+            WorldState::new().add("missing", true).build(),
+            WorldState::new(),
+        ];
+        for subset in subsets.iter() {
+            assert_eq!(layered.validate(subset), flat.validate(subset));
+        }
+    }
+
+    #[test]
+    fn prewarm_names() {
+        const NAMES: [&str; 4] = [
+            "prewarm_alpha",
+            "prewarm_beta",
+            "prewarm_gamma",
+            "prewarm_delta",
+        ];
+        let registered = || {
+            let lock = lock_unique_names();
+            NAMES.iter().filter(|n| lock.contains_key(**n)).count()
+        };
+        assert_eq!(registered(), 0);
+
+        UniqueName::prewarm(&NAMES);
+        assert_eq!(registered(), NAMES.len());
+        let stored: Vec<_> = {
+            let lock = lock_unique_names();
+            NAMES.iter().map(|n| lock[*n].clone()).collect()
+        };
+
+        // prewarming again, or creating the names, reuses the existing entries
+        UniqueName::prewarm(&NAMES);
+        for (name, stored) in NAMES.iter().zip(stored) {
+            assert!(Arc::ptr_eq(&UniqueName::new(name).0, &stored));
+        }
+        assert_eq!(registered(), NAMES.len());
+    }
+
+    #[test]
+    fn enum_variants() {
+        let kitchen = Variant::register_enum("enum_test_kitchen");
+        let hall = Variant::register_enum("enum_test_hall");
+        assert_ne!(kitchen, hall);
+        assert_eq!(Variant::register_enum("enum_test_kitchen"), kitchen);
+        assert_eq!(
+            Variant::enum_named("enum_test_kitchen"),
+            Some(kitchen.clone())
+        );
+        assert_eq!(Variant::enum_named("enum_test_kitchn"), None);
+        assert_eq!(kitchen.enum_name(), Some("enum_test_kitchen".to_string()));
+        assert_eq!(kitchen.kind(), VariantKind::Enum);
+
+        let world = WorldState::new().add("room", kitchen.clone()).build();
+        assert!(Requirements::new()
+            .req_equals("room", kitchen.clone())
+            .build()
+            .validate(&world));
+        assert!(!Requirements::new()
+            .req_equals("room", hall)
+            .build()
+            .validate(&world));
+        // enums only match their name when coerced
+        assert!(!Requirements::new()
+            .req_equals("room", "enum_test_kitchen")
+            .build()
+            .validate(&world));
+        assert!(Requirements::new()
+            .req_equals_coerced("room", "enum_test_kitchen")
+            .build()
+            .validate(&world));
+    }
+
+    #[test]
+    fn strict_world_construction() {
+        let entries = || -> Vec<(&str, Variant)> {
+            vec![
+                ("room", "A".into()),
+                ("door_open", false.into()),
+                ("room", "B".into()),
+                ("room", "C".into()),
+            ]
+        };
+        assert_eq!(
+            WorldState::try_from_iter(entries()),
+            Err(DuplicateKeys(vec![UniqueName::new("room")]))
+        );
+
+        let lenient: WorldState = entries().into_iter().into();
+        assert_eq!(lenient.get("room"), Some("C".into()));
+        assert_eq!(lenient.len(), 2);
+
+        let strict = WorldState::try_from_iter([("room", "A".into())]).unwrap();
+        assert_eq!(strict.get("room"), Some("A".into()));
+    }
+
+    #[test]
+    fn world_from_literals() {
+        let world = WorldState::from([
+            ("room", "A".into()),
+            ("door_open", false.into()),
+            ("hunger", 2.0.into()),
+        ]);
+        assert_eq!(
+            world,
+            WorldState::new()
+                .add("room", "A")
+                .add("door_open", false)
+                .add("hunger", 2.0)
+                .build()
+        );
+
+        let map: HashMap<&'static str, Variant> =
+            HashMap::from([("room", "A".into()), ("door_open", false.into())]);
+        let from_map: WorldState = map.into();
+        assert!(world.validate(&from_map));
+        assert_eq!(from_map.len(), 2);
+    }
+
+    #[test]
+    fn key_migration() {
+        let mut world = WorldState::new()
+            .add("hp", 10.0)
+            .add("location", "A")
+            .add("door", false)
+            .build();
+        world.add_merged("gold", 5.0, MergeStrategy::SumNumbers);
+
+        assert!(world.rename_key("hp", "health"));
+        assert!(!world.rename_key("hp", "health"));
+        assert_eq!(world.get("hp"), None);
+        assert!(Requirements::new()
+            .req_greater("health", 5.0)
+            .build()
+            .validate(&world));
+
+        // renames apply in order, and missing keys are skipped
+        let renamed = world.migrate(&[
+            ("location", "room"),
+            ("door", "door_open"),
+            ("mana", "magic"),
+            ("gold", "coins"),
+        ]);
+        assert_eq!(renamed, 3);
+        assert!(Requirements::new()
+            .req_equals("room", "A")
+            .req_equals("door_open", false)
+            .build()
+            .validate(&world));
+        assert_eq!(world.merge_strategy("coins"), MergeStrategy::SumNumbers);
+        assert_eq!(world.len(), 4);
+    }
 }
@@ -1,15 +1,514 @@
-use bevy::prelude::{Commands, Event, Trigger};
+use bevy::{
+    prelude::{
+        Changed, Commands, DetectChanges, Entity, Event, EventWriter, Local, Query, Ref, Res,
+        Trigger,
+    },
+    utils::{HashMap, HashSet},
+};
 
-use crate::prelude::{HtnAgentCurrentTask, HtnAgentPlan, HtnAgentState};
+use crate::{
+    data::{HtnSettings, NumberEpsilonGuard, UniqueName, Variant, WorldState},
+    execution::{
+        compose_agent_world, HtnAgentGroup, HtnAgentPlan, HtnAgentState, HtnAgentTaskStarted,
+        HtnAgentWorld, HtnSharedWorld, HtnTaskProgress,
+    },
+    planning::HtnAgent,
+    prelude::{HtnAgentCurrentTask, HtnAgentCurrentTasks, HtnPlanExecutionComponents},
+    tasks::TaskRegistry,
+};
 
 #[derive(Event)]
 pub struct HtnPlanInvalidated;
 
+/// Triggered on an agent when it finishes every step of its plan, just before the plan is removed
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct HtnPlanCompleted {
+    /// The goal the plan was made for
+    pub goal: String,
+}
+
+/// Triggered on an agent when a subtask of a macro task in its [`HtnAgentPlan`](crate::execution::HtnAgentPlan) fails.
+/// A failure inside nested macros triggers this once for each macro, innermost first
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct HtnMacroFailed {
+    pub macro_name: String,
+    /// The primitive task which failed
+    pub task: String,
+}
+
+/// Adds a one-off task to the agent's current plan without replanning, such as dodging when a projectile is spotted. Agents without a plan ignore this
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct HtnInjectTask {
+    pub task: String,
+    /// Run the task next, pausing the running task until it is done. Otherwise the task runs once the rest of the plan has finished
+    pub at_front: bool,
+}
+
+/// Adds the injected task to the agent's [`HtnAgentPlan`]. A paused task is removed through the [`TaskRegistry`] and started again from scratch afterwards.
+/// Agents with [`HtnParallelExecution`](crate::execution::HtnParallelExecution) are never paused, the task instead starts with the next batch
+pub fn observer_inject_task(
+    trigger: Trigger<HtnInjectTask>,
+    mut query: Query<(
+        &mut HtnAgentPlan,
+        Option<&HtnAgentCurrentTask>,
+        Option<&HtnAgentState>,
+    )>,
+    task_registry: Option<Res<TaskRegistry>>,
+    mut commands: Commands,
+) {
+    let Ok((mut plan, current, state)) = query.get_mut(trigger.entity()) else {
+        return;
+    };
+    let event = trigger.event();
+    if let (true, Some(current), Some(HtnAgentState::Running)) = (event.at_front, current, state) {
+        let mut entity = commands.entity(trigger.entity());
+        if let Some(data) = task_registry.as_ref().and_then(|r| r.get_named(&current.0)) {
+            data.remove(&mut entity);
+        }
+        // without a state, the next step is started straight away
+        entity.remove::<(HtnAgentCurrentTask, HtnAgentTaskStarted, HtnAgentState)>();
+        plan.plan_stack.push(current.0.clone());
+    }
+    plan.insert_step(event.task.clone(), event.at_front);
+}
+
+/// Abandons the agent's current plan. Any running tasks are removed through the [`TaskRegistry`] so their cleanup logic still runs
 pub fn observer_handle_invalidated_plan(
     trigger: Trigger<HtnPlanInvalidated>,
+    query: Query<(Option<&HtnAgentCurrentTask>, Option<&HtnAgentCurrentTasks>)>,
+    task_registry: Option<Res<TaskRegistry>>,
     mut commands: Commands,
 ) {
-    commands
-        .entity(trigger.entity())
-        .remove::<(HtnAgentCurrentTask, HtnAgentState, HtnAgentPlan)>();
+    let mut entity = commands.entity(trigger.entity());
+    if let (Ok((task, tasks)), Some(registry)) = (query.get(trigger.entity()), task_registry) {
+        let running = task
+            .map(|t| &t.0)
+            .into_iter()
+            .chain(tasks.into_iter().flat_map(|t| t.0.iter()));
+        for name in running {
+            if let Some(data) = registry.get_named(name) {
+                data.remove(&mut entity);
+            }
+        }
+    }
+    entity.remove::<HtnPlanExecutionComponents>();
+}
+
+/// Sent by [`system_report_task_progress`] whenever an agent's [`HtnTaskProgress`] is added or changed
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct HtnTaskProgressed {
+    pub entity: Entity,
+    /// The task the agent was running at the time, if any
+    pub task: Option<String>,
+    /// The new progress, clamped between 0.0 and 1.0
+    pub progress: f32,
+}
+
+pub fn system_report_task_progress(
+    query: Query<
+        (Entity, &HtnTaskProgress, Option<&HtnAgentCurrentTask>),
+        Changed<HtnTaskProgress>,
+    >,
+    mut events: EventWriter<HtnTaskProgressed>,
+) {
+    for (entity, progress, task) in query.iter() {
+        events.send(HtnTaskProgressed {
+            entity,
+            task: task.map(|t| t.0.clone()),
+            progress: progress.fraction(),
+        });
+    }
+}
+
+/// Sent by [`system_report_achieved_goals`] when the world an agent sees comes to satisfy one of its goals.
+/// Unlike [`HtnPlanCompleted`], this reflects the actual world, whether or not a plan got it there
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct HtnGoalAchieved {
+    pub entity: Entity,
+    pub goal: String,
+}
+
+/// Checks each agent's goals against its world whenever either changes, sending [`HtnGoalAchieved`] for goals that were not satisfied before but are now.
+/// Goals already satisfied when an agent is first checked are not reported, as they weren't achieved while it was watched
+#[allow(clippy::type_complexity)]
+pub fn system_report_achieved_goals(
+    query: Query<(
+        Entity,
+        Ref<HtnAgent>,
+        Option<Ref<HtnAgentWorld>>,
+        Option<Ref<HtnAgentGroup>>,
+    )>,
+    world: Res<WorldState>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    mut satisfied: Local<HashMap<Entity, HashSet<String>>>,
+    mut events: EventWriter<HtnGoalAchieved>,
+) {
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    let shared_changed = world.is_changed()
+        || shared.as_ref().is_some_and(|s| s.is_changed())
+        || settings.as_ref().is_some_and(|s| s.is_changed());
+    satisfied.retain(|entity, _| query.contains(*entity));
+    for (entity, agent, agent_world, group) in query.iter() {
+        let changed = shared_changed
+            || agent.is_changed()
+            || agent_world.as_ref().is_some_and(|w| w.is_changed())
+            || group.as_ref().is_some_and(|g| g.is_changed());
+        let previous = satisfied.get(&entity);
+        if previous.is_some() && !changed {
+            continue;
+        }
+        let context = compose_agent_world(
+            &world,
+            shared.as_deref(),
+            group.as_deref(),
+            agent_world.as_deref(),
+        );
+        let now: HashSet<String> = agent
+            .goals
+            .iter()
+            .filter(|goal| goal.is_satisfied(&context))
+            .map(|goal| goal.name.clone())
+            .collect();
+        if let Some(previous) = previous {
+            for goal in now.difference(previous) {
+                events.send(HtnGoalAchieved {
+                    entity,
+                    goal: goal.clone(),
+                });
+            }
+        }
+        satisfied.insert(entity, now);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeKind {
+    Added(Variant),
+    Modified { from: Variant, to: Variant },
+    Removed(Variant),
+}
+
+/// Every change made to the global [`WorldState`] since it was last checked, sorted by key. Sent by [`system_track_world_state_changes`]
+#[derive(Event, Debug, Clone)]
+pub struct WorldStateChanged {
+    pub changes: Vec<(UniqueName, ChangeKind)>,
+}
+
+/// An opt-in debugging aid which diffs the global [`WorldState`] against a cached copy whenever it changes, sending a [`WorldStateChanged`] event.
+/// Requires the event to be registered with `app.add_event::<WorldStateChanged>()`
+pub fn system_track_world_state_changes(
+    world: Res<WorldState>,
+    mut previous: Local<WorldState>,
+    mut events: EventWriter<WorldStateChanged>,
+) {
+    if !world.is_changed() {
+        return;
+    }
+    let mut changes = Vec::new();
+    for key in world.keys() {
+        let value = world.get(key.clone()).unwrap_or_default();
+        match previous.get(key.clone()) {
+            None => changes.push((key.clone(), ChangeKind::Added(value))),
+            Some(old) if old != value => changes.push((
+                key.clone(),
+                ChangeKind::Modified {
+                    from: old,
+                    to: value,
+                },
+            )),
+            Some(_) => (),
+        }
+    }
+    for key in previous.keys() {
+        if world.get(key.clone()).is_none() {
+            let old = previous.get(key.clone()).unwrap_or_default();
+            changes.push((key.clone(), ChangeKind::Removed(old)));
+        }
+    }
+    *previous = world.clone();
+    if changes.is_empty() {
+        return;
+    }
+    changes.sort_by_key(|(key, _)| key.as_str());
+    events.send(WorldStateChanged { changes });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn invalidation_removes_running_task() {
+        #[derive(Component, Default)]
+        struct Walk;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<Walk, _>("walk", Requirements::new(), WorldState::new(), 1.);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentPlan::new(vec!["walk".into(), "walk".into()]),
+            ))
+            .id();
+        app.update();
+        assert!(app.world().get::<Walk>(entity).is_some());
+
+        app.world_mut().trigger_targets(HtnPlanInvalidated, entity);
+        app.world_mut().flush();
+        assert!(app.world().get::<Walk>(entity).is_none());
+        assert!(app.world().get::<HtnAgentCurrentTask>(entity).is_none());
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+    }
+
+    #[test]
+    fn injected_task_runs_next() {
+        #[derive(Component, Default)]
+        struct Walk;
+        #[derive(Component, Default)]
+        struct Dodge;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<Walk, _>("walk", Requirements::new(), WorldState::new(), 1.);
+        registry.task::<Walk, _>("rest", Requirements::new(), WorldState::new(), 1.);
+        registry.task::<Dodge, _>("dodge", Requirements::new(), WorldState::new(), 1.);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentPlan::new(vec!["rest".into(), "walk".into()]),
+            ))
+            .id();
+        app.update();
+        assert!(app.world().get::<Walk>(entity).is_some());
+
+        app.world_mut().trigger_targets(
+            HtnInjectTask {
+                task: "dodge".into(),
+                at_front: true,
+            },
+            entity,
+        );
+        app.world_mut().flush();
+        assert!(app.world().get::<Walk>(entity).is_none());
+
+        let mut started = Vec::new();
+        for _ in 0..4 {
+            app.update();
+            let Some(task) = app.world().get::<HtnAgentCurrentTask>(entity) else {
+                break;
+            };
+            started.push(task.0.clone());
+            app.world_mut()
+                .entity_mut(entity)
+                .insert(HtnAgentState::Success);
+        }
+        // the paused walk starts over once dodging is done
+        assert_eq!(started, vec!["dodge", "walk", "rest"]);
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+    }
+
+    #[test]
+    fn goal_achieved_without_a_plan() {
+        use crate::planning::goals::Goal;
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(WorldState::new());
+        app.add_event::<HtnGoalAchieved>();
+        app.add_systems(Update, system_report_achieved_goals);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent {
+                    goals: vec![Goal::new(
+                        "Pick up item",
+                        Requirements::new()
+                            .req_equals("item_picked_up", true)
+                            .build(),
+                        1.0,
+                    )],
+                    ..Default::default()
+                },
+                HtnAgentWorld(WorldState::new().add("item_picked_up", false).build()),
+            ))
+            .id();
+        let mut reader = app
+            .world()
+            .resource::<Events<HtnGoalAchieved>>()
+            .get_reader();
+        let mut read = |app: &App| -> Vec<HtnGoalAchieved> {
+            reader
+                .read(app.world().resource::<Events<HtnGoalAchieved>>())
+                .cloned()
+                .collect()
+        };
+        let set_item = |app: &mut App, picked_up: bool| {
+            app.world_mut().entity_mut(entity).insert(HtnAgentWorld(
+                WorldState::new().add("item_picked_up", picked_up).build(),
+            ));
+        };
+
+        // an agent whose goal is already satisfied when first seen isn't reported
+        app.world_mut().spawn((
+            HtnAgent {
+                goals: vec![Goal::new(
+                    "Pick up item",
+                    Requirements::new()
+                        .req_equals("item_picked_up", true)
+                        .build(),
+                    1.0,
+                )],
+                ..Default::default()
+            },
+            HtnAgentWorld(WorldState::new().add("item_picked_up", true).build()),
+        ));
+        app.update();
+        assert!(read(&app).is_empty());
+
+        // something other than a plan picks the item up
+        set_item(&mut app, true);
+        app.update();
+        assert_eq!(
+            read(&app),
+            vec![HtnGoalAchieved {
+                entity,
+                goal: "Pick up item".into(),
+            }]
+        );
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+
+        // only reported once while the goal stays satisfied
+        app.update();
+        assert!(read(&app).is_empty());
+
+        set_item(&mut app, false);
+        app.update();
+        set_item(&mut app, true);
+        app.update();
+        assert_eq!(read(&app).len(), 1);
+    }
+
+    #[test]
+    fn task_progress_events() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.add_event::<HtnTaskProgressed>();
+        app.add_systems(Update, system_report_task_progress);
+        let entity = app
+            .world_mut()
+            .spawn((HtnAgentCurrentTask("build".into()), HtnTaskProgress(0.0)))
+            .id();
+        let mut reader = app
+            .world()
+            .resource::<Events<HtnTaskProgressed>>()
+            .get_reader();
+        let mut read = |app: &App| -> Vec<HtnTaskProgressed> {
+            reader
+                .read(app.world().resource::<Events<HtnTaskProgressed>>())
+                .cloned()
+                .collect()
+        };
+
+        app.update();
+        assert_eq!(read(&app).len(), 1);
+        app.update();
+        assert!(read(&app).is_empty());
+
+        app.world_mut()
+            .get_mut::<HtnTaskProgress>(entity)
+            .unwrap()
+            .0 = 1.5;
+        app.update();
+        assert_eq!(
+            read(&app),
+            vec![HtnTaskProgressed {
+                entity,
+                task: Some("build".into()),
+                progress: 1.0,
+            }]
+        );
+
+        let mut plan = HtnAgentPlan::new(vec!["decorate".into(), "build".into()]);
+        plan.plan_stack.pop();
+        assert_eq!(plan.progress_with_task(Some(&HtnTaskProgress(0.5))), 0.25);
+        assert_eq!(plan.progress_with_task(None), 0.0);
+    }
+
+    #[test]
+    fn world_state_change_events() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.add_event::<WorldStateChanged>();
+        app.insert_resource(WorldState::new().add("door_open", false).build());
+        app.add_systems(Update, system_track_world_state_changes);
+        let mut reader = app
+            .world()
+            .resource::<Events<WorldStateChanged>>()
+            .get_reader();
+        let mut read = |app: &App| -> Vec<WorldStateChanged> {
+            reader
+                .read(app.world().resource::<Events<WorldStateChanged>>())
+                .cloned()
+                .collect()
+        };
+
+        app.update();
+        let events = read(&app);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].changes,
+            vec![("door_open".into(), ChangeKind::Added(false.into()))]
+        );
+
+        // nothing changed, so nothing is sent
+        app.update();
+        assert!(read(&app).is_empty());
+
+        let mut world = app.world_mut().resource_mut::<WorldState>();
+        world.insert("door_open", true);
+        world.insert("room", "B");
+        world.erase("door_open");
+        world.insert("alarm", true);
+        app.update();
+        let events = read(&app);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].changes,
+            vec![
+                ("alarm".into(), ChangeKind::Added(true.into())),
+                ("door_open".into(), ChangeKind::Removed(false.into())),
+                ("room".into(), ChangeKind::Added("B".into())),
+            ]
+        );
+
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("room", "A");
+        app.update();
+        assert_eq!(
+            read(&app)[0].changes,
+            vec![(
+                "room".into(),
+                ChangeKind::Modified {
+                    from: "B".into(),
+                    to: "A".into()
+                }
+            )]
+        );
+    }
 }
@@ -1,24 +1,399 @@
-use bevy::{ecs::system::EntityCommands, prelude::*};
+use std::{any::Any, collections::HashMap, time::Duration};
+
+use bevy::{
+    ecs::{
+        component::Tick,
+        system::{EntityCommands, SystemChangeTick},
+    },
+    prelude::*,
+};
 
 use crate::{
-    data::{HtnSettings, WorldState},
-    planning::HtnAgent,
+    data::{HtnSettings, NumberEpsilonGuard, WorldState},
+    events::{HtnGoalAchieved, HtnMacroFailed, HtnPlanCompleted, HtnTaskProgressed},
+    planning::{
+        goals::{HtnAgentGoalTimers, HtnAgentRestoring, HtnAgentRng},
+        plan_data::{HtnAgentPlanStats, Plan},
+        providers::{HtnAgentRole, StaticGoalProvider, StaticTaskProvider},
+        CompletionPolicy, HtnAgent,
+    },
     prelude::{plan_data::TimeSlicedTreeGen, HtnAgentPlanningPriority},
-    tasks::TaskRegistry,
+    tasks::{AbortOutcome, Task, TaskRegistry},
 };
 
+pub(crate) fn plugin(app: &mut App) {
+    app.init_resource::<HtnGoalCounts>()
+        .add_event::<HtnTaskProgressed>()
+        .add_event::<HtnGoalAchieved>();
+}
+
 #[derive(Component)]
 pub struct HtnAgentWorld(pub WorldState);
 
+/// Scratch data for an agent's task systems, such as a target entity or a last known position. Unlike [`HtnAgentWorld`], the planner only reads the candidate targets
+/// of targeted tasks from it (see [`TaskRegistry::targeted_task`]), so changing anything else doesn't cause replanning and it can hold values of any type
+#[derive(Component, Default)]
+pub struct HtnBlackboard(HashMap<String, Box<dyn Any + Send + Sync>>);
+
+impl HtnBlackboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a value, returning true if it replaced an existing entry
+    pub fn set<T: Any + Send + Sync>(&mut self, key: impl Into<String>, value: T) -> bool {
+        self.0.insert(key.into(), Box::new(value)).is_some()
+    }
+
+    /// The value stored under `key`, or `None` if there isn't one or it isn't a `T`
+    pub fn get<T: Any>(&self, key: &str) -> Option<&T> {
+        self.0.get(key)?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any>(&mut self, key: &str) -> Option<&mut T> {
+        self.0.get_mut(key)?.downcast_mut()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Removes the entry, returning true if there was one
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.0.remove(key).is_some()
+    }
+}
+
+pub type GroupId = u32;
+
+/// World state shared by every agent in a group (e.g. a squad), layered between the global [`WorldState`] and each agent's [`HtnAgentWorld`]
+#[derive(Resource, Default, Debug, Clone)]
+pub struct HtnSharedWorld(pub HashMap<GroupId, WorldState>);
+
+impl HtnSharedWorld {
+    pub fn group(&self, group: GroupId) -> Option<&WorldState> {
+        self.0.get(&group)
+    }
+
+    /// The shared world for the group, created empty if it doesn't exist yet
+    pub fn group_mut(&mut self, group: GroupId) -> &mut WorldState {
+        self.0.entry(group).or_default()
+    }
+}
+
+/// Places the agent in a group, giving it access to that group's [`HtnSharedWorld`] layer
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HtnAgentGroup(pub GroupId);
+
+/// The world an agent plans against: the global world, then its group's shared world, then its own world, with later layers taking precedence
+pub(crate) fn compose_agent_world(
+    global: &WorldState,
+    shared: Option<&HtnSharedWorld>,
+    group: Option<&HtnAgentGroup>,
+    agent_world: Option<&HtnAgentWorld>,
+) -> WorldState {
+    let mut world = global.clone();
+    if let Some(group_world) = group.and_then(|g| shared?.group(g.0)) {
+        world.append(group_world);
+    }
+    if let Some(w) = agent_world {
+        world.append(&w.0);
+    }
+    world
+}
+
 #[derive(Component, Debug)]
 pub struct HtnAgentPlan {
     pub plan_stack: Vec<String>,
+    /// How many steps the plan had when it was created
+    pub total_steps: usize,
+    /// The macro tasks making up the plan, as ranges of steps in execution order. Nested macros are listed after the macros containing them
+    pub macros: Vec<HtnMacroSpan>,
+}
+
+/// The steps of an [`HtnAgentPlan`] which were decomposed from a single macro task, as the half-open range `start..end` in execution order
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtnMacroSpan {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl HtnAgentPlan {
+    pub fn new(plan_stack: Vec<String>) -> Self {
+        Self {
+            total_steps: plan_stack.len(),
+            plan_stack,
+            macros: Vec::new(),
+        }
+    }
+
+    /// Builds a plan from tasks in execution order, keeping track of which steps belong to which macro task.
+    /// The primitive tasks are still streamed one at a time, but a failing subtask fails every macro containing it, see [`HtnMacroFailed`]
+    pub fn from_tasks<'a>(tasks: impl IntoIterator<Item = &'a Task>) -> Self {
+        fn flatten(task: &Task, steps: &mut Vec<String>, macros: &mut Vec<HtnMacroSpan>) {
+            match task {
+                Task::Primitive(name) => steps.push(name.clone()),
+                Task::Macro(subtasks, name) => {
+                    let index = macros.len();
+                    macros.push(HtnMacroSpan {
+                        name: name.clone(),
+                        start: steps.len(),
+                        end: steps.len(),
+                    });
+                    for subtask in subtasks {
+                        flatten(subtask, steps, macros);
+                    }
+                    macros[index].end = steps.len();
+                }
+            }
+        }
+        let mut steps = Vec::new();
+        let mut macros = Vec::new();
+        for task in tasks {
+            flatten(task, &mut steps, &mut macros);
+        }
+        // tasks are popped from the end of the stack, so the first task to execute goes last
+        steps.reverse();
+        Self {
+            macros,
+            ..Self::new(steps)
+        }
+    }
+
+    /// Adds a step to the plan, either as the next step to start or as the final step. When added as the next step, macros spanning that point grow to include it
+    pub fn insert_step(&mut self, task: String, at_front: bool) {
+        let index = if at_front {
+            let index = self.total_steps - self.plan_stack.len();
+            self.plan_stack.push(task);
+            index
+        } else {
+            self.plan_stack.insert(0, task);
+            self.total_steps
+        };
+        self.total_steps += 1;
+        for span in self.macros.iter_mut() {
+            if span.start >= index {
+                span.start += 1;
+            }
+            if span.end > index {
+                span.end += 1;
+            }
+        }
+    }
+
+    /// The index (in execution order) of the step which was started most recently, if any
+    pub fn current_step(&self) -> Option<usize> {
+        (self.total_steps - self.plan_stack.len()).checked_sub(1)
+    }
+
+    /// The macros containing the step which was started most recently, innermost first
+    pub fn current_macros(&self) -> impl Iterator<Item = &HtnMacroSpan> {
+        let step = self.current_step();
+        self.macros
+            .iter()
+            .rev()
+            .filter(move |m| step.is_some_and(|s| (m.start..m.end).contains(&s)))
+    }
+
+    /// The fraction of the plan's steps that have been started, from 0.0 (nothing started) to 1.0 (the final step is underway).
+    /// Empty plans count as complete
+    pub fn progress(&self) -> f32 {
+        if self.total_steps == 0 {
+            return 1.0;
+        }
+        let started = self.total_steps.saturating_sub(self.plan_stack.len());
+        started as f32 / self.total_steps as f32
+    }
+
+    /// Like [`HtnAgentPlan::progress`], but counting the current task as partly done according to its [`HtnTaskProgress`] rather than as a whole step
+    pub fn progress_with_task(&self, task: Option<&HtnTaskProgress>) -> f32 {
+        if self.total_steps == 0 {
+            return 1.0;
+        }
+        let finished = self.current_step().unwrap_or_default() as f32;
+        let current = match self.current_step() {
+            Some(_) => task.map_or(0.0, HtnTaskProgress::fraction),
+            None => 0.0,
+        };
+        (finished + current) / self.total_steps as f32
+    }
+
+    /// The task that will be started once the current task is complete. Tasks are popped from the end of `plan_stack`, so this is the last element
+    pub fn peek_next(&self) -> Option<&str> {
+        self.plan_stack.last().map(|s| s.as_str())
+    }
+
+    /// The tasks that have not been started yet, in stack order (the last element is executed next)
+    pub fn remaining(&self) -> &[String] {
+        &self.plan_stack
+    }
 }
 
 #[derive(Component)]
 pub struct HtnAgentCurrentTask(pub String);
 
-#[derive(Component, PartialEq)]
+/// How far through its current task an agent is, from 0.0 to 1.0, for long tasks such as constructing a building. Task systems update this themselves, the crate only reads it.
+/// Changes are reported with [`HtnTaskProgressed`] by [`system_report_task_progress`]
+#[derive(Component, Default, Clone, Copy, Debug, PartialEq)]
+pub struct HtnTaskProgress(pub f32);
+
+impl HtnTaskProgress {
+    /// The progress clamped between 0.0 and 1.0
+    pub fn fraction(&self) -> f32 {
+        self.0.clamp(0.0, 1.0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.0 >= 1.0
+    }
+}
+
+/// When the agent's current task was started, as the [`Time`] elapsed since startup. Used to complete tasks with a duration registered in the [`TaskRegistry`]
+#[derive(Component, Debug, Clone, Copy)]
+pub struct HtnAgentTaskStarted(pub Duration);
+
+/// The name of the goal that the agent's current plan was made for. Present for as long as the agent has an [`HtnAgentPlan`]
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct HtnAgentActiveGoal(pub String);
+
+/// The goal an agent is committed to. Unlike [`HtnAgentActiveGoal`], this outlasts plans which fail or are invalidated, and is only removed once a plan completes.
+/// See [`HtnAgent::commitment_margin`]
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct HtnAgentCommitment(pub String);
+
+/// How many agents are pursuing each goal, rebuilt every frame by [`system_count_agents_pursuing_goals`]. Useful for coordination, such as limiting how many agents attack at once
+#[derive(Resource, Default, Debug, Clone)]
+pub struct HtnGoalCounts(pub HashMap<String, usize>);
+
+impl HtnGoalCounts {
+    pub fn count_agents_pursuing(&self, goal: &str) -> usize {
+        self.0.get(goal).copied().unwrap_or_default()
+    }
+}
+
+pub fn system_count_agents_pursuing_goals(
+    query: Query<&HtnAgentActiveGoal>,
+    mut counts: ResMut<HtnGoalCounts>,
+) {
+    counts.0.clear();
+    for goal in query.iter() {
+        *counts.0.entry(goal.0.clone()).or_default() += 1;
+    }
+}
+
+/// Opts an agent into running independent plan steps at the same time (e.g. walking while reloading) rather than one at a time.
+/// Agents with this component are handled by [`system_handle_parallel_agent_tasks`] instead of [`system_handle_agent_state_changes`]
+#[derive(Component, Default)]
+pub struct HtnParallelExecution;
+
+/// The tasks an agent with [`HtnParallelExecution`] is currently running
+#[derive(Component, Debug, Clone)]
+pub struct HtnAgentCurrentTasks(pub Vec<String>);
+
+/// The state of each task in [`HtnAgentCurrentTasks`]. Task systems report success or failure here rather than through [`HtnAgentState`]
+#[derive(Component, Debug, Clone, Default)]
+pub struct HtnAgentTaskStates(pub HashMap<String, HtnAgentState>);
+
+impl HtnAgentTaskStates {
+    pub fn get(&self, task: &str) -> Option<HtnAgentState> {
+        self.0.get(task).copied()
+    }
+
+    pub fn succeed(&mut self, task: &str) {
+        self.0.insert(task.into(), HtnAgentState::Success);
+    }
+
+    pub fn fail(&mut self, task: &str) {
+        self.0.insert(task.into(), HtnAgentState::Failure);
+    }
+}
+
+/// Every component related to executing a plan. Removed when the plan completes, fails, or is invalidated
+pub type HtnPlanExecutionComponents = (
+    HtnAgentCurrentTask,
+    HtnAgentTaskStarted,
+    HtnAgentState,
+    HtnAgentPlan,
+    HtnAgentActiveGoal,
+    HtnAgentCurrentTasks,
+    HtnAgentTaskStates,
+);
+
+/// Every component this crate puts on agents, including those used for planning and configuration. Removed by [`despawn_htn_state`]
+pub type HtnAgentComponents = (
+    HtnPlanExecutionComponents,
+    (
+        HtnAgent,
+        HtnAgentWorld,
+        HtnAgentGroup,
+        HtnAgentCommitment,
+        HtnParallelExecution,
+        HtnAgentCooldowns,
+        HtnAgentIdle,
+        HtnAgentReplanInterval,
+        HtnBlackboard,
+        HtnTaskProgress,
+    ),
+    (
+        TimeSlicedTreeGen,
+        HtnAgentPlanStats,
+        HtnAgentPlanningPriority,
+        HtnAgentRng,
+        HtnAgentGoalTimers,
+        HtnAgentRestoring,
+        StaticTaskProvider,
+        StaticGoalProvider,
+        HtnAgentRole,
+    ),
+);
+
+/// Tears down the HTN state of the given entities, or of every agent when `None`, such as when leaving a scene. Running tasks are removed through the [`TaskRegistry`]
+/// so their cleanup logic still runs, then every component in [`HtnAgentComponents`] is removed. The entities themselves are kept.
+///
+/// From a system, use `commands.add(|world: &mut World| despawn_htn_state(world, None))`
+pub fn despawn_htn_state(world: &mut World, entities: Option<&[Entity]>) {
+    let targets: Vec<Entity> = match entities {
+        Some(entities) => entities
+            .iter()
+            .copied()
+            .filter(|e| world.get_entity(*e).is_some())
+            .collect(),
+        None => world
+            .query_filtered::<Entity, Or<(With<HtnAgent>, With<HtnAgentPlan>)>>()
+            .iter(world)
+            .collect(),
+    };
+    let mut running = Vec::new();
+    if let Some(registry) = world.get_resource::<TaskRegistry>() {
+        for entity in targets.iter() {
+            let task = world.get::<HtnAgentCurrentTask>(*entity).map(|t| &t.0);
+            let tasks = world.get::<HtnAgentCurrentTasks>(*entity);
+            for name in task
+                .into_iter()
+                .chain(tasks.into_iter().flat_map(|t| t.0.iter()))
+            {
+                if let Some(data) = registry.get_named(name) {
+                    running.push((*entity, data.clone()));
+                }
+            }
+        }
+    }
+    let mut commands = world.commands();
+    for (entity, data) in running {
+        data.remove(&mut commands.entity(entity));
+    }
+    for entity in targets {
+        commands.entity(entity).remove::<HtnAgentComponents>();
+    }
+    world.flush();
+}
+
+/// Tracks when each task was last executed by this agent, as the [`Time`] elapsed since startup. Tasks with a cooldown registered in the [`TaskRegistry`] are excluded from planning until it elapses
+#[derive(Component, Default, Clone, Debug)]
+pub struct HtnAgentCooldowns(pub HashMap<String, Duration>);
+
+#[derive(Component, PartialEq, Clone, Copy, Debug)]
 pub enum HtnAgentState {
     // TODO: should this be constructed in a way that allows observers?
     Running,
@@ -26,117 +401,595 @@ pub enum HtnAgentState {
     Failure,
 }
 
+impl From<AbortOutcome> for HtnAgentState {
+    fn from(value: AbortOutcome) -> Self {
+        match value {
+            AbortOutcome::Failure => HtnAgentState::Failure,
+            AbortOutcome::Success => HtnAgentState::Success,
+        }
+    }
+}
+
+/// Marks an agent that completed a plan under [`CompletionPolicy::Idle`]. The agent is not given a new plan until its world (or the global world) changes
+#[derive(Component, Debug, Default)]
+pub struct HtnAgentIdle;
+
+/// Rate-limits how often an agent is given a new plan: it is only considered every N frames while unplanned, trading responsiveness for throughput in large crowds
+#[derive(Component, Debug, Clone, Copy)]
+pub struct HtnAgentReplanInterval(pub u32);
+
+/// Removes expired entries (see [`WorldState::insert_expiring`]) from the global world, every group's shared world, and every agent's world.
+/// Worlds are only marked as changed when something expires, so agents replan only when a fact actually goes away
+pub fn system_expire_world_entries(
+    mut world: ResMut<WorldState>,
+    shared: Option<ResMut<HtnSharedWorld>>,
+    mut agents: Query<&mut HtnAgentWorld>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed();
+    if world.has_expired(now) {
+        for key in world.remove_expired(now) {
+            debug!("World entry '{}' expired", key.as_str());
+        }
+    }
+    if let Some(mut shared) = shared {
+        if shared.0.values().any(|w| w.has_expired(now)) {
+            for group_world in shared.0.values_mut() {
+                group_world.remove_expired(now);
+            }
+        }
+    }
+    for mut agent_world in agents.iter_mut() {
+        if agent_world.0.has_expired(now) {
+            agent_world.0.remove_expired(now);
+        }
+    }
+}
+
+/// Ends running tasks whose abort condition (see [`TaskRegistry::set_abort_condition`]) is met by the world the agent executes in.
+/// Each aborted task is given the state of its [`AbortOutcome`], to be handled as usual by [`system_handle_agent_state_changes`] or [`system_handle_parallel_agent_tasks`]
+#[allow(clippy::type_complexity)]
+pub fn system_abort_tasks(
+    mut query: Query<(
+        Entity,
+        Option<&HtnAgentState>,
+        Option<&HtnAgentCurrentTask>,
+        Option<&mut HtnAgentTaskStates>,
+        Option<&HtnAgentWorld>,
+        Option<&HtnAgentGroup>,
+    )>,
+    task_registry: Res<TaskRegistry>,
+    world: Option<Res<WorldState>>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    mut command: Commands,
+) {
+    if task_registry.abort_conditions.is_empty() {
+        return;
+    }
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    for (entity, state, task, states, agent_world, group) in query.iter_mut() {
+        let mut context = None;
+        let mut should_abort = |name: &String| {
+            let (condition, outcome) = task_registry.abort_condition(name)?;
+            let context = context.get_or_insert_with(|| {
+                execution_context(world.as_deref(), shared.as_deref(), group, agent_world)
+            });
+            condition.validate(context).then(|| {
+                debug!("Aborting task '{}' for entity {}", name, entity);
+                HtnAgentState::from(*outcome)
+            })
+        };
+        if let (Some(HtnAgentState::Running), Some(task)) = (state, task) {
+            if let Some(new_state) = should_abort(&task.0) {
+                command.entity(entity).insert(new_state);
+            }
+        }
+        if let Some(mut states) = states {
+            let aborted: Vec<_> = states
+                .0
+                .iter()
+                .filter(|(_, state)| **state == HtnAgentState::Running)
+                .filter_map(|(name, _)| Some((name.clone(), should_abort(name)?)))
+                .collect();
+            for (name, new_state) in aborted {
+                states.0.insert(name, new_state);
+            }
+        }
+    }
+}
+
+/// Removes [`HtnAgentIdle`] from agents whose world has changed since they went idle, allowing them to plan again
+pub fn system_wake_idle_agents(
+    query: Query<(Entity, Ref<HtnAgentIdle>, Option<Ref<HtnAgentWorld>>)>,
+    world: Res<WorldState>,
+    ticks: SystemChangeTick,
+    mut command: Commands,
+) {
+    for (entity, idle, agent_world) in query.iter() {
+        // the marker is never mutated, so its last change is when the agent went idle
+        let changed_since = |tick: Tick| tick.is_newer_than(idle.last_changed(), ticks.this_run());
+        if changed_since(world.last_changed())
+            || agent_world.is_some_and(|w| changed_since(w.last_changed()))
+        {
+            command.entity(entity).remove::<HtnAgentIdle>();
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub fn system_extract_plans_for_unplanned_agents(
-    query: Query<
+    mut query: Query<
         (
             Entity,
             &HtnAgent,
-            &TimeSlicedTreeGen,
+            &mut TimeSlicedTreeGen,
             Option<&HtnAgentWorld>,
             Option<&HtnAgentPlanningPriority>,
+            Option<&HtnAgentGoalTimers>,
+            Option<&HtnAgentRestoring>,
+            Option<&HtnAgentGroup>,
+            Option<&mut HtnAgentRng>,
+            Option<&HtnAgentReplanInterval>,
+            Option<&HtnAgentCommitment>,
         ),
-        Without<HtnAgentPlan>,
+        (Without<HtnAgentPlan>, Without<HtnAgentIdle>),
     >,
     world: Res<WorldState>,
+    shared: Option<Res<HtnSharedWorld>>,
     settings: Res<HtnSettings>,
+    mut frames_waited: Local<HashMap<Entity, u32>>,
+    time: Res<Time>,
     mut command: Commands,
 ) {
+    let _epsilon = NumberEpsilonGuard::new(settings.number_epsilon);
     let mut vec: Vec<(
         Entity,
         &HtnAgent,
-        &TimeSlicedTreeGen,
+        Mut<TimeSlicedTreeGen>,
         Option<&HtnAgentWorld>,
         Option<&HtnAgentPlanningPriority>,
-    )> = query.iter().collect();
+        Option<&HtnAgentGoalTimers>,
+        Option<&HtnAgentRestoring>,
+        Option<&HtnAgentGroup>,
+        Option<Mut<HtnAgentRng>>,
+        Option<&HtnAgentReplanInterval>,
+        Option<&HtnAgentCommitment>,
+    )> = query.iter_mut().collect();
 
     if !settings.disable_priority_sort.unwrap_or_default() {
         // TODO: someday this should be replaced by bevy's table sorting feature that is in development as of writing
-        vec.sort_by(|a, b| {
-            a.4.cloned()
-                .unwrap_or_default()
-                .0
-                .total_cmp(&b.4.cloned().unwrap_or_default().0)
-        });
+        let comparator = settings
+            .priority_comparator
+            .unwrap_or(|a, b| b.total_cmp(&a));
+        vec.sort_by(|a, b| comparator(a.4.map_or(0.0, |p| p.0), b.4.map_or(0.0, |p| p.0)));
     }
-    for (entity, agent, tree, ctx, _) in vec {
-        let mut agent_context = world.clone();
-        if let Some(w) = ctx {
-            agent_context.append(&w.0);
+    let mut extracted = 0;
+    let now = time.elapsed();
+    for (entity, agent, mut tree, ctx, _, timers, restoring, group, rng, interval, commitment) in
+        vec
+    {
+        if let Some(interval) = interval {
+            let waited = frames_waited.entry(entity).or_default();
+            *waited += 1;
+            if *waited < interval.0 {
+                continue;
+            }
+            *waited = 0;
         }
-        let Some(goal) = agent.get_next_goal(&agent_context) else {
+        let agent_context = compose_agent_world(&world, shared.as_deref(), group, ctx);
+        let mut seeded = None;
+        let rng = match (rng, settings.rng_seed) {
+            (Some(rng), _) => Some(rng.into_inner()),
+            (None, Some(seed)) => Some(seeded.insert(HtnAgentRng::new(seed, entity))),
+            (None, None) => None,
+        };
+        let next_goal = match (restoring, timers, rng) {
+            (Some(restoring), _, _) => Some(restoring.0.clone()),
+            (None, timers, Some(rng)) => {
+                agent.get_next_goal_seeded(&agent_context, timers.map(|t| (t, now)), rng)
+            }
+            (None, Some(timers), None) => agent.get_next_goal_timed(&agent_context, timers, now),
+            (None, None, None) => agent.get_next_goal(&agent_context),
+        };
+        let next_goal = match (next_goal, commitment, restoring) {
+            (Some(next), Some(commitment), None) => {
+                Some(agent.hold_commitment(next, &commitment.0, timers.map(|t| (t, now))))
+            }
+            (next, ..) => next,
+        };
+        if let Some(seeded) = seeded {
+            // keep the sequence going from here next time the agent picks a goal
+            command.entity(entity).insert(seeded);
+        }
+        let Some(goal) = next_goal else {
             continue;
         };
+        if restoring.is_none() && !tree.plans.contains_key(&goal.name) {
+            // the generator searches for its top goal unless told otherwise, which may not be the goal picked
+            tree.request_goal(&goal);
+        }
 
-        let Some(plan) = tree.plans.get(&goal.name) else {
+        let affordable = |plan: &&Plan| agent.max_plan_cost.is_none_or(|max| plan.cost <= max);
+        let Some((goal_name, plan)) = tree
+            .plans
+            .get(&goal.name)
+            .filter(affordable)
+            .map(|plan| (&goal.name, plan))
+            .or_else(|| {
+                let fallback = agent.fallback_goal.as_ref()?;
+                Some((
+                    &fallback.name,
+                    tree.plans.get(&fallback.name).filter(affordable)?,
+                ))
+            })
+        else {
             continue;
         };
-        command.entity(entity).insert(HtnAgentPlan {
-            plan_stack: plan.decompose_tasks(),
-        });
+        if settings
+            .plan_extraction_limit
+            .is_some_and(|limit| extracted >= limit)
+        {
+            break;
+        }
+        command.entity(entity).insert((
+            HtnAgentPlan::from_tasks(plan.tasks.iter().rev()),
+            HtnAgentActiveGoal(goal_name.clone()),
+            HtnAgentCommitment(goal.name.clone()),
+        ));
+        extracted += 1;
     }
+    // forget agents that have been planned or despawned since
+    frames_waited.retain(|entity, _| query.contains(*entity));
 }
 
+#[allow(clippy::type_complexity)]
 pub fn system_handle_agent_state_changes(
-    mut query: Query<(
-        Entity,
-        &mut HtnAgentPlan,
-        Option<&HtnAgentState>,
-        Option<&HtnAgentCurrentTask>,
-    )>,
+    mut query: Query<
+        (
+            Entity,
+            &mut HtnAgentPlan,
+            Option<&HtnAgentState>,
+            Option<&HtnAgentCurrentTask>,
+            Option<&HtnAgentTaskStarted>,
+            Option<&mut HtnAgentCooldowns>,
+            Option<&HtnAgent>,
+            Option<&HtnAgentWorld>,
+            Option<&HtnAgentGroup>,
+            Option<&HtnAgentActiveGoal>,
+        ),
+        Without<HtnParallelExecution>,
+    >,
     task_registry: Res<TaskRegistry>,
+    world: Option<Res<WorldState>>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    time: Res<Time>,
     mut command: Commands,
 ) {
-    for (entity, mut plan, state, task) in query.iter_mut() {
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    let skip_satisfied = settings
+        .as_ref()
+        .is_some_and(|s| s.skip_satisfied_tasks.unwrap_or_default());
+    let validate_start = settings.is_some_and(|s| s.validate_plan_start.unwrap_or_default());
+    let now = time.elapsed();
+
+    for (entity, mut plan, state, task, started, cooldowns, agent, agent_world, group, goal) in
+        query.iter_mut()
+    {
+        let context = || execution_context(world.as_deref(), shared.as_deref(), group, agent_world);
+        let mut state = state.copied();
+        if let (Some(HtnAgentState::Running), Some(task), Some(started)) = (state, task, started) {
+            // timed tasks finish on their own once their duration is up
+            if task_registry
+                .duration(&task.0)
+                .is_some_and(|duration| now.saturating_sub(started.0) >= duration)
+            {
+                state = Some(HtnAgentState::Success);
+            }
+        }
+        if skip_satisfied && matches!(state, None | Some(HtnAgentState::Success)) {
+            skip_satisfied_tasks(&mut plan, &task_registry, &context());
+        }
         if let Some(agent_state) = state {
             match agent_state {
                 // running states process as handled by that task ( user defined system(s) )
                 HtnAgentState::Running => continue,
                 // when a task succeeds, push this state. Old task removed and next task injected
                 HtnAgentState::Success => {
+                    if let Some(prev_task) = task {
+                        start_cooldowns(
+                            &mut command.entity(entity),
+                            cooldowns,
+                            &task_registry,
+                            [&prev_task.0],
+                            now,
+                        );
+                        try_remove_previous_task(
+                            &mut command.entity(entity),
+                            &task_registry,
+                            prev_task,
+                        );
+                    }
                     if let Some(next_task) = plan.plan_stack.pop() {
-                        if let Some(prev_task) = task {
-                            try_remove_previous_task(
-                                &mut command.entity(entity),
-                                &task_registry,
-                                prev_task,
-                            );
-                        }
-                        push_task_to_agent(next_task, &mut command.entity(entity), &task_registry);
+                        push_task_to_agent(
+                            next_task,
+                            &mut command.entity(entity),
+                            &task_registry,
+                            now,
+                        );
                     } else {
-                        command
-                            .entity(entity)
-                            .remove::<(HtnAgentCurrentTask, HtnAgentState, HtnAgentPlan)>();
+                        complete_plan(&mut command.entity(entity), agent, goal);
                     }
                 }
                 // When a task fails for some reason we push this state, which purges existing execution data
                 HtnAgentState::Failure => {
+                    if let Some(prev_task) = task {
+                        try_remove_previous_task(
+                            &mut command.entity(entity),
+                            &task_registry,
+                            prev_task,
+                        );
+                    }
+                    let task = task.map(|t| t.0.clone()).unwrap_or_default();
+                    for span in plan.current_macros() {
+                        debug!(
+                            "Macro task '{}' of entity {} failed at subtask '{}'",
+                            span.name, entity, task
+                        );
+                        command.trigger_targets(
+                            HtnMacroFailed {
+                                macro_name: span.name.clone(),
+                                task: task.clone(),
+                            },
+                            entity,
+                        );
+                    }
                     command
                         .entity(entity)
-                        .remove::<(HtnAgentCurrentTask, HtnAgentState, HtnAgentPlan)>();
+                        .remove::<HtnPlanExecutionComponents>();
                 }
             }
         } else if let Some(next_task) = plan.plan_stack.pop() {
-            push_task_to_agent(next_task, &mut command.entity(entity), &task_registry);
+            // the world may have changed between the frame the plan was made and now, so make sure it can still start
+            if validate_start
+                && task_registry
+                    .get_named(&next_task)
+                    .is_some_and(|data| !data.preconditions().validate(&context()))
+            {
+                debug!(
+                    "Rejecting plan for entity {}, the preconditions of '{}' no longer hold",
+                    entity, next_task
+                );
+                command
+                    .entity(entity)
+                    .remove::<HtnPlanExecutionComponents>();
+                continue;
+            }
+            push_task_to_agent(next_task, &mut command.entity(entity), &task_registry, now);
         } else {
             command
                 .entity(entity)
-                .remove::<(HtnAgentCurrentTask, HtnAgentState, HtnAgentPlan)>();
+                .remove::<HtnPlanExecutionComponents>();
             warn!("Failed to initialize a plan for entity {}", entity);
         }
     }
 }
 
+/// The parallel counterpart to [`system_handle_agent_state_changes`] for agents with [`HtnParallelExecution`].
+/// Consecutive plan steps that are independent of each other are started together, and the next batch begins once all of them succeed.
+/// If any task fails, every running task is removed and the plan is abandoned. Timed tasks and cooldowns behave as they do for sequential agents.
+///
+/// Note that concurrent tasks should use distinct components, as finishing one task removes its component
+#[allow(clippy::type_complexity)]
+pub fn system_handle_parallel_agent_tasks(
+    mut query: Query<
+        (
+            Entity,
+            &mut HtnAgentPlan,
+            Option<&mut HtnAgentCurrentTasks>,
+            Option<&HtnAgentTaskStates>,
+            Option<&HtnAgentTaskStarted>,
+            Option<&mut HtnAgentCooldowns>,
+            Option<&HtnAgent>,
+            Option<&HtnAgentWorld>,
+            Option<&HtnAgentGroup>,
+            Option<&HtnAgentActiveGoal>,
+        ),
+        With<HtnParallelExecution>,
+    >,
+    task_registry: Res<TaskRegistry>,
+    world: Option<Res<WorldState>>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    time: Res<Time>,
+    mut command: Commands,
+) {
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    let skip_satisfied = settings.is_some_and(|s| s.skip_satisfied_tasks.unwrap_or_default());
+    let now = time.elapsed();
+
+    for (entity, mut plan, current, states, started, cooldowns, agent, agent_world, group, goal) in
+        query.iter_mut()
+    {
+        let mut entity_commands = command.entity(entity);
+        if let Some(mut current) = current {
+            let states = states.cloned().unwrap_or_default();
+            if current
+                .0
+                .iter()
+                .any(|t| states.get(t) == Some(HtnAgentState::Failure))
+            {
+                for task in current.0.iter() {
+                    if let Some(data) = task_registry.get_named(task) {
+                        data.remove(&mut entity_commands);
+                    }
+                }
+                entity_commands.remove::<HtnPlanExecutionComponents>();
+                continue;
+            }
+            // every task in the batch started together, and timed tasks finish on their own once their duration is up
+            let timed_out = |task: &String| {
+                started.is_some_and(|started| {
+                    task_registry
+                        .duration(task)
+                        .is_some_and(|duration| now.saturating_sub(started.0) >= duration)
+                })
+            };
+            let (finished, running): (Vec<String>, Vec<String>) = current
+                .0
+                .drain(..)
+                .partition(|t| states.get(t) == Some(HtnAgentState::Success) || timed_out(t));
+            for task in finished.iter() {
+                if let Some(data) = task_registry.get_named(task) {
+                    data.remove(&mut entity_commands);
+                }
+            }
+            start_cooldowns(
+                &mut entity_commands,
+                cooldowns,
+                &task_registry,
+                &finished,
+                now,
+            );
+            current.0 = running;
+            if !current.0.is_empty() {
+                continue;
+            }
+        }
+
+        // start the next batch of independent tasks
+        if skip_satisfied {
+            let context =
+                execution_context(world.as_deref(), shared.as_deref(), group, agent_world);
+            skip_satisfied_tasks(&mut plan, &task_registry, &context);
+        }
+        let mut batch = Vec::<String>::new();
+        while let Some(next) = plan.plan_stack.last() {
+            if !batch.iter().all(|t| task_registry.are_independent(t, next)) {
+                break;
+            }
+            batch.push(plan.plan_stack.pop().unwrap());
+        }
+        if batch.is_empty() {
+            complete_plan(&mut entity_commands, agent, goal);
+            continue;
+        }
+        let mut states = HtnAgentTaskStates::default();
+        for task in batch.iter() {
+            if let Some(data) = task_registry.get_named(task) {
+                data.add(&mut entity_commands);
+            }
+            states.0.insert(task.clone(), HtnAgentState::Running);
+        }
+        entity_commands.insert((
+            HtnAgentCurrentTasks(batch),
+            states,
+            HtnAgentTaskStarted(now),
+        ));
+    }
+}
+
+/// Starts the cooldown (see [`TaskRegistry::set_cooldown`]) of each of the tasks the agent just finished
+fn start_cooldowns<'a>(
+    entity: &mut EntityCommands,
+    cooldowns: Option<Mut<HtnAgentCooldowns>>,
+    task_registry: &TaskRegistry,
+    finished: impl IntoIterator<Item = &'a String>,
+    now: Duration,
+) {
+    let mut started = finished
+        .into_iter()
+        .filter(|task| task_registry.cooldown(task).is_some())
+        .peekable();
+    if started.peek().is_none() {
+        return;
+    }
+    match cooldowns {
+        Some(mut c) => c.0.extend(started.map(|task| (task.clone(), now))),
+        None => {
+            entity.insert(HtnAgentCooldowns(
+                started.map(|task| (task.clone(), now)).collect(),
+            ));
+        }
+    }
+}
+
+/// The world an agent's tasks run against, the same one it plans against. See [`compose_agent_world`]
+fn execution_context(
+    world: Option<&WorldState>,
+    shared: Option<&HtnSharedWorld>,
+    group: Option<&HtnAgentGroup>,
+    agent_world: Option<&HtnAgentWorld>,
+) -> WorldState {
+    match world {
+        Some(world) => compose_agent_world(world, shared, group, agent_world),
+        None => compose_agent_world(&WorldState::default(), shared, group, agent_world),
+    }
+}
+
+/// Drops upcoming plan steps whose effects are already present in the agent's world, see [`HtnSettings::skip_satisfied_tasks`]
+fn skip_satisfied_tasks(
+    plan: &mut HtnAgentPlan,
+    task_registry: &TaskRegistry,
+    context: &WorldState,
+) {
+    while let Some(next) = plan.plan_stack.last() {
+        let Some(task) = task_registry.get_named(next) else {
+            return;
+        };
+        // a task without effects is run for its own sake, so it is never considered satisfied
+        if task.postconditions().is_empty() || !context.validate(task.postconditions()) {
+            return;
+        }
+        debug!(
+            "Skipping task '{}', its effects are already satisfied",
+            next
+        );
+        plan.plan_stack.pop();
+    }
+}
+
+/// Clears out the finished plan and applies the agent's [`CompletionPolicy`], triggering [`HtnPlanCompleted`] first
+fn complete_plan(
+    entity: &mut EntityCommands,
+    agent: Option<&HtnAgent>,
+    goal: Option<&HtnAgentActiveGoal>,
+) {
+    let id = entity.id();
+    entity.commands().trigger_targets(
+        HtnPlanCompleted {
+            goal: goal.map(|g| g.0.clone()).unwrap_or_default(),
+        },
+        id,
+    );
+    entity.remove::<(HtnPlanExecutionComponents, HtnAgentCommitment)>();
+    match agent.map(|a| a.on_complete).unwrap_or_default() {
+        CompletionPolicy::Replan => (),
+        CompletionPolicy::Idle => {
+            entity.insert(HtnAgentIdle);
+        }
+        CompletionPolicy::RemoveAgent => {
+            entity.remove::<HtnAgent>();
+        }
+    }
+}
+
 fn push_task_to_agent(
     task: String,
     entity: &mut EntityCommands,
     task_registry: &Res<TaskRegistry>,
+    now: Duration,
 ) {
     let Some(task_data) = task_registry.get_named(&task) else {
         return;
     };
     task_data.add(entity);
-    entity.insert((HtnAgentCurrentTask(task), HtnAgentState::Running));
+    entity.insert((
+        HtnAgentCurrentTask(task),
+        HtnAgentState::Running,
+        HtnAgentTaskStarted(now),
+    ));
 }
 
 fn try_remove_previous_task(
@@ -149,3 +1002,1518 @@ fn try_remove_previous_task(
     };
     task.remove(entity);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::prelude::*;
+
+    use crate::events::{HtnMacroFailed, HtnPlanCompleted};
+    use crate::planning::{
+        goals::{
+            system_monitor_maintenance_goals, Goal, GoalEvaluation, HtnAgentGoalTimers,
+            HtnAgentRestoring,
+        },
+        plan_data::{system_update_time_sliced_tree_gen, TimeSlicedTreeGen},
+    };
+    use crate::prelude::*;
+
+    #[derive(Component, Default)]
+    struct TaskStub;
+
+    #[test]
+    fn fallback_goal_planning() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "wander",
+            Requirements::new().req_equals("wandering", false).build(),
+            WorldState::new().add("wandering", true).build(),
+            1.,
+        );
+        let impossible = Goal::new(
+            "Fly",
+            Requirements::new().req_equals("flying", true).build(),
+            1.0,
+        );
+        let fallback = Goal::new(
+            "Wander",
+            Requirements::new().req_equals("wandering", true).build(),
+            0.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("wandering", false).build());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(
+            Update,
+            (
+                system_update_time_sliced_tree_gen,
+                system_extract_plans_for_unplanned_agents,
+            )
+                .chain(),
+        );
+
+        let mut agent = HtnAgent::new();
+        agent.add_goal(impossible.name.clone(), impossible.requires.clone(), 1.0);
+        agent.fallback(fallback);
+        let entity = app
+            .world_mut()
+            .spawn((
+                agent,
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("wander")],
+                    vec![impossible],
+                ),
+            ))
+            .id();
+        app.update();
+        app.update();
+
+        let plan = app
+            .world()
+            .get::<HtnAgentPlan>(entity)
+            .expect("Failed to plan for the fallback goal");
+        assert_eq!(plan.plan_stack, vec!["wander".to_string()]);
+    }
+
+    #[test]
+    fn plan_over_cost_ceiling_rejected() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "long_walk",
+            Requirements::new().req_equals("arrived", false).build(),
+            WorldState::new().add("arrived", true).build(),
+            5.,
+        );
+        let goal = Goal::new(
+            "Arrive",
+            Requirements::new().req_equals("arrived", true).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("arrived", false).build());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(
+            Update,
+            (
+                system_update_time_sliced_tree_gen,
+                system_extract_plans_for_unplanned_agents,
+            )
+                .chain(),
+        );
+
+        let mut agent = HtnAgent::new();
+        agent.add_goal(goal.name.clone(), goal.requires.clone(), 1.0);
+        agent.max_plan_cost = Some(3.0);
+        let entity = app
+            .world_mut()
+            .spawn((
+                agent,
+                TimeSlicedTreeGen::new_initialized(vec![Task::primitive("long_walk")], vec![goal]),
+            ))
+            .id();
+        app.update();
+        app.update();
+
+        let tree = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert_eq!(tree.plans.get("Arrive").map(|plan| plan.cost), Some(5.0));
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+
+        app.world_mut()
+            .get_mut::<HtnAgent>(entity)
+            .unwrap()
+            .max_plan_cost = Some(5.0);
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_some());
+    }
+
+    #[test]
+    fn plan_look_ahead() {
+        let mut registry = TaskRegistry::new();
+        for name in ["a", "b", "c"] {
+            registry.task::<TaskStub, _>(name, Requirements::new(), WorldState::new(), 1.);
+        }
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let entity = app
+            .world_mut()
+            .spawn(HtnAgentPlan::new(vec!["c".into(), "b".into(), "a".into()]))
+            .id();
+
+        let plan = app.world().get::<HtnAgentPlan>(entity).unwrap();
+        assert_eq!(plan.peek_next(), Some("a"));
+        assert_eq!(plan.remaining().len(), 3);
+        assert_eq!(plan.total_steps, 3);
+        assert_eq!(plan.progress(), 0.0);
+
+        let mut last_progress = 0.0;
+        for (current, next, remaining) in [("a", Some("b"), 2), ("b", Some("c"), 1), ("c", None, 0)]
+        {
+            app.update();
+            let current_task = app.world().get::<HtnAgentCurrentTask>(entity).unwrap();
+            assert_eq!(current_task.0, current);
+            let plan = app.world().get::<HtnAgentPlan>(entity).unwrap();
+            assert_eq!(plan.peek_next(), next);
+            assert_eq!(plan.remaining().len(), remaining);
+            assert_eq!(plan.total_steps, 3);
+            assert!(plan.progress() > last_progress);
+            last_progress = plan.progress();
+            app.world_mut()
+                .entity_mut(entity)
+                .insert(HtnAgentState::Success);
+        }
+        assert_eq!(last_progress, 1.0);
+    }
+
+    /// an app where agents can plan to eat, with only the tree gen and extraction systems running
+    fn hungry_app(settings: HtnSettings) -> (App, Goal) {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("hungry", true).build());
+        app.insert_resource(settings);
+        app.add_systems(
+            Update,
+            (
+                system_update_time_sliced_tree_gen,
+                system_extract_plans_for_unplanned_agents,
+            )
+                .chain(),
+        );
+        (app, goal)
+    }
+
+    fn spawn_hungry_agent(app: &mut App, goal: &Goal, priority: f32) -> Entity {
+        let mut agent = HtnAgent::new();
+        agent.add_goal(goal.name.clone(), goal.requires.clone(), 1.0);
+        app.world_mut()
+            .spawn((
+                agent,
+                HtnAgentPlanningPriority(priority),
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("eat")],
+                    vec![goal.clone()],
+                ),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn priority_extraction_order() {
+        let (mut app, goal) = hungry_app(HtnSettings {
+            plan_extraction_limit: Some(1),
+            ..Default::default()
+        });
+        let low = spawn_hungry_agent(&mut app, &goal, 1.0);
+        let high = spawn_hungry_agent(&mut app, &goal, 10.0);
+        let mid = spawn_hungry_agent(&mut app, &goal, 5.0);
+
+        let has_plan =
+            |app: &App, entity: Entity| app.world().get::<HtnAgentPlan>(entity).is_some();
+
+        app.update();
+        assert!(has_plan(&app, high));
+        assert!(!has_plan(&app, mid));
+        assert!(!has_plan(&app, low));
+
+        app.update();
+        assert!(has_plan(&app, mid));
+        assert!(!has_plan(&app, low));
+
+        app.update();
+        assert!(has_plan(&app, low));
+    }
+
+    #[test]
+    fn custom_priority_comparator() {
+        let (mut app, goal) = hungry_app(HtnSettings {
+            plan_extraction_limit: Some(1),
+            // lowest priority first
+            priority_comparator: Some(|a, b| a.total_cmp(&b)),
+            ..Default::default()
+        });
+        let low = spawn_hungry_agent(&mut app, &goal, 1.0);
+        let high = spawn_hungry_agent(&mut app, &goal, 10.0);
+
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(low).is_some());
+        assert!(app.world().get::<HtnAgentPlan>(high).is_none());
+    }
+
+    #[test]
+    fn last_task_removed_when_plan_ends() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>("wander", Requirements::new(), WorldState::new(), 1.);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.add_systems(Update, system_handle_agent_state_changes);
+
+        for outcome in [HtnAgentState::Success, HtnAgentState::Failure] {
+            let entity = app
+                .world_mut()
+                .spawn((HtnAgent::new(), HtnAgentPlan::new(vec!["wander".into()])))
+                .id();
+            app.update();
+            assert!(app.world().get::<TaskStub>(entity).is_some());
+
+            app.world_mut().entity_mut(entity).insert(outcome);
+            app.update();
+            assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+            assert!(app.world().get::<TaskStub>(entity).is_none());
+        }
+    }
+
+    #[test]
+    fn active_goal_lifecycle() {
+        let (mut app, goal) = hungry_app(HtnSettings::default());
+        app.add_systems(
+            Update,
+            system_handle_agent_state_changes.after(system_extract_plans_for_unplanned_agents),
+        );
+        let entity = spawn_hungry_agent(&mut app, &goal, 1.0);
+
+        app.update();
+        let active = app.world().get::<HtnAgentActiveGoal>(entity);
+        assert_eq!(active, Some(&HtnAgentActiveGoal(goal.name.clone())));
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_some());
+
+        // finish the only task, completing the plan
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentState::Success);
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("hungry", false);
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+        assert!(app.world().get::<HtnAgentActiveGoal>(entity).is_none());
+    }
+
+    /// A [`hungry_app`] where agents can also wander, returning the goals to eat and to wander. Wandering is the more useful of the two
+    fn wandering_app(settings: HtnSettings) -> (App, Goal, Goal) {
+        let (mut app, eat) = hungry_app(settings);
+        app.world_mut()
+            .resource_mut::<TaskRegistry>()
+            .task::<TaskStub, _>(
+                "wander",
+                Requirements::new(),
+                WorldState::new().add("wandered", true).build(),
+                1.,
+            );
+        let wander = Goal::new(
+            "Wander",
+            Requirements::new().req_equals("wandered", true).build(),
+            2.0,
+        );
+        app.add_systems(
+            Update,
+            system_handle_agent_state_changes.after(system_extract_plans_for_unplanned_agents),
+        );
+        (app, eat, wander)
+    }
+
+    /// Spawns an agent which can eat or wander. Its generator knows the agent's goals, but not how the agent will pick between them
+    fn spawn_wandering_agent(app: &mut App, agent: HtnAgent) -> Entity {
+        let tree = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("eat"), Task::primitive("wander")],
+            agent.goals.clone(),
+        );
+        app.world_mut().spawn((agent, tree)).id()
+    }
+
+    fn current_task(app: &App, entity: Entity) -> Option<&str> {
+        app.world()
+            .get::<HtnAgentCurrentTask>(entity)
+            .map(|t| t.0.as_str())
+    }
+
+    #[test]
+    fn urgent_goal_planned_and_executed() {
+        let (mut app, eat, wander) = wandering_app(HtnSettings::default());
+        let agent = HtnAgent {
+            goal_eval: GoalEvaluation::HighestUtility,
+            goals: vec![eat.clone().with_urgency(1.0), wander],
+            ..Default::default()
+        };
+        let entity = spawn_wandering_agent(&mut app, agent);
+        // hungry for long enough that eating has become more important than wandering
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentGoalTimers::starting_at(Duration::ZERO));
+        app.world_mut()
+            .resource_mut::<Time<Virtual>>()
+            .advance_by(Duration::from_secs(10));
+
+        app.update();
+        app.update();
+        let active = app.world().get::<HtnAgentActiveGoal>(entity);
+        assert_eq!(active, Some(&HtnAgentActiveGoal(eat.name.clone())));
+        assert_eq!(current_task(&app, entity), Some("eat"));
+    }
+
+    #[test]
+    fn scored_goal_planned_and_executed() {
+        use crate::planning::{goals::GoalScorer, system_collect_agent_goal_scores};
+        use bevy_trait_query::RegisterExt;
+
+        #[derive(Component)]
+        struct StarvingScorer;
+
+        impl GoalScorer for StarvingScorer {
+            fn score(&self, goal_name: &str, world: &WorldState) -> f32 {
+                let starving = world.get("starving") == Some(true.into());
+                if goal_name == "Be Not Hungry" && starving {
+                    5.0
+                } else {
+                    0.0
+                }
+            }
+        }
+
+        let (mut app, eat, wander) = wandering_app(HtnSettings::default());
+        app.register_component_as::<dyn GoalScorer, StarvingScorer>();
+        app.add_systems(
+            Update,
+            system_collect_agent_goal_scores.before(system_update_time_sliced_tree_gen),
+        );
+        // the squad is starving, which only the group's shared world knows
+        let mut shared = HtnSharedWorld::default();
+        shared.group_mut(1).insert("starving", true);
+        app.insert_resource(shared);
+        let agent = HtnAgent {
+            goal_eval: GoalEvaluation::HighestUtility,
+            goals: vec![eat.clone(), wander],
+            ..Default::default()
+        };
+        let entity = spawn_wandering_agent(&mut app, agent);
+        app.world_mut()
+            .entity_mut(entity)
+            .insert((StarvingScorer, HtnAgentGroup(1)));
+
+        app.update();
+        app.update();
+        let active = app.world().get::<HtnAgentActiveGoal>(entity);
+        assert_eq!(active, Some(&HtnAgentActiveGoal(eat.name.clone())));
+        assert_eq!(current_task(&app, entity), Some("eat"));
+    }
+
+    #[test]
+    fn commitment_holds_on_tie() {
+        let (mut app, eat, wander) = wandering_app(HtnSettings::default());
+        // eating is just as useful as wandering, and the agent already committed to it
+        let agent = HtnAgent {
+            goal_eval: GoalEvaluation::HighestUtility,
+            goals: vec![
+                Goal {
+                    utility: 2.0,
+                    ..eat.clone()
+                },
+                wander,
+            ],
+            ..Default::default()
+        };
+        let entity = spawn_wandering_agent(&mut app, agent);
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentCommitment(eat.name.clone()));
+
+        app.update();
+        app.update();
+        let active = app.world().get::<HtnAgentActiveGoal>(entity);
+        assert_eq!(active, Some(&HtnAgentActiveGoal(eat.name.clone())));
+        assert_eq!(current_task(&app, entity), Some("eat"));
+    }
+
+    #[test]
+    fn plan_completed_event() {
+        let (mut app, goal) = hungry_app(HtnSettings::default());
+        app.add_systems(
+            Update,
+            system_handle_agent_state_changes.after(system_extract_plans_for_unplanned_agents),
+        );
+
+        #[derive(Resource, Default)]
+        struct Completed(Vec<(Entity, String, bool)>);
+        app.init_resource::<Completed>();
+        app.observe(
+            |trigger: Trigger<HtnPlanCompleted>,
+             plans: Query<&HtnAgentPlan>,
+             mut completed: ResMut<Completed>| {
+                let entity = trigger.entity();
+                // the plan is still around when the event fires
+                let has_plan = plans.contains(entity);
+                completed
+                    .0
+                    .push((entity, trigger.event().goal.clone(), has_plan));
+            },
+        );
+        let entity = spawn_hungry_agent(&mut app, &goal, 1.0);
+
+        app.update();
+        assert!(app.world().resource::<Completed>().0.is_empty());
+
+        // finishing the only task completes the plan
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentState::Success);
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("hungry", false);
+        app.update();
+        assert_eq!(
+            app.world().resource::<Completed>().0,
+            vec![(entity, goal.name.clone(), true)]
+        );
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+    }
+
+    #[test]
+    fn parallel_tasks() {
+        #[derive(Component, Default)]
+        struct Walk;
+        #[derive(Component, Default)]
+        struct Reload;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<Walk, _>(
+            "walk",
+            Requirements::new().req_equals("at_cover", false).build(),
+            WorldState::new().add("at_cover", true).build(),
+            1.,
+        );
+        registry.task::<Reload, _>(
+            "reload",
+            Requirements::new().req_equals("loaded", false).build(),
+            WorldState::new().add("loaded", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "shoot",
+            Requirements::new()
+                .req_equals("at_cover", true)
+                .req_equals("loaded", true)
+                .build(),
+            WorldState::new().add("loaded", false).build(),
+            1.,
+        );
+        assert!(registry.are_independent(&"walk".into(), &"reload".into()));
+        assert!(!registry.are_independent(&"reload".into(), &"shoot".into()));
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.add_systems(
+            Update,
+            (
+                system_handle_agent_state_changes,
+                system_handle_parallel_agent_tasks,
+            ),
+        );
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnParallelExecution,
+                HtnAgentPlan::new(vec!["shoot".into(), "reload".into(), "walk".into()]),
+            ))
+            .id();
+
+        // walking and reloading start together
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTasks>(entity).unwrap();
+        assert_eq!(current.0, vec!["walk".to_string(), "reload".to_string()]);
+        assert!(app.world().get::<Walk>(entity).is_some());
+        assert!(app.world().get::<Reload>(entity).is_some());
+        assert!(app.world().get::<HtnAgentCurrentTask>(entity).is_none());
+
+        // reloading finishes first, walking carries on
+        app.world_mut()
+            .get_mut::<HtnAgentTaskStates>(entity)
+            .unwrap()
+            .succeed("reload");
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTasks>(entity).unwrap();
+        assert_eq!(current.0, vec!["walk".to_string()]);
+        assert!(app.world().get::<Reload>(entity).is_none());
+        assert!(app.world().get::<Walk>(entity).is_some());
+        assert!(app.world().get::<TaskStub>(entity).is_none());
+
+        // once walking finishes, shooting begins on its own
+        app.world_mut()
+            .get_mut::<HtnAgentTaskStates>(entity)
+            .unwrap()
+            .succeed("walk");
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTasks>(entity).unwrap();
+        assert_eq!(current.0, vec!["shoot".to_string()]);
+        assert!(app.world().get::<Walk>(entity).is_none());
+        assert!(app.world().get::<TaskStub>(entity).is_some());
+
+        app.world_mut()
+            .get_mut::<HtnAgentTaskStates>(entity)
+            .unwrap()
+            .succeed("shoot");
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+        assert!(app.world().get::<HtnAgentCurrentTasks>(entity).is_none());
+        assert!(app.world().get::<TaskStub>(entity).is_none());
+    }
+
+    #[test]
+    fn parallel_tasks_cooldowns_and_durations() {
+        #[derive(Component, Default)]
+        struct Walk;
+
+        let mut registry = TaskRegistry::new();
+        registry.timed_task::<Walk, _>(
+            "walk",
+            Requirements::new(),
+            WorldState::new().add("at_cover", true).build(),
+            1.,
+            Duration::from_secs(4),
+        );
+        registry.task::<TaskStub, _>(
+            "reload",
+            Requirements::new(),
+            WorldState::new().add("loaded", true).build(),
+            1.,
+        );
+        registry.set_cooldown("reload", Duration::from_secs(60));
+
+        let mut app = App::new();
+        app.insert_resource(Time::<()>::default());
+        app.insert_resource(registry);
+        app.add_systems(Update, system_handle_parallel_agent_tasks);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnParallelExecution,
+                HtnAgentPlan::new(vec!["reload".into(), "walk".into()]),
+            ))
+            .id();
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTasks>(entity).unwrap();
+        assert_eq!(current.0, vec!["walk".to_string(), "reload".to_string()]);
+
+        // reloading finishes and goes on cooldown
+        app.world_mut()
+            .get_mut::<HtnAgentTaskStates>(entity)
+            .unwrap()
+            .succeed("reload");
+        app.world_mut()
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(2));
+        app.update();
+        let cooldowns = app.world().get::<HtnAgentCooldowns>(entity).unwrap();
+        // cooldowns start at the game time the task finished, not the wall-clock time
+        assert_eq!(cooldowns.0.get("reload"), Some(&Duration::from_secs(2)));
+        assert!(!cooldowns.0.contains_key("walk"));
+        let current = app.world().get::<HtnAgentCurrentTasks>(entity).unwrap();
+        assert_eq!(current.0, vec!["walk".to_string()]);
+
+        // walking finishes on its own once its duration is up
+        app.world_mut()
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(2));
+        app.update();
+        assert!(app.world().get::<Walk>(entity).is_none());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+    }
+
+    /// runs the hungry agent through a complete plan with the given completion policy
+    fn complete_hungry_plan(policy: CompletionPolicy) -> (App, Entity) {
+        let (mut app, goal) = hungry_app(HtnSettings::default());
+        app.add_systems(
+            Update,
+            (
+                system_wake_idle_agents.before(system_update_time_sliced_tree_gen),
+                system_handle_agent_state_changes.after(system_extract_plans_for_unplanned_agents),
+            ),
+        );
+        let entity = spawn_hungry_agent(&mut app, &goal, 1.0);
+        app.world_mut()
+            .get_mut::<HtnAgent>(entity)
+            .unwrap()
+            .on_complete = policy;
+
+        app.update();
+        assert!(app.world().get::<TaskStub>(entity).is_some());
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentState::Success);
+        app.update();
+        assert!(app.world().get::<HtnAgentState>(entity).is_none());
+        (app, entity)
+    }
+
+    #[test]
+    fn completion_replan() {
+        let (mut app, entity) = complete_hungry_plan(CompletionPolicy::Replan);
+        assert!(app.world().get::<HtnAgentIdle>(entity).is_none());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_some());
+    }
+
+    #[test]
+    fn completion_idle() {
+        let (mut app, entity) = complete_hungry_plan(CompletionPolicy::Idle);
+        assert!(app.world().get::<HtnAgentIdle>(entity).is_some());
+        for _ in 0..3 {
+            app.update();
+            assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+        }
+
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("hungry", true);
+        app.update();
+        assert!(app.world().get::<HtnAgentIdle>(entity).is_none());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_some());
+    }
+
+    #[test]
+    fn completion_remove_agent() {
+        let (mut app, entity) = complete_hungry_plan(CompletionPolicy::RemoveAgent);
+        assert!(app.world().get::<HtnAgent>(entity).is_none());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+    }
+
+    #[test]
+    fn skip_satisfied_tasks() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "walk_through",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings {
+            skip_satisfied_tasks: Some(true),
+            ..Default::default()
+        });
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let entity = app
+            .world_mut()
+            .spawn((
+                // someone else opened the door since the plan was made
+                HtnAgentWorld(WorldState::new().add("door_open", true).build()),
+                HtnAgentPlan::new(vec!["walk_through".into(), "open_door".into()]),
+            ))
+            .id();
+
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTask>(entity).unwrap();
+        assert_eq!(current.0, "walk_through");
+        assert!(app
+            .world()
+            .get::<HtnAgentPlan>(entity)
+            .unwrap()
+            .remaining()
+            .is_empty());
+
+        // the group's shared world is part of the world the agent executes in
+        let mut shared = HtnSharedWorld::default();
+        shared.group_mut(1).insert("door_open", true);
+        app.insert_resource(shared);
+        let grouped = app
+            .world_mut()
+            .spawn((
+                HtnAgentGroup(1),
+                HtnAgentPlan::new(vec!["walk_through".into(), "open_door".into()]),
+            ))
+            .id();
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTask>(grouped).unwrap();
+        assert_eq!(current.0, "walk_through");
+
+        // without the setting, the redundant step is still executed
+        app.insert_resource(HtnSettings::default());
+        let other = app
+            .world_mut()
+            .spawn((
+                HtnAgentWorld(WorldState::new().add("door_open", true).build()),
+                HtnAgentPlan::new(vec!["walk_through".into(), "open_door".into()]),
+            ))
+            .id();
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTask>(other).unwrap();
+        assert_eq!(current.0, "open_door");
+    }
+
+    #[test]
+    fn blackboard_read_by_task_system() {
+        #[derive(Component, Default)]
+        struct Attack;
+
+        #[derive(Resource, Default)]
+        struct Attacked(Vec<Entity>);
+
+        fn system_attack(
+            query: Query<(Entity, &HtnBlackboard, &HtnAgentState), With<Attack>>,
+            mut attacked: ResMut<Attacked>,
+            mut command: Commands,
+        ) {
+            for (entity, blackboard, state) in query.iter() {
+                if *state != HtnAgentState::Running {
+                    continue;
+                }
+                if let Some(target) = blackboard.get::<Entity>("target") {
+                    attacked.0.push(*target);
+                    command.entity(entity).insert(HtnAgentState::Success);
+                }
+            }
+        }
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<Attack, _>("attack", Requirements::new(), WorldState::new(), 1.);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.init_resource::<Attacked>();
+        app.add_systems(
+            Update,
+            (system_handle_agent_state_changes, system_attack).chain(),
+        );
+        let target = app.world_mut().spawn_empty().id();
+        let mut blackboard = HtnBlackboard::new();
+        blackboard.set("target", target);
+        blackboard.set("last_seen", Vec3::new(1.0, 0.0, 2.0));
+        assert!(blackboard.get::<f32>("target").is_none());
+        assert_eq!(
+            blackboard.get::<Vec3>("last_seen"),
+            Some(&Vec3::new(1.0, 0.0, 2.0))
+        );
+        app.world_mut()
+            .spawn((blackboard, HtnAgentPlan::new(vec!["attack".into()])));
+
+        app.update();
+        assert_eq!(app.world().resource::<Attacked>().0, vec![target]);
+    }
+
+    #[test]
+    fn despawn_htn_state_removes_components() {
+        #[derive(Component, Default)]
+        struct Walk;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<Walk, _>("walk", Requirements::new(), WorldState::new(), 1.);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let spawn_agent = |app: &mut App| {
+            app.world_mut()
+                .spawn((
+                    HtnAgent::new(),
+                    HtnAgentWorld(WorldState::new()),
+                    TimeSlicedTreeGen::new(),
+                    HtnAgentCooldowns::default(),
+                    HtnAgentPlan::new(vec!["walk".into()]),
+                ))
+                .id()
+        };
+        let agent = spawn_agent(&mut app);
+        let other = spawn_agent(&mut app);
+        app.update();
+        assert!(app.world().get::<Walk>(agent).is_some());
+
+        despawn_htn_state(app.world_mut(), Some(&[agent]));
+        let entity = app.world().entity(agent);
+        assert!(!entity.contains::<Walk>());
+        assert!(!entity.contains::<HtnAgent>());
+        assert!(!entity.contains::<HtnAgentWorld>());
+        assert!(!entity.contains::<TimeSlicedTreeGen>());
+        assert!(!entity.contains::<HtnAgentCooldowns>());
+        assert!(!entity.contains::<HtnAgentPlan>());
+        assert!(!entity.contains::<HtnAgentCurrentTask>());
+        assert!(!entity.contains::<HtnAgentState>());
+        // only the given entities are torn down
+        assert!(app.world().get::<HtnAgent>(other).is_some());
+
+        despawn_htn_state(app.world_mut(), None);
+        let entity = app.world().entity(other);
+        assert!(!entity.contains::<Walk>());
+        assert!(!entity.contains::<HtnAgent>());
+        assert!(!entity.contains::<HtnAgentPlan>());
+    }
+
+    #[test]
+    fn abort_condition_ends_task() {
+        let mut registry = TaskRegistry::new();
+        for name in ["flee", "hide"] {
+            registry.task::<TaskStub, _>(name, Requirements::new(), WorldState::new(), 1.);
+        }
+        registry.set_abort_condition(
+            "flee",
+            Requirements::new().req_equals("safe", true).build(),
+            AbortOutcome::Failure,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.add_systems(
+            Update,
+            (system_abort_tasks, system_handle_agent_state_changes).chain(),
+        );
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgentWorld(WorldState::new().add("safe", false).build()),
+                HtnAgentPlan::new(vec!["hide".into(), "flee".into()]),
+            ))
+            .id();
+
+        app.update();
+        app.update();
+        assert_eq!(
+            app.world().get::<HtnAgentCurrentTask>(entity).unwrap().0,
+            "flee"
+        );
+        assert_eq!(
+            app.world().get::<HtnAgentState>(entity),
+            Some(&HtnAgentState::Running)
+        );
+
+        // reaching safety part way through makes fleeing pointless
+        app.world_mut()
+            .get_mut::<HtnAgentWorld>(entity)
+            .unwrap()
+            .0
+            .insert("safe", true);
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+        assert!(app.world().get::<HtnAgentCurrentTask>(entity).is_none());
+    }
+
+    #[test]
+    fn macro_subtask_failure_fails_macro() {
+        let mut registry = TaskRegistry::new();
+        for name in ["draw", "aim", "fire", "celebrate"] {
+            registry.task::<TaskStub, _>(name, Requirements::new(), WorldState::new(), 1.);
+        }
+
+        #[derive(Resource, Default)]
+        struct Failed(Vec<HtnMacroFailed>);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.init_resource::<Failed>();
+        app.observe(
+            |trigger: Trigger<HtnMacroFailed>, mut failed: ResMut<Failed>| {
+                failed.0.push(trigger.event().clone());
+            },
+        );
+        app.add_systems(Update, system_handle_agent_state_changes);
+
+        let shoot = Task::macro_(
+            [
+                Task::primitive("draw"),
+                Task::macro_(
+                    [Task::primitive("aim"), Task::primitive("fire")].into_iter(),
+                    "attack".into(),
+                ),
+            ]
+            .into_iter(),
+            "shoot".into(),
+        );
+        let plan = HtnAgentPlan::from_tasks(&[shoot, Task::primitive("celebrate")]);
+        assert_eq!(
+            plan.remaining(),
+            ["celebrate", "fire", "aim", "draw"].map(String::from)
+        );
+        let entity = app.world_mut().spawn(plan).id();
+
+        // "draw" succeeds, "aim" starts
+        app.update();
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentState::Success);
+        app.update();
+        assert_eq!(
+            app.world().get::<HtnAgentCurrentTask>(entity).unwrap().0,
+            "aim"
+        );
+        assert!(app.world().resource::<Failed>().0.is_empty());
+
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentState::Failure);
+        app.update();
+        let failed: Vec<_> = app
+            .world()
+            .resource::<Failed>()
+            .0
+            .iter()
+            .map(|f| (f.macro_name.as_str(), f.task.as_str()))
+            .collect();
+        assert_eq!(failed, vec![("attack", "aim"), ("shoot", "aim")]);
+        // the rest of the plan is abandoned along with the macro
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+    }
+
+    #[test]
+    fn stale_plan_rejected_at_start() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "walk_through",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("door_open", false).build());
+        app.insert_resource(HtnSettings {
+            validate_plan_start: Some(true),
+            ..Default::default()
+        });
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let spawn_plan = |app: &mut App| {
+            app.world_mut()
+                .spawn(HtnAgentPlan::new(vec![
+                    "walk_through".into(),
+                    "open_door".into(),
+                ]))
+                .id()
+        };
+
+        let valid = spawn_plan(&mut app);
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTask>(valid).unwrap();
+        assert_eq!(current.0, "open_door");
+
+        // someone else opened the door between the plan being made and it starting
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("door_open", true);
+        let stale = spawn_plan(&mut app);
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(stale).is_none());
+        assert!(app.world().get::<HtnAgentCurrentTask>(stale).is_none());
+        assert!(app.world().get::<TaskStub>(stale).is_none());
+
+        // numbers are compared with the same tolerance as when planning
+        app.world_mut()
+            .resource_mut::<TaskRegistry>()
+            .task::<TaskStub, _>(
+                "aim",
+                Requirements::new().req_equals("angle", 45.).build(),
+                WorldState::new(),
+                1.,
+            );
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("angle", 45.001);
+        app.world_mut().resource_mut::<HtnSettings>().number_epsilon = 0.01;
+        let aiming = app
+            .world_mut()
+            .spawn(HtnAgentPlan::new(vec!["aim".into()]))
+            .id();
+        app.update();
+        let current = app.world().get::<HtnAgentCurrentTask>(aiming).unwrap();
+        assert_eq!(current.0, "aim");
+    }
+
+    #[test]
+    fn maintenance_goal_preempts_plan() {
+        #[derive(Component, Default)]
+        struct Patrol;
+        #[derive(Component, Default)]
+        struct Heal;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<Patrol, _>(
+            "patrol",
+            Requirements::new().req_equals("patrolled", false).build(),
+            WorldState::new().add("patrolled", true).build(),
+            1.,
+        );
+        registry.task::<Heal, _>(
+            "heal",
+            Requirements::new().req_less("health", 50.).build(),
+            WorldState::new().add("health", 100.).build(),
+            1.,
+        );
+        let patrol = Goal::new(
+            "Patrol",
+            Requirements::new().req_equals("patrolled", true).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(
+            Update,
+            (
+                system_monitor_maintenance_goals,
+                system_update_time_sliced_tree_gen,
+                system_extract_plans_for_unplanned_agents,
+                system_handle_agent_state_changes,
+            )
+                .chain(),
+        );
+
+        let mut agent = HtnAgent::new();
+        agent.add_goal_req(patrol.name.clone(), patrol.requires.clone(), 1.0);
+        agent.add_maintenance_goal(
+            Requirements::new().req_greater("health", 50.).build(),
+            Goal::new(
+                "Heal",
+                Requirements::new().req_greater("health", 50.).build(),
+                1.0,
+            ),
+        );
+        let entity = app
+            .world_mut()
+            .spawn((
+                agent,
+                HtnAgentWorld(
+                    WorldState::new()
+                        .add("health", 100.)
+                        .add("patrolled", false)
+                        .build(),
+                ),
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("patrol"), Task::primitive("heal")],
+                    vec![patrol],
+                ),
+            ))
+            .id();
+
+        app.update();
+        assert!(app.world().get::<Patrol>(entity).is_some());
+
+        // taking damage mid-patrol preempts the plan
+        app.world_mut()
+            .get_mut::<HtnAgentWorld>(entity)
+            .unwrap()
+            .0
+            .insert("health", 20.);
+        app.update();
+        assert!(app.world().get::<HtnAgentRestoring>(entity).is_some());
+        assert!(app.world().get::<Patrol>(entity).is_none());
+        app.update();
+        assert_eq!(
+            app.world().get::<HtnAgentActiveGoal>(entity).unwrap().0,
+            "Heal"
+        );
+        assert!(app.world().get::<Heal>(entity).is_some());
+
+        // once healed, the agent goes back to patrolling
+        app.world_mut()
+            .get_mut::<HtnAgentWorld>(entity)
+            .unwrap()
+            .0
+            .insert("health", 100.);
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(HtnAgentState::Success);
+        app.update();
+        assert!(app.world().get::<HtnAgentRestoring>(entity).is_none());
+        app.update();
+        assert_eq!(
+            app.world().get::<HtnAgentActiveGoal>(entity).unwrap().0,
+            "Patrol"
+        );
+    }
+
+    #[test]
+    fn timed_task_completes() {
+        use std::time::Duration;
+
+        #[derive(Component, Default)]
+        struct Sleep;
+
+        let mut registry = TaskRegistry::new();
+        registry.timed_task::<Sleep, _>(
+            "sleep",
+            Requirements::new(),
+            WorldState::new().add("rested", true).build(),
+            1.,
+            Duration::from_secs(8),
+        );
+
+        // time is advanced by hand rather than by the time plugin so the test doesn't depend on the real clock
+        let mut app = App::new();
+        app.insert_resource(Time::<()>::default());
+        app.insert_resource(registry);
+        app.add_systems(Update, system_handle_agent_state_changes);
+        let entity = app
+            .world_mut()
+            .spawn(HtnAgentPlan::new(vec!["sleep".into()]))
+            .id();
+
+        app.update();
+        assert!(app.world().get::<Sleep>(entity).is_some());
+        for _ in 0..3 {
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs(2));
+            app.update();
+            assert_eq!(
+                app.world().get::<HtnAgentState>(entity),
+                Some(&HtnAgentState::Running)
+            );
+        }
+
+        app.world_mut()
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(2));
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+        assert!(app.world().get::<HtnAgentTaskStarted>(entity).is_none());
+    }
+
+    #[test]
+    fn goal_counts() {
+        let mut app = App::new();
+        app.init_resource::<HtnGoalCounts>();
+        app.add_systems(Update, system_count_agents_pursuing_goals);
+        let attackers: Vec<Entity> = (0..3)
+            .map(|_| {
+                app.world_mut()
+                    .spawn(HtnAgentActiveGoal("Attack".into()))
+                    .id()
+            })
+            .collect();
+        app.world_mut().spawn(HtnAgentActiveGoal("Heal".into()));
+        app.world_mut().spawn(HtnAgent::new());
+        app.update();
+
+        let counts = app.world().resource::<HtnGoalCounts>();
+        assert_eq!(counts.count_agents_pursuing("Attack"), 3);
+        assert_eq!(counts.count_agents_pursuing("Heal"), 1);
+        assert_eq!(counts.count_agents_pursuing("Flee"), 0);
+
+        // counts follow agents as they finish their plans
+        app.world_mut()
+            .entity_mut(attackers[0])
+            .remove::<HtnAgentActiveGoal>();
+        app.update();
+        let counts = app.world().resource::<HtnGoalCounts>();
+        assert_eq!(counts.count_agents_pursuing("Attack"), 2);
+    }
+
+    #[test]
+    fn world_entries_expire() {
+        use std::time::Duration;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+        let mut world = WorldState::new();
+        world.insert_expiring("heard_noise", true, Duration::from_secs(5));
+
+        let mut app = App::new();
+        app.insert_resource(Time::<()>::default());
+        app.insert_resource(registry);
+        app.insert_resource(world);
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(
+            Update,
+            (
+                system_expire_world_entries,
+                system_update_time_sliced_tree_gen,
+            )
+                .chain(),
+        );
+        let mut agent_world = WorldState::new().add("hungry", true).build();
+        agent_world.insert_expiring("alert", true, Duration::from_secs(3));
+        let mut shared = HtnSharedWorld::default();
+        shared
+            .group_mut(1)
+            .insert_expiring("squad_alert", true, Duration::from_secs(7));
+        app.insert_resource(shared);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentWorld(agent_world),
+                HtnAgentGroup(1),
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("eat")],
+                    vec![goal.clone()],
+                ),
+            ))
+            .id();
+        app.update();
+        let clear_plans = |app: &mut App| {
+            app.world_mut()
+                .get_mut::<TimeSlicedTreeGen>(entity)
+                .unwrap()
+                .plans
+                .clear();
+        };
+        let has_plan = |app: &App| {
+            app.world()
+                .get::<TimeSlicedTreeGen>(entity)
+                .unwrap()
+                .plans
+                .contains_key(&goal.name)
+        };
+        let advance = |app: &mut App, secs: u64| {
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs(secs));
+            app.update();
+        };
+        assert!(has_plan(&app));
+
+        // nothing has expired yet, so there is nothing new to plan for
+        clear_plans(&mut app);
+        advance(&mut app, 2);
+        assert!(!has_plan(&app));
+
+        // the agent's fact expires, which triggers a new search
+        advance(&mut app, 2);
+        let agent_world = &app.world().get::<HtnAgentWorld>(entity).unwrap().0;
+        assert_eq!(agent_world.get("alert"), None);
+        assert_eq!(agent_world.get("hungry"), Some(true.into()));
+        assert!(has_plan(&app));
+
+        // as does the global fact expiring
+        clear_plans(&mut app);
+        advance(&mut app, 2);
+        assert_eq!(
+            app.world().resource::<WorldState>().get("heard_noise"),
+            None
+        );
+        assert!(has_plan(&app));
+
+        // and the group's fact expiring
+        clear_plans(&mut app);
+        advance(&mut app, 2);
+        let shared = app.world().resource::<HtnSharedWorld>();
+        assert_eq!(shared.group(1).unwrap().get("squad_alert"), None);
+        assert!(has_plan(&app));
+    }
+
+    #[test]
+    fn replan_interval() {
+        let (mut app, goal) = hungry_app(HtnSettings::default());
+        let throttled = spawn_hungry_agent(&mut app, &goal, 0.0);
+        app.world_mut()
+            .entity_mut(throttled)
+            .insert(HtnAgentReplanInterval(3));
+        let unthrottled = spawn_hungry_agent(&mut app, &goal, 0.0);
+
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(unthrottled).is_some());
+        assert!(app.world().get::<HtnAgentPlan>(throttled).is_none());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(throttled).is_none());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(throttled).is_some());
+    }
+
+    #[test]
+    fn goal_commitment() {
+        use crate::planning::{goals::GoalEvaluation, plan_data::Plan};
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(TaskRegistry::new());
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_extract_plans_for_unplanned_agents);
+        let spawn = |app: &mut App, rest_utility: f32| {
+            let mut agent = HtnAgent {
+                goal_eval: GoalEvaluation::HighestUtility,
+                commitment_margin: 0.5,
+                ..Default::default()
+            };
+            agent.add_goal("Gather", Requirements::new(), 1.0);
+            agent.add_goal("Rest", Requirements::new(), rest_utility);
+            let mut tree = TimeSlicedTreeGen::new();
+            for (goal, task) in [("Gather", "gather"), ("Rest", "rest")] {
+                tree.plans.insert(
+                    goal.to_string(),
+                    Plan {
+                        tasks: vec![Task::primitive(task)].into(),
+                        cost: 1.0,
+                        ..Default::default()
+                    },
+                );
+            }
+            app.world_mut()
+                .spawn((
+                    agent,
+                    tree,
+                    // committed to gathering by a plan that was since invalidated
+                    HtnAgentCommitment("Gather".to_string()),
+                ))
+                .id()
+        };
+        let within_margin = spawn(&mut app, 1.4);
+        let beyond_margin = spawn(&mut app, 1.6);
+        app.update();
+
+        let goal_of = |entity: Entity| {
+            app.world()
+                .get::<HtnAgentActiveGoal>(entity)
+                .map(|g| g.0.clone())
+        };
+        assert_eq!(goal_of(within_margin), Some("Gather".to_string()));
+        assert_eq!(goal_of(beyond_margin), Some("Rest".to_string()));
+        assert_eq!(
+            app.world().get::<HtnAgentCommitment>(beyond_margin),
+            Some(&HtnAgentCommitment("Rest".to_string()))
+        );
+    }
+
+    #[test]
+    fn urgent_goal_preempts_plan() {
+        use crate::planning::{goals::system_preempt_plans, plan_data::Plan};
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>("wander", Requirements::new(), WorldState::new(), 1.);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings {
+            preempt_margin: Some(2.0),
+            ..Default::default()
+        });
+        app.add_systems(
+            Update,
+            (system_preempt_plans, system_handle_agent_state_changes).chain(),
+        );
+        let mut agent = HtnAgent {
+            goal_eval: GoalEvaluation::HighestUtility,
+            ..Default::default()
+        };
+        agent.add_goal("Wander", Requirements::new(), 1.0);
+        agent.add_goal("Flee", Requirements::new(), 2.5);
+        let entity = app
+            .world_mut()
+            .spawn((
+                agent,
+                HtnAgentPlan::new(vec!["wander".into(), "wander".into()]),
+                HtnAgentActiveGoal("Wander".to_string()),
+                HtnAgentCommitment("Wander".to_string()),
+                TimeSlicedTreeGen::new(),
+            ))
+            .id();
+
+        // fleeing is more important, but not by enough to interrupt
+        app.update();
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_some());
+        assert!(app.world().get::<TaskStub>(entity).is_some());
+
+        // danger appears
+        app.world_mut()
+            .get_mut::<HtnAgent>(entity)
+            .unwrap()
+            .goals
+            .iter_mut()
+            .find(|g| g.name == "Flee")
+            .unwrap()
+            .utility = 10.0;
+        app.update();
+        // there is no plan to flee with yet, so the agent keeps wandering while one is searched for
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_some());
+        let tree = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert_eq!(
+            tree.target_goal.as_ref().map(|g| g.name.as_str()),
+            Some("Flee")
+        );
+
+        app.world_mut()
+            .get_mut::<TimeSlicedTreeGen>(entity)
+            .unwrap()
+            .plans
+            .insert("Flee".to_string(), Plan::default());
+        app.update();
+        assert!(app.world().get::<HtnAgentPlan>(entity).is_none());
+        assert!(app.world().get::<TaskStub>(entity).is_none());
+        assert!(app.world().get::<HtnAgentCommitment>(entity).is_none());
+
+        // agents picking goals at random aren't preempted by whatever goal is drawn next
+        let mut agent = HtnAgent {
+            goal_eval: GoalEvaluation::Random,
+            ..Default::default()
+        };
+        agent.add_goal("Wander", Requirements::new(), 1.0);
+        agent.add_goal("Flee", Requirements::new(), 10.0);
+        let mut tree = TimeSlicedTreeGen::new();
+        tree.plans.insert("Flee".to_string(), Plan::default());
+        let random = app
+            .world_mut()
+            .spawn((
+                agent,
+                HtnAgentPlan::new(vec!["wander".into(), "wander".into()]),
+                HtnAgentActiveGoal("Wander".to_string()),
+                tree,
+            ))
+            .id();
+        for _ in 0..10 {
+            app.update();
+        }
+        assert!(app.world().get::<HtnAgentPlan>(random).is_some());
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "ron_assets")]
+pub mod assets;
 pub mod data;
 pub mod events;
 pub mod execution;
@@ -18,14 +20,19 @@ pub mod prelude {
     pub struct HtnPlanningPlugin {
         initial_world: Option<WorldState>,
         orchestrate: OrchestrateFor,
+        settings: Option<HtnSettings>,
     }
 
     impl Plugin for HtnPlanningPlugin {
         fn build(&self, app: &mut App) {
             app.insert_resource(self.initial_world.as_ref().cloned().unwrap_or_default());
             crate::data::plugin(app);
+            if let Some(settings) = &self.settings {
+                app.insert_resource(settings.clone());
+            }
             crate::tasks::plugin(app);
             crate::planning::plugin(app);
+            crate::execution::plugin(app);
             crate::orchestration::orchestrate_systems(app, &self.orchestrate);
         }
     }
@@ -39,6 +46,7 @@ pub mod prelude {
             Self {
                 initial_world: None,
                 orchestrate: Default::default(),
+                settings: None,
             }
         }
 
@@ -46,6 +54,7 @@ pub mod prelude {
             Self {
                 initial_world: Some(world.into()),
                 orchestrate: self.orchestrate,
+                settings: self.settings,
             }
         }
 
@@ -53,10 +62,49 @@ pub mod prelude {
             Self {
                 orchestrate: orch,
                 initial_world: self.initial_world,
+                settings: self.settings,
+            }
+        }
+
+        /// Installs custom settings for planning instead of the defaults
+        pub fn settings(self, settings: HtnSettings) -> Self {
+            Self {
+                settings: Some(settings),
+                initial_world: self.initial_world,
+                orchestrate: self.orchestrate,
             }
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::time::Duration;
+
+    use bevy::prelude::*;
+
+    use crate::prelude::*;
+
+    #[test]
+    fn plugin_settings() {
+        let settings = HtnSettings {
+            frame_processing_limit: Some(Duration::from_millis(2)),
+            node_branch_limit: Some(6),
+            disable_priority_sort: Some(true),
+            ..Default::default()
+        };
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            HtnPlanningPlugin::new().settings(settings.clone()),
+        ));
+        assert_eq!(*app.world().resource::<HtnSettings>(), settings);
+
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, HtnPlanningPlugin::new()));
+        assert_eq!(
+            *app.world().resource::<HtnSettings>(),
+            HtnSettings::default()
+        );
+    }
+}
@@ -1,10 +1,17 @@
+use crate::events::{system_report_achieved_goals, system_report_task_progress};
 use crate::execution::*;
+use crate::planning::goals::{
+    system_monitor_maintenance_goals, system_preempt_plans, system_update_goal_timers,
+};
 use crate::planning::plan_data::system_update_time_sliced_tree_gen;
+use crate::planning::system_collect_agent_goal_scores;
+#[cfg(not(feature = "static_provider_fast_path"))]
 use crate::planning::{
     system_collect_agent_goals_from_providers, system_collect_agent_tasks_from_providers,
 };
 use bevy::{
     app::{App, Update},
+    ecs::schedule::SystemConfigs,
     prelude::IntoSystemConfigs,
 };
 
@@ -19,16 +26,52 @@ pub enum OrchestrateFor {
     Custom,
 }
 
+#[cfg(feature = "static_provider_fast_path")]
+fn provider_collection_systems() -> SystemConfigs {
+    use crate::planning::{
+        system_collect_agent_goals_from_other_providers,
+        system_collect_agent_goals_from_static_provider,
+        system_collect_agent_tasks_from_other_providers,
+        system_collect_agent_tasks_from_static_provider,
+    };
+    (
+        system_collect_agent_tasks_from_other_providers,
+        system_collect_agent_goals_from_other_providers,
+        system_collect_agent_tasks_from_static_provider,
+        system_collect_agent_goals_from_static_provider,
+    )
+        .into_configs()
+}
+
+#[cfg(not(feature = "static_provider_fast_path"))]
+fn provider_collection_systems() -> SystemConfigs {
+    (
+        system_collect_agent_tasks_from_providers,
+        system_collect_agent_goals_from_providers,
+    )
+        .into_configs()
+}
+
 pub(crate) fn orchestrate_systems(app: &mut App, style: &OrchestrateFor) {
     match style {
         OrchestrateFor::ParallelProcessing => {
             app.add_systems(
                 Update,
                 (
-                    system_collect_agent_tasks_from_providers,
-                    system_collect_agent_goals_from_providers,
+                    provider_collection_systems(),
+                    system_expire_world_entries,
+                    system_collect_agent_goal_scores,
+                    system_update_goal_timers,
+                    system_monitor_maintenance_goals,
+                    system_preempt_plans,
+                    system_wake_idle_agents,
                     system_extract_plans_for_unplanned_agents,
+                    system_abort_tasks,
                     system_handle_agent_state_changes,
+                    system_handle_parallel_agent_tasks,
+                    system_count_agents_pursuing_goals,
+                    system_report_task_progress,
+                    system_report_achieved_goals,
                     system_update_time_sliced_tree_gen,
                 ), // no chaining means all systems run independently.
                    // This means some agents might not get a full processing sequence until a few frames later. Though it does allow beter multiprocessing
@@ -38,10 +81,20 @@ pub(crate) fn orchestrate_systems(app: &mut App, style: &OrchestrateFor) {
             app.add_systems(
                 Update,
                 (
-                    system_collect_agent_tasks_from_providers,
-                    system_collect_agent_goals_from_providers,
+                    provider_collection_systems(),
+                    system_expire_world_entries,
+                    system_collect_agent_goal_scores,
+                    system_update_goal_timers,
+                    system_monitor_maintenance_goals,
+                    system_preempt_plans,
+                    system_wake_idle_agents,
                     system_extract_plans_for_unplanned_agents,
+                    system_abort_tasks,
                     system_handle_agent_state_changes,
+                    system_handle_parallel_agent_tasks,
+                    system_count_agents_pursuing_goals,
+                    system_report_task_progress,
+                    system_report_achieved_goals,
                     system_update_time_sliced_tree_gen,
                 )
                     .chain(), // chaining ensures each system provides the requirements for the next for better response across frames
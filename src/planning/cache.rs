@@ -0,0 +1,299 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use bevy::prelude::Resource;
+
+use crate::{
+    data::{Requirements, WorldState},
+    tasks::Task,
+};
+
+use super::{goals::Goal, plan_data::Plan};
+
+/// Identifies a search: the goal and what satisfies it, the world it starts from, the tasks available to it, and the cache generation it was made in.
+/// Keys are hashed and compared by digests of the search, while the search itself is kept and checked on a hit, so a hash collision can't return a plan for a different search
+#[derive(Clone, Debug)]
+pub struct PlanCacheKey {
+    pub goal: String,
+    pub requires: u64,
+    pub world: u64,
+    pub tasks: u64,
+    pub generation: u64,
+    search: CachedSearch,
+}
+
+/// What a [`PlanCacheKey`]'s digests were made from
+#[derive(Clone, Debug, PartialEq)]
+struct CachedSearch {
+    requires: Requirements,
+    alternatives: Vec<Requirements>,
+    world: WorldState,
+    /// sorted by their digests
+    tasks: Vec<Task>,
+}
+
+impl PartialEq for PlanCacheKey {
+    fn eq(&self, other: &Self) -> bool {
+        (
+            &self.goal,
+            self.requires,
+            self.world,
+            self.tasks,
+            self.generation,
+        ) == (
+            &other.goal,
+            other.requires,
+            other.world,
+            other.tasks,
+            other.generation,
+        )
+    }
+}
+
+impl Eq for PlanCacheKey {}
+
+impl Hash for PlanCacheKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (
+            &self.goal,
+            self.requires,
+            self.world,
+            self.tasks,
+            self.generation,
+        )
+            .hash(state);
+    }
+}
+
+impl PlanCacheKey {
+    pub fn new(goal: &Goal, world: &WorldState, tasks: &[Task], generation: u64) -> Self {
+        let mut hasher = DefaultHasher::new();
+        goal.requires.hash(&mut hasher);
+        goal.alternatives.hash(&mut hasher);
+        let requires = hasher.finish();
+        let mut hasher = DefaultHasher::new();
+        world.hash(&mut hasher);
+        let world_hash = hasher.finish();
+        // the planner doesn't depend on the order tasks are given in, so neither does the key
+        let mut tasks: Vec<(u64, Task)> = tasks
+            .iter()
+            .map(|task| {
+                let mut hasher = DefaultHasher::new();
+                task.hash(&mut hasher);
+                (hasher.finish(), task.clone())
+            })
+            .collect();
+        tasks.sort_unstable_by_key(|(hash, _)| *hash);
+        let mut hasher = DefaultHasher::new();
+        for (hash, _) in tasks.iter() {
+            hash.hash(&mut hasher);
+        }
+        Self {
+            goal: goal.name.clone(),
+            requires,
+            world: world_hash,
+            tasks: hasher.finish(),
+            generation,
+            search: CachedSearch {
+                requires: goal.requires.clone(),
+                alternatives: goal.alternatives.clone(),
+                world: world.clone(),
+                tasks: tasks.into_iter().map(|(_, task)| task).collect(),
+            },
+        }
+    }
+}
+
+/// A cached plan, linked by index to the entries used just before and after it
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    key: PlanCacheKey,
+    plan: Plan,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+/// Plans shared between agents, so agents in identical situations don't each search for the same plan.
+/// Insert this resource to enable caching in [`system_update_time_sliced_tree_gen`](super::plan_data::system_update_time_sliced_tree_gen).
+/// Once full, the least recently used plan is evicted. Plans cached before the [`TaskRegistry`](crate::tasks::TaskRegistry) or [`HtnSettings`](crate::data::HtnSettings) last changed are never used
+#[derive(Resource, Debug, Clone)]
+pub struct HtnPlanCache {
+    capacity: usize,
+    index: HashMap<PlanCacheKey, usize>,
+    entries: Vec<CacheEntry>,
+    /// the least recently used entry
+    oldest: Option<usize>,
+    /// the most recently used entry
+    newest: Option<usize>,
+    generation: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl HtnPlanCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            index: HashMap::new(),
+            entries: Vec::new(),
+            oldest: None,
+            newest: None,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &PlanCacheKey) -> Option<Plan> {
+        let Some(&entry) = self
+            .index
+            .get(key)
+            .filter(|&&entry| self.entries[entry].key.search == key.search)
+        else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.touch(entry);
+        Some(self.entries[entry].plan.clone())
+    }
+
+    pub fn insert(&mut self, key: PlanCacheKey, plan: Plan) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&entry) = self.index.get(&key) {
+            // on a hash collision, the newer search takes the entry over
+            self.entries[entry].key = key;
+            self.entries[entry].plan = plan;
+            self.touch(entry);
+            return;
+        }
+        let new_entry = CacheEntry {
+            key: key.clone(),
+            plan,
+            prev: None,
+            next: None,
+        };
+        let entry = match self.oldest {
+            // full, so the least recently used entry makes way
+            Some(oldest) if self.entries.len() >= self.capacity => {
+                self.unlink(oldest);
+                self.index.remove(&self.entries[oldest].key);
+                self.entries[oldest] = new_entry;
+                oldest
+            }
+            _ => {
+                self.entries.push(new_entry);
+                self.entries.len() - 1
+            }
+        };
+        self.index.insert(key, entry);
+        self.link_newest(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.entries.clear();
+        self.oldest = None;
+        self.newest = None;
+    }
+
+    /// Keys made from this generation onwards, see [`PlanCacheKey::new`]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Starts a new generation, so plans cached so far are never used again. They are evicted as newer plans are cached
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Marks the entry as the most recently used
+    fn touch(&mut self, entry: usize) {
+        if self.newest != Some(entry) {
+            self.unlink(entry);
+            self.link_newest(entry);
+        }
+    }
+
+    fn unlink(&mut self, entry: usize) {
+        let CacheEntry { prev, next, .. } = self.entries[entry];
+        match prev {
+            Some(prev) => self.entries[prev].next = next,
+            None => self.oldest = next,
+        }
+        match next {
+            Some(next) => self.entries[next].prev = prev,
+            None => self.newest = prev,
+        }
+        self.entries[entry].prev = None;
+        self.entries[entry].next = None;
+    }
+
+    fn link_newest(&mut self, entry: usize) {
+        self.entries[entry].prev = self.newest;
+        match self.newest {
+            Some(newest) => self.entries[newest].next = Some(entry),
+            None => self.oldest = Some(entry),
+        }
+        self.newest = Some(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(goal: &'static str) -> PlanCacheKey {
+        let goal = Goal::new(goal, WorldState::new().add(goal, true).build(), 1.0);
+        PlanCacheKey::new(&goal, &WorldState::new(), &[], 0)
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let mut cache = HtnPlanCache::new(2);
+        cache.insert(key("a"), Plan::default());
+        cache.insert(key("b"), Plan::default());
+        // using "a" leaves "b" as the least recently used
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("c"), Plan::default());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("c")).is_some());
+
+        // replacing a plan counts as using it
+        cache.insert(key("a"), Plan::default());
+        cache.insert(key("d"), Plan::default());
+        assert!(cache.get(&key("c")).is_none());
+        assert_eq!((cache.hits, cache.misses), (3, 2));
+
+        // keys from an older generation are never matched
+        cache.invalidate();
+        let current = PlanCacheKey {
+            generation: cache.generation(),
+            ..key("a")
+        };
+        assert!(cache.get(&current).is_none());
+
+        // a key whose digests collide with a cached key's, but for a different search, doesn't match it
+        cache.insert(key("e"), Plan::default());
+        let collision = PlanCacheKey {
+            search: key("f").search,
+            ..key("e")
+        };
+        assert!(cache.get(&collision).is_none());
+        assert!(cache.get(&key("e")).is_some());
+    }
+}
@@ -1,6 +1,20 @@
-use rand::{distributions::WeightedIndex, prelude::Distribution, seq::IteratorRandom, thread_rng};
+use std::{collections::HashMap, time::Duration};
 
-use crate::data::{Requirements, WorldState};
+use bevy::prelude::*;
+use rand::{
+    distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, seq::IteratorRandom,
+    thread_rng, Rng, SeedableRng,
+};
+
+use crate::{
+    data::{HtnSettings, NumberEpsilonGuard, Requirements, WorldState},
+    events::HtnPlanInvalidated,
+    execution::{
+        compose_agent_world, HtnAgentActiveGoal, HtnAgentCommitment, HtnAgentGroup, HtnAgentPlan,
+        HtnAgentWorld, HtnSharedWorld,
+    },
+    planning::{plan_data::TimeSlicedTreeGen, HtnAgent},
+};
 
 #[derive(Default)]
 pub enum GoalEvaluation {
@@ -10,34 +24,86 @@ pub enum GoalEvaluation {
     RandomWeighted,
     #[default]
     Top,
+    /// Picks the goal with the highest utility, which includes any urgency when the agent has [`HtnAgentGoalTimers`]
+    HighestUtility,
 
     Custom(fn(&Vec<Goal>, &WorldState) -> Option<Goal>),
 }
 
 impl GoalEvaluation {
+    /// Whether goals are picked at random, so that picking again may give a different goal in the same world
+    pub fn is_random(&self) -> bool {
+        matches!(
+            self,
+            GoalEvaluation::Random | GoalEvaluation::RandomWeighted
+        )
+    }
+
     pub fn next_goal(&self, goals: &Vec<Goal>, world: &WorldState) -> Option<Goal> {
+        self.next_goal_with_rng(goals, world, &mut thread_rng())
+    }
+
+    /// Like [`GoalEvaluation::next_goal`], but random choices are drawn from `rng`
+    pub fn next_goal_with_rng(
+        &self,
+        goals: &Vec<Goal>,
+        world: &WorldState,
+        rng: &mut impl Rng,
+    ) -> Option<Goal> {
         if goals.is_empty() {
             return None;
         }
         match *self {
             GoalEvaluation::Top => goals.first().cloned(),
+            GoalEvaluation::HighestUtility => goals
+                .iter()
+                .max_by(|a, b| a.utility.total_cmp(&b.utility))
+                .cloned(),
             GoalEvaluation::Custom(f) => f(goals, world),
-            GoalEvaluation::Random => goals.iter().choose(&mut thread_rng()).cloned(),
+            GoalEvaluation::Random => goals.iter().choose(rng).cloned(),
             GoalEvaluation::RandomWeighted => {
                 let Ok(distribution) = WeightedIndex::new(goals.iter().map(|g| g.utility)) else {
                     return None;
                 };
-                goals.get(distribution.sample(&mut thread_rng())).cloned()
+                goals.get(distribution.sample(rng)).cloned()
             }
         }
     }
 }
 
+/// The source of an agent's random goal choices. Seeded from a seed and the agent's entity, so replays are reproducible while agents still choose differently.
+/// Inserted automatically when [`HtnSettings::rng_seed`](crate::data::HtnSettings::rng_seed) is set
+#[derive(Component, Clone)]
+pub struct HtnAgentRng(pub StdRng);
+
+impl HtnAgentRng {
+    pub fn new(seed: u64, entity: Entity) -> Self {
+        Self(StdRng::seed_from_u64(seed ^ entity.to_bits()))
+    }
+
+    /// Restarts the sequence of choices, as if the agent had just been seeded
+    pub fn reset(&mut self, seed: u64, entity: Entity) {
+        *self = Self::new(seed, entity);
+    }
+}
+
+#[bevy_trait_query::queryable]
+/// Implement this trait on a component to contribute to the utility of an agent's goals, in the style of utility AI scorers.
+/// Register it with `register_component_as::<dyn GoalScorer, _>()`, the same as a [`TaskProvider`](crate::planning::providers::TaskProvider)
+pub trait GoalScorer {
+    /// Added onto the goal's utility when the agent picks its next goal. Return 0.0 for goals this scorer has no opinion on
+    fn score(&self, goal_name: &str, world: &WorldState) -> f32;
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Goal {
     pub name: String,
     pub requires: Requirements,
     pub utility: f32, // TODO: replace with some kind of function reference or boxed closure
+    /// Utility gained per second since the goal was last satisfied. Only applies to agents with [`HtnAgentGoalTimers`]
+    pub urgency: f32,
+    /// Other target states which also satisfy the goal, so the goal is met when `requires` or any of these hold
+    pub alternatives: Vec<Requirements>,
 }
 
 impl Goal {
@@ -46,6 +112,269 @@ impl Goal {
             name: name.into(),
             requires: requires.into(),
             utility,
+            urgency: 0.0,
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// A goal satisfied by any one of several target states, such as being in cover, having fled, or the enemy being dead
+    pub fn any_of<R: Into<Requirements>>(
+        name: impl Into<String>,
+        targets: impl IntoIterator<Item = R>,
+        utility: f32,
+    ) -> Self {
+        let mut targets = targets.into_iter().map(Into::into);
+        let requires = targets.next().unwrap_or_default();
+        Self {
+            alternatives: targets.collect(),
+            ..Self::new(name, requires, utility)
+        }
+    }
+
+    /// Adds another target state which satisfies the goal
+    pub fn or(mut self, requires: impl Into<Requirements>) -> Self {
+        self.alternatives.push(requires.into());
+        self
+    }
+
+    /// Every target state of the goal, starting with `requires`
+    pub fn targets(&self) -> impl Iterator<Item = &Requirements> {
+        std::iter::once(&self.requires).chain(self.alternatives.iter())
+    }
+
+    /// Whether any of the goal's target states hold in `world`
+    pub fn is_satisfied(&self, world: &WorldState) -> bool {
+        self.targets().any(|target| target.validate(world))
+    }
+
+    pub fn with_urgency(mut self, per_second: f32) -> Self {
+        self.urgency = per_second;
+        self
+    }
+
+    /// The utility of this goal once `elapsed` time has passed since it was last satisfied
+    pub fn utility_after(&self, elapsed: Duration) -> f32 {
+        self.utility + self.urgency * elapsed.as_secs_f32()
+    }
+}
+
+/// Tracks when each of an agent's goals was last satisfied, so that goals with [`Goal::urgency`] grow more important the longer they are ignored.
+/// Goals that have never been satisfied count from when the timers started. Times are the [`Time`] elapsed since startup
+#[derive(Component, Debug, Clone, Default)]
+pub struct HtnAgentGoalTimers {
+    /// when the timers started. Left as `None`, it is set by [`system_update_goal_timers`] the first time it sees the timers
+    pub started: Option<Duration>,
+    pub last_satisfied: HashMap<String, Duration>,
+}
+
+impl HtnAgentGoalTimers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timers which started at the given time, rather than when first updated
+    pub fn starting_at(started: Duration) -> Self {
+        Self {
+            started: Some(started),
+            ..Default::default()
+        }
+    }
+
+    pub fn satisfy(&mut self, goal: impl Into<String>, at: Duration) {
+        self.last_satisfied.insert(goal.into(), at);
+    }
+
+    /// How long the goal has gone unsatisfied. Zero until the timers have started
+    pub fn elapsed(&self, goal: &str, now: Duration) -> Duration {
+        let since = self.last_satisfied.get(goal).copied().or(self.started);
+        now.saturating_sub(since.unwrap_or(now))
+    }
+
+    /// The agent's goals with their utility adjusted by how long each has gone unsatisfied
+    pub fn apply(&self, goals: &[Goal], now: Duration) -> Vec<Goal> {
+        goals
+            .iter()
+            .map(|g| Goal {
+                utility: g.utility_after(self.elapsed(&g.name, now)),
+                ..g.clone()
+            })
+            .collect()
+    }
+}
+
+/// Marks each goal that currently holds in the agent's world as satisfied, resetting its urgency
+#[allow(clippy::type_complexity)]
+pub fn system_update_goal_timers(
+    mut query: Query<(
+        &HtnAgent,
+        Option<&HtnAgentWorld>,
+        Option<&HtnAgentGroup>,
+        &mut HtnAgentGoalTimers,
+    )>,
+    world: Res<WorldState>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    time: Res<Time>,
+) {
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    let now = time.elapsed();
+    for (agent, agent_world, group, mut timers) in query.iter_mut() {
+        if timers.started.is_none() {
+            timers.started = Some(now);
+        }
+        let context = compose_agent_world(&world, shared.as_deref(), group, agent_world);
+        for goal in agent.goals.iter() {
+            if goal.is_satisfied(&context) {
+                timers.satisfy(goal.name.clone(), now);
+            }
+        }
+    }
+}
+
+/// A condition the agent must keep satisfied. Whenever the world violates it, the agent drops what it is doing and plans for `restore_goal` instead
+#[derive(Clone, Debug)]
+pub struct MaintenanceGoal {
+    pub condition: Requirements,
+    pub restore_goal: Goal,
+}
+
+impl MaintenanceGoal {
+    pub fn new(condition: impl Into<Requirements>, restore_goal: Goal) -> Self {
+        Self {
+            condition: condition.into(),
+            restore_goal,
+        }
+    }
+}
+
+/// Present while an agent is restoring a violated [`MaintenanceGoal`]. Holds the goal being planned for in place of the agent's usual goals
+#[derive(Component, Clone, Debug)]
+pub struct HtnAgentRestoring(pub Goal);
+
+/// Checks each agent's maintenance goals, preempting the current plan when one is violated. Once every condition holds again the agent returns to its usual goals
+#[allow(clippy::type_complexity)]
+pub fn system_monitor_maintenance_goals(
+    query: Query<(
+        Entity,
+        &HtnAgent,
+        Option<&HtnAgentWorld>,
+        Option<&HtnAgentGroup>,
+        Option<&HtnAgentRestoring>,
+    )>,
+    world: Res<WorldState>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    mut command: Commands,
+) {
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    for (entity, agent, agent_world, group, restoring) in query.iter() {
+        if agent.maintenance_goals.is_empty() {
+            continue;
+        }
+        let context = compose_agent_world(&world, shared.as_deref(), group, agent_world);
+        let violated = agent
+            .maintenance_goals
+            .iter()
+            .find(|m| !m.condition.validate(&context));
+        match (violated, restoring) {
+            (None, Some(_)) => {
+                command.entity(entity).remove::<HtnAgentRestoring>();
+            }
+            (Some(m), restoring) if restoring.map(|r| &r.0.name) != Some(&m.restore_goal.name) => {
+                debug!(
+                    "Maintenance condition violated for {}, restoring with '{}'",
+                    entity, m.restore_goal.name
+                );
+                command
+                    .entity(entity)
+                    .insert(HtnAgentRestoring(m.restore_goal.clone()));
+                command.trigger_targets(HtnPlanInvalidated, entity);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Re-evaluates the next goal of each agent that is executing a plan, invalidating the plan when another goal's utility beats the active goal's by more than [`HtnSettings::preempt_margin`].
+/// The plan is only invalidated once the agent's [`TimeSlicedTreeGen`] has a plan for the new goal, which it is asked to search for until then.
+/// The agent's commitment is dropped too, so it is free to switch. This is the proactive counterpart to [`HtnAgent::commitment_margin`].
+/// Agents restoring a maintenance goal are left alone, as are agents picking goals at random, which would otherwise be preempted by a fresh draw on any frame
+#[allow(clippy::type_complexity)]
+pub fn system_preempt_plans(
+    mut query: Query<
+        (
+            Entity,
+            &HtnAgent,
+            &HtnAgentActiveGoal,
+            &mut TimeSlicedTreeGen,
+            Option<Ref<HtnAgentWorld>>,
+            Option<Ref<HtnAgentGroup>>,
+            Option<&HtnAgentGoalTimers>,
+        ),
+        (With<HtnAgentPlan>, Without<HtnAgentRestoring>),
+    >,
+    world: Res<WorldState>,
+    shared: Option<Res<HtnSharedWorld>>,
+    settings: Option<Res<HtnSettings>>,
+    mut contexts: Local<HashMap<Entity, WorldState>>,
+    time: Res<Time>,
+    mut command: Commands,
+) {
+    // forget agents that have finished their plan or been despawned since
+    contexts.retain(|entity, _| query.contains(*entity));
+    let Some(margin) = settings.as_ref().and_then(|s| s.preempt_margin) else {
+        contexts.clear();
+        return;
+    };
+    let _epsilon = NumberEpsilonGuard::from_settings(settings.as_deref());
+    let now = time.elapsed();
+    let layers_changed = world.is_changed() || shared.as_ref().is_some_and(|s| s.is_changed());
+    for (entity, agent, active, mut tree, agent_world, group, timers) in query.iter_mut() {
+        if agent.goal_eval.is_random() {
+            continue;
+        }
+        // the composed world is kept between frames, as utilities change every frame but the world rarely does
+        if layers_changed
+            || agent_world.as_ref().is_some_and(|w| w.is_changed())
+            || group.as_ref().is_some_and(|g| g.is_changed())
+            || !contexts.contains_key(&entity)
+        {
+            let context = compose_agent_world(
+                &world,
+                shared.as_deref(),
+                group.as_deref(),
+                agent_world.as_deref(),
+            );
+            contexts.insert(entity, context);
+        }
+        let context = &contexts[&entity];
+        let next = match timers {
+            Some(timers) => agent.get_next_goal_timed(context, timers, now),
+            None => agent.get_next_goal(context),
+        };
+        let Some(next) = next else {
+            continue;
+        };
+        if next.name == active.0 {
+            continue;
+        }
+        let current = agent
+            .effective_goal(&active.0, timers.map(|t| (t, now)))
+            .map(|g| g.utility)
+            .unwrap_or(f32::NEG_INFINITY);
+        if next.utility <= current + margin {
+            continue;
+        }
+        if !tree.plans.contains_key(&next.name) {
+            // keep executing until there is something to switch to
+            tree.request_goal(&next);
+            continue;
         }
+        debug!(
+            "Goal '{}' preempts '{}' for entity {}",
+            next.name, active.0, entity
+        );
+        command.entity(entity).remove::<HtnAgentCommitment>();
+        command.trigger_targets(HtnPlanInvalidated, entity);
     }
 }
@@ -1,17 +1,25 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
 use bevy::{
     app::App,
     ecs::component::{ComponentHooks, StorageType},
-    prelude::{Component, Query},
+    prelude::{Component, Entity, Local, Query, Res, Without},
 };
-use goals::{Goal, GoalEvaluation};
-use providers::{GoalProvider, TaskProvider};
+use goals::{Goal, GoalEvaluation, GoalScorer, HtnAgentGoalTimers, HtnAgentRng, MaintenanceGoal};
+use providers::{GoalProvider, HtnAgentRole, StaticGoalProvider, StaticTaskProvider, TaskProvider};
 
 use crate::{
     data::{Requirements, WorldState},
-    events::observer_handle_invalidated_plan,
+    events::{observer_handle_invalidated_plan, observer_inject_task},
+    execution::{compose_agent_world, HtnAgentGroup, HtnAgentWorld, HtnSharedWorld},
     tasks::Task,
 };
 
+pub mod cache;
 pub mod goals;
 pub mod plan_data;
 pub mod providers;
@@ -19,19 +27,68 @@ pub mod tree;
 
 pub(crate) fn plugin(app: &mut App) {
     providers::plugin(app);
+    app.init_resource::<plan_data::HtnMetrics>();
 }
 
-#[derive(Default)]
 pub struct HtnAgent {
     pub goals: Vec<Goal>,
     pub current_plan: Option<plan_data::Plan>,
     pub available_tasks: Vec<Task>,
     pub goal_eval: GoalEvaluation,
+    /// A goal to pursue instead when no plan can be found for the next goal (e.g. idling or wandering)
+    pub fallback_goal: Option<Goal>,
+    /// What happens to the agent once a plan has been completed
+    pub on_complete: CompletionPolicy,
+    /// Conditions that must stay satisfied, checked by [`goals::system_monitor_maintenance_goals`]
+    pub maintenance_goals: Vec<MaintenanceGoal>,
+    /// Scales the cost of every task this agent plans with, e.g. a faster unit could use 0.5 to make movement cheaper. Defaults to 1.0
+    pub cost_multiplier: f32,
+    /// Added onto the utility of the named goals when picking the next goal. Kept up to date from the agent's [`GoalScorer`]s by [`system_collect_agent_goal_scores`]
+    pub goal_scores: HashMap<String, f32>,
+    /// How much more utility another goal needs over the goal the agent is committed to (see [`HtnAgentCommitment`](crate::execution::HtnAgentCommitment)) before the agent switches to it.
+    /// This stops agents thrashing between goals of near-equal utility. Defaults to 0.0, where the agent only stays on its goal when no other goal beats it
+    pub commitment_margin: f32,
+    /// Subtracted from the cost of tasks with the matching tag (see [`TaskRegistry::set_tags`](crate::tasks::TaskRegistry::set_tags)) when planning, so personality can shape plans without changing task costs.
+    /// Negative weights penalise a tag instead. A task's cost never drops below zero
+    pub tag_preferences: HashMap<String, f32>,
+    /// Plans costing more than this are never committed to, even when they are the only plan for the goal. The fallback goal's plan is used instead if it fits
+    pub max_plan_cost: Option<f32>,
+}
+
+impl Default for HtnAgent {
+    fn default() -> Self {
+        Self {
+            goals: Vec::new(),
+            current_plan: None,
+            available_tasks: Vec::new(),
+            goal_eval: GoalEvaluation::default(),
+            fallback_goal: None,
+            on_complete: CompletionPolicy::default(),
+            maintenance_goals: Vec::new(),
+            cost_multiplier: 1.0,
+            goal_scores: HashMap::new(),
+            commitment_margin: 0.0,
+            tag_preferences: HashMap::new(),
+            max_plan_cost: None,
+        }
+    }
 }
 
+/// How soon an agent is given a plan relative to other agents, see [`HtnSettings::priority_comparator`](crate::data::HtnSettings::priority_comparator). Higher priorities go first by default
 #[derive(Component, Default, Clone, Debug)]
 pub struct HtnAgentPlanningPriority(pub f32);
 
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionPolicy {
+    /// The agent picks up a new plan straight away
+    #[default]
+    Replan,
+    /// The agent is left without a plan until the world changes, see [`HtnAgentIdle`](crate::execution::HtnAgentIdle)
+    Idle,
+    /// The [`HtnAgent`] component is removed, making this a one-off plan
+    RemoveAgent,
+}
+
 impl HtnAgent {
     pub fn new() -> Self {
         Self::default()
@@ -50,37 +107,330 @@ impl HtnAgent {
         self.goals.push(Goal::new(name, goal, static_utility));
         self
     }
+    /// Adds a goal from a set of requirements directly, allowing for goals like `health > 50` which can't be expressed as a [`WorldState`]
+    pub fn add_goal_req(
+        &mut self,
+        name: impl Into<String>,
+        requires: Requirements,
+        static_utility: f32,
+    ) -> &mut Self {
+        self.goals.push(Goal::new(name, requires, static_utility));
+        self
+    }
+
+    pub fn add_maintenance_goal(
+        &mut self,
+        condition: impl Into<Requirements>,
+        restore_goal: Goal,
+    ) -> &mut Self {
+        self.maintenance_goals
+            .push(MaintenanceGoal::new(condition, restore_goal));
+        self
+    }
+
+    pub fn fallback(&mut self, goal: Goal) -> &mut Self {
+        self.fallback_goal = Some(goal);
+        self
+    }
+
     pub fn has_plan(&self) -> bool {
         self.current_plan.is_some()
     }
 
     pub fn get_next_goal(&self, world: &WorldState) -> Option<Goal> {
-        self.goal_eval.next_goal(&self.goals, world)
+        if self.goal_scores.is_empty() {
+            return self.goal_eval.next_goal(&self.goals, world);
+        }
+        self.goal_eval
+            .next_goal(&self.apply_scores(self.goals.clone()), world)
+    }
+
+    /// Like [`HtnAgent::get_next_goal`], but with each goal's utility raised by how long it has gone unsatisfied
+    pub fn get_next_goal_timed(
+        &self,
+        world: &WorldState,
+        timers: &HtnAgentGoalTimers,
+        now: Duration,
+    ) -> Option<Goal> {
+        self.goal_eval
+            .next_goal(&self.apply_scores(timers.apply(&self.goals, now)), world)
+    }
+
+    /// Picks the next goal with random choices drawn from the agent's own RNG, applying goal timers when given
+    pub fn get_next_goal_seeded(
+        &self,
+        world: &WorldState,
+        timers: Option<(&HtnAgentGoalTimers, Duration)>,
+        rng: &mut HtnAgentRng,
+    ) -> Option<Goal> {
+        let goals = match timers {
+            Some((timers, now)) => timers.apply(&self.goals, now),
+            None => self.goals.clone(),
+        };
+        self.goal_eval
+            .next_goal_with_rng(&self.apply_scores(goals), world, &mut rng.0)
+    }
+
+    /// Given the goal that was picked next, keeps the agent on the goal it is committed to unless the next goal's utility beats it by more than [`HtnAgent::commitment_margin`].
+    /// Timers, when given, are applied to the committed goal the same way they were when picking
+    pub fn hold_commitment(
+        &self,
+        next: Goal,
+        committed: &str,
+        timers: Option<(&HtnAgentGoalTimers, Duration)>,
+    ) -> Goal {
+        if next.name == committed {
+            return next;
+        }
+        let Some(current) = self.effective_goal(committed, timers) else {
+            return next;
+        };
+        // ties go to the goal the agent is already committed to
+        if current.utility + self.commitment_margin >= next.utility {
+            current
+        } else {
+            next
+        }
+    }
+
+    /// The named goal (or fallback goal) with its utility adjusted by the agent's goal scores and, when given, its goal timers
+    pub fn effective_goal(
+        &self,
+        name: &str,
+        timers: Option<(&HtnAgentGoalTimers, Duration)>,
+    ) -> Option<Goal> {
+        let goal = self
+            .goals
+            .iter()
+            .chain(self.fallback_goal.iter())
+            .find(|g| g.name == name)?;
+        let goal = match timers {
+            Some((timers, now)) => timers.apply(std::slice::from_ref(goal), now),
+            None => vec![goal.clone()],
+        };
+        self.apply_scores(goal).pop()
+    }
+
+    fn apply_scores(&self, mut goals: Vec<Goal>) -> Vec<Goal> {
+        for goal in goals.iter_mut() {
+            if let Some(score) = self.goal_scores.get(&goal.name) {
+                goal.utility += score;
+            }
+        }
+        goals
     }
 }
 
+/// The combined provider versions of each agent as of the last run of a collect system, used to skip agents whose providers haven't changed
+#[derive(Default)]
+pub struct ProviderVersions {
+    previous: HashMap<Entity, u64>,
+    current: HashMap<Entity, u64>,
+}
+
+impl ProviderVersions {
+    /// Records the agent's provider versions for this run, returning true if they differ from the last run
+    fn changed(
+        &mut self,
+        entity: Entity,
+        versions: impl Iterator<Item = u64>,
+        role: Option<&HtnAgentRole>,
+    ) -> bool {
+        let version = combined_version(versions, role);
+        self.current.insert(entity, version);
+        self.previous.get(&entity) != Some(&version)
+    }
+
+    /// Ends the run, forgetting agents which weren't seen in it
+    fn finish(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+    }
+}
+
+/// Combines the versions of all of an agent's providers into one, which changes whenever any of them do, or the agent's role changes
+fn combined_version(versions: impl Iterator<Item = u64>, role: Option<&HtnAgentRole>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    role.hash(&mut hasher);
+    versions
+        .chain([hasher.finish()])
+        .fold(0xcbf2_9ce4_8422_2325, |acc, v| {
+            (acc ^ v).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Sorts collected tasks by their provider's [`TaskProvider::order`], then by name, so the planner sees them in the same order however the providers were queried
+fn sort_provided_tasks(mut tasks: Vec<(i32, Task)>) -> Vec<Task> {
+    tasks.sort_by(|(a_order, a), (b_order, b)| {
+        a_order.cmp(b_order).then_with(|| a.name().cmp(&b.name()))
+    });
+    tasks.into_iter().map(|(_, task)| task).collect()
+}
+
+/// Collects tasks from every [`TaskProvider`] on each agent. Agents whose providers all report the same [`TaskProvider::version`] as last time are skipped
 pub fn system_collect_agent_tasks_from_providers(
-    mut query: Query<(&dyn TaskProvider, &mut HtnAgent)>,
+    mut query: Query<(
+        Entity,
+        &dyn TaskProvider,
+        &mut HtnAgent,
+        Option<&HtnAgentRole>,
+    )>,
+    mut versions: Local<ProviderVersions>,
 ) {
-    for (providers, mut agent) in query.iter_mut() {
-        let mut tasks = Vec::<Task>::new();
+    for (entity, providers, mut agent, role) in query.iter_mut() {
+        if !versions.changed(entity, providers.iter().map(|p| p.version()), role) {
+            continue;
+        }
+        let mut tasks = Vec::<(i32, Task)>::new();
         for p in providers {
-            tasks.append(&mut p.tasks());
+            let order = p.order();
+            tasks.extend(
+                p.tasks_for_role(role.map(|r| r.0.as_str()))
+                    .into_iter()
+                    .map(|task| (order, task)),
+            );
         }
-        agent.available_tasks = tasks;
+        agent.available_tasks = sort_provided_tasks(tasks);
     }
+    versions.finish();
 }
 
+/// Collects goals from every [`GoalProvider`] on each agent, skipping agents whose providers haven't changed version
 pub fn system_collect_agent_goals_from_providers(
-    mut query: Query<(&dyn GoalProvider, &mut HtnAgent)>,
+    mut query: Query<(
+        Entity,
+        &dyn GoalProvider,
+        &mut HtnAgent,
+        Option<&HtnAgentRole>,
+    )>,
+    mut versions: Local<ProviderVersions>,
 ) {
-    for (providers, mut agent) in query.iter_mut() {
+    for (entity, providers, mut agent, role) in query.iter_mut() {
+        if !versions.changed(entity, providers.iter().map(|p| p.version()), role) {
+            continue;
+        }
         let mut goals = Vec::<Goal>::new();
         for p in providers {
-            goals.append(&mut p.goals());
+            goals.append(&mut p.goals_for_role(role.map(|r| r.0.as_str())));
         }
         agent.goals = goals;
     }
+    versions.finish();
+}
+
+/// Sums the scores from every [`GoalScorer`] on each agent into [`HtnAgent::goal_scores`], scoring against the world the agent plans against (see [`compose_agent_world`]).
+/// Scores are left as they were on agents which no longer have any scorers
+#[allow(clippy::type_complexity)]
+pub fn system_collect_agent_goal_scores(
+    mut query: Query<(
+        &dyn GoalScorer,
+        &mut HtnAgent,
+        Option<&HtnAgentWorld>,
+        Option<&HtnAgentGroup>,
+    )>,
+    world: Option<Res<WorldState>>,
+    shared: Option<Res<HtnSharedWorld>>,
+) {
+    let empty = WorldState::default();
+    for (scorers, mut agent, agent_world, group) in query.iter_mut() {
+        let global = world.as_deref().unwrap_or(&empty);
+        let context = compose_agent_world(global, shared.as_deref(), group, agent_world);
+        let scores: HashMap<String, f32> = agent
+            .goals
+            .iter()
+            .map(|goal| {
+                let score = scorers.iter().map(|s| s.score(&goal.name, &context)).sum();
+                (goal.name.clone(), score)
+            })
+            .collect();
+        if agent.goal_scores != scores {
+            agent.goal_scores = scores;
+        }
+    }
+}
+
+/// A fast path for the common case of an agent with a single [`StaticTaskProvider`], avoiding the overhead of trait queries. Tasks are sorted the same way as by the trait query path.
+/// With the `static_provider_fast_path` feature, this is orchestrated alongside [`system_collect_agent_tasks_from_other_providers`] instead of [`system_collect_agent_tasks_from_providers`]
+pub fn system_collect_agent_tasks_from_static_provider(
+    mut query: Query<(&StaticTaskProvider, &mut HtnAgent, Option<&HtnAgentRole>)>,
+) {
+    for (provider, mut agent, role) in query.iter_mut() {
+        agent.available_tasks = sort_provided_tasks(
+            provider
+                .tasks_for_role(role.map(|r| r.0.as_str()))
+                .into_iter()
+                .map(|task| (provider.order(), task))
+                .collect(),
+        );
+    }
+}
+
+/// The goal counterpart to [`system_collect_agent_tasks_from_static_provider`]
+pub fn system_collect_agent_goals_from_static_provider(
+    mut query: Query<(&StaticGoalProvider, &mut HtnAgent, Option<&HtnAgentRole>)>,
+) {
+    for (provider, mut agent, role) in query.iter_mut() {
+        agent.goals = provider.goals_for_role(role.map(|r| r.0.as_str()));
+    }
+}
+
+/// Collects tasks for agents without a [`StaticTaskProvider`]. Agents using a static provider should not have any other task providers when paired with the fast path, as they will not be collected
+#[allow(clippy::type_complexity)]
+pub fn system_collect_agent_tasks_from_other_providers(
+    mut query: Query<
+        (
+            Entity,
+            &dyn TaskProvider,
+            &mut HtnAgent,
+            Option<&HtnAgentRole>,
+        ),
+        Without<StaticTaskProvider>,
+    >,
+    mut versions: Local<ProviderVersions>,
+) {
+    for (entity, providers, mut agent, role) in query.iter_mut() {
+        if !versions.changed(entity, providers.iter().map(|p| p.version()), role) {
+            continue;
+        }
+        let mut tasks = Vec::<(i32, Task)>::new();
+        for p in providers {
+            let order = p.order();
+            tasks.extend(
+                p.tasks_for_role(role.map(|r| r.0.as_str()))
+                    .into_iter()
+                    .map(|task| (order, task)),
+            );
+        }
+        agent.available_tasks = sort_provided_tasks(tasks);
+    }
+    versions.finish();
+}
+
+/// The goal counterpart to [`system_collect_agent_tasks_from_other_providers`]
+#[allow(clippy::type_complexity)]
+pub fn system_collect_agent_goals_from_other_providers(
+    mut query: Query<
+        (
+            Entity,
+            &dyn GoalProvider,
+            &mut HtnAgent,
+            Option<&HtnAgentRole>,
+        ),
+        Without<StaticGoalProvider>,
+    >,
+    mut versions: Local<ProviderVersions>,
+) {
+    for (entity, providers, mut agent, role) in query.iter_mut() {
+        if !versions.changed(entity, providers.iter().map(|p| p.version()), role) {
+            continue;
+        }
+        let mut goals = Vec::<Goal>::new();
+        for p in providers {
+            goals.append(&mut p.goals_for_role(role.map(|r| r.0.as_str())));
+        }
+        agent.goals = goals;
+    }
+    versions.finish();
 }
 
 impl Component for HtnAgent {
@@ -93,7 +443,8 @@ impl Component for HtnAgent {
             world
                 .commands()
                 .entity(entity)
-                .observe(observer_handle_invalidated_plan);
+                .observe(observer_handle_invalidated_plan)
+                .observe(observer_inject_task);
         });
     }
 }
@@ -107,8 +458,10 @@ mod tests {
 
     #[test]
     fn goal_picking_planning() {
-        let mut agent = HtnAgent::default();
-        agent.goal_eval = GoalEvaluation::Top;
+        let mut agent = HtnAgent {
+            goal_eval: GoalEvaluation::Top,
+            ..Default::default()
+        };
         let goal_a: WorldState = vec![("A", true.into())].into_iter().into();
         let goal_b: WorldState = vec![("B", true.into())].into_iter().into();
         let goal_c: WorldState = vec![("C", true.into())].into_iter().into();
@@ -135,7 +488,7 @@ mod tests {
         let req = Requirements::new()
             .req_equals("bool_eq", true)
             .req_equals("str_eq", "something")
-            .req_equals("num_eq", 3.1415)
+            .req_equals("num_eq", 2.75)
             .req_has("any_key")
             .req_greater("num_grt", 0.0)
             .req_less("num_lst", 0.0)
@@ -144,7 +497,7 @@ mod tests {
         let valid_world = WorldState::new()
             .add("bool_eq", true)
             .add("str_eq", "something")
-            .add("num_eq", 3.1415)
+            .add("num_eq", 2.75)
             .add("any_key", 25.)
             .add("num_grt", 10.)
             .add("num_lst", -12.36)
@@ -162,4 +515,210 @@ mod tests {
         assert!(!req.validate(&WorldState::new()));
         assert!(!req.validate(&invalid_world));
     }
+
+    #[test]
+    fn requirements_goal_planning() {
+        use crate::tasks::TaskRegistry;
+        use plan_data::TimeSlicedTreeGen;
+
+        #[derive(Component, Default)]
+        struct TaskStub;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "heal",
+            Requirements::new().req_less("health", 50.).build(),
+            WorldState::new().add("health", 100.).build(),
+            1.,
+        );
+
+        let mut agent = HtnAgent::new();
+        agent.add_goal_req(
+            "Be Healthy",
+            Requirements::new().req_greater("health", 50.).build(),
+            1.0,
+        );
+        let goal = agent
+            .get_next_goal(&WorldState::new())
+            .expect("Failed to find goal");
+        assert!(!goal
+            .requires
+            .validate(&WorldState::new().add("health", 50.).build()));
+        assert!(goal
+            .requires
+            .validate(&WorldState::new().add("health", 75.).build()));
+
+        let mut gen =
+            TimeSlicedTreeGen::new_initialized(vec![Task::primitive("heal")], vec![goal.clone()]);
+        gen.generate_to_completion(
+            &registry,
+            &WorldState::new().add("health", 20.).build(),
+            Some(4),
+        );
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.decompose_tasks(), vec!["heal".to_string()]);
+    }
+
+    #[test]
+    fn goal_urgency_overtakes_static_utility() {
+        use std::time::Duration;
+
+        let mut agent = HtnAgent {
+            goal_eval: GoalEvaluation::HighestUtility,
+            ..Default::default()
+        };
+        agent.add_goal(
+            "Patrol",
+            WorldState::new().add("patrolled", true).build(),
+            5.0,
+        );
+        agent.goals.push(
+            Goal::new("Eat", WorldState::new().add("hungry", false).build(), 1.0).with_urgency(1.0),
+        );
+
+        let timers = HtnAgentGoalTimers::starting_at(Duration::ZERO);
+        let world = WorldState::new();
+        let next = |secs: u64| {
+            agent
+                .get_next_goal_timed(&world, &timers, Duration::from_secs(secs))
+                .unwrap()
+                .name
+        };
+        assert_eq!(next(0), "Patrol");
+        assert_eq!(next(3), "Patrol");
+        assert_eq!(next(10), "Eat");
+
+        // eating resets the timer, so patrolling wins again for a while
+        let mut timers = timers;
+        let ate = Duration::from_secs(10);
+        timers.satisfy("Eat", ate);
+        let next = agent
+            .get_next_goal_timed(&world, &timers, ate + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(next.name, "Patrol");
+    }
+
+    #[test]
+    fn goal_timers_track_satisfaction() {
+        use bevy::prelude::*;
+        use goals::system_update_goal_timers;
+
+        let mut app = App::new();
+        app.insert_resource(Time::<()>::default());
+        app.insert_resource(WorldState::new().add("hungry", false).build());
+        app.add_systems(Update, system_update_goal_timers);
+
+        let mut agent = HtnAgent::new();
+        agent.add_goal("Eat", WorldState::new().add("hungry", false).build(), 1.0);
+        agent.add_goal(
+            "Patrol",
+            WorldState::new().add("patrolled", true).build(),
+            5.0,
+        );
+        let entity = app
+            .world_mut()
+            .spawn((agent, HtnAgentGoalTimers::new()))
+            .id();
+        app.update();
+
+        let timers = app.world().get::<HtnAgentGoalTimers>(entity).unwrap();
+        assert_eq!(timers.started, Some(Duration::ZERO));
+        assert!(timers.last_satisfied.contains_key("Eat"));
+        assert!(!timers.last_satisfied.contains_key("Patrol"));
+
+        // the patrol goal has gone unsatisfied for as long as the game has run
+        app.world_mut()
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(3));
+        app.update();
+        let timers = app.world().get::<HtnAgentGoalTimers>(entity).unwrap();
+        let now = Duration::from_secs(3);
+        assert_eq!(timers.elapsed("Patrol", now), now);
+        assert_eq!(timers.elapsed("Eat", now), Duration::ZERO);
+    }
+
+    #[test]
+    fn seeded_random_goals() {
+        let mut agent = HtnAgent {
+            goal_eval: GoalEvaluation::Random,
+            ..Default::default()
+        };
+        for i in 0..10 {
+            agent.add_goal(format!("goal_{i}"), Requirements::new(), 1.0);
+        }
+        let choices = |rng: &mut HtnAgentRng| -> Vec<String> {
+            (0..20)
+                .map(|_| {
+                    agent
+                        .get_next_goal_seeded(&WorldState::new(), None, rng)
+                        .unwrap()
+                        .name
+                })
+                .collect()
+        };
+        let entity = Entity::from_raw(7);
+        let first_run = choices(&mut HtnAgentRng::new(42, entity));
+        let second_run = choices(&mut HtnAgentRng::new(42, entity));
+        assert_eq!(first_run, second_run);
+
+        let other_agent = choices(&mut HtnAgentRng::new(42, Entity::from_raw(8)));
+        assert_ne!(first_run, other_agent);
+
+        let mut rng = HtnAgentRng::new(42, entity);
+        choices(&mut rng);
+        rng.reset(42, entity);
+        assert_eq!(choices(&mut rng), first_run);
+    }
+
+    #[test]
+    fn goal_scorer_changes_winner() {
+        use bevy::prelude::*;
+        use bevy_trait_query::RegisterExt;
+
+        #[derive(Component)]
+        struct ThreatScorer;
+        impl GoalScorer for ThreatScorer {
+            fn score(&self, goal_name: &str, world: &WorldState) -> f32 {
+                let threatened = world.get("enemy_near") == Some(true.into());
+                if goal_name == "Flee" && threatened {
+                    5.0
+                } else {
+                    0.0
+                }
+            }
+        }
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.register_component_as::<dyn GoalScorer, ThreatScorer>();
+        app.insert_resource(WorldState::new().add("enemy_near", false).build());
+        app.add_systems(Update, system_collect_agent_goal_scores);
+
+        let mut agent = HtnAgent {
+            goal_eval: GoalEvaluation::HighestUtility,
+            ..Default::default()
+        };
+        agent.add_goal("Flee", Requirements::new(), 1.0);
+        agent.add_goal("Gather", Requirements::new(), 2.0);
+        let entity = app.world_mut().spawn((agent, ThreatScorer)).id();
+        let next_goal = |app: &App| {
+            app.world()
+                .get::<HtnAgent>(entity)
+                .unwrap()
+                .get_next_goal(&WorldState::new())
+                .unwrap()
+                .name
+        };
+
+        app.update();
+        assert_eq!(next_goal(&app), "Gather");
+
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("enemy_near", true);
+        app.update();
+        assert_eq!(next_goal(&app), "Flee");
+        let agent = app.world().get::<HtnAgent>(entity).unwrap();
+        assert_eq!(agent.goal_scores.get("Flee"), Some(&5.0));
+    }
 }
@@ -1,29 +1,44 @@
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
     fmt::Debug,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use bevy::{
-    log::error,
-    prelude::{Component, Query, Res, With},
+    ecs::system::EntityCommands,
+    log::{debug_span, error, trace, trace_span},
+    prelude::{Component, DetectChanges, Entity, Query, Ref, Res, ResMut, Resource, Time},
 };
 
 use crate::{
-    data::{HtnSettings, WorldState},
-    prelude::HtnAgentWorld,
-    tasks::{Task, TaskRegistry},
+    data::{
+        with_number_epsilon, HtnSettings, NumberEpsilonGuard, Predicate, PredicateFailure,
+        Requirements, UniqueName, Variant, WorldState,
+    },
+    execution::{compose_agent_world, HtnAgentGroup, HtnBlackboard, HtnSharedWorld},
+    prelude::{HtnAgentCooldowns, HtnAgentWorld},
+    tasks::{TargetCandidates, Task, TaskRegistry},
 };
 
 use std::collections::VecDeque;
 
-use super::{goals::Goal, tree::Node, HtnAgent};
+use super::{
+    cache::{HtnPlanCache, PlanCacheKey},
+    goals::{Goal, HtnAgentRestoring},
+    tree::Node,
+    HtnAgent,
+};
 
 #[derive(Default, Clone)]
 pub struct Plan {
     pub tasks: VecDeque<Task>,
     pub cost: f32,
+    /// the total of each step's [`TaskRegistry::estimated_duration`]
+    pub estimated_duration: Duration,
+    /// whether this plan only gets closer to the goal rather than meeting it, see [`HtnSettings::partial_plans`]
+    pub partial: bool,
 }
 
 impl Plan {
@@ -34,6 +49,334 @@ impl Plan {
     pub fn simple_print_tasks(&self) -> Vec<String> {
         self.tasks.iter().map(|t| t.name()).collect()
     }
+
+    /// Each top level task of the plan in execution order, with its cost when simulated from `start_world`. Costs are missing from the first step which can't be simulated onwards
+    fn step_costs(
+        &self,
+        registry: &TaskRegistry,
+        start_world: &WorldState,
+    ) -> Vec<(String, Option<f32>)> {
+        let mut world = Some(start_world.clone());
+        self.tasks
+            .iter()
+            .rev()
+            .map(|task| {
+                let step = world.as_ref().and_then(|w| registry.simulate(task, w));
+                world = step.as_ref().map(|(next, _)| next.clone());
+                (task.name(), step.map(|(_, cost)| cost))
+            })
+            .collect()
+    }
+
+    /// The plan as a Graphviz DOT graph, running left to right from a start node through each top level task. Edges are labelled with the cost of the task they lead to
+    pub fn to_dot(&self, registry: &TaskRegistry, start_world: &WorldState) -> String {
+        let mut dot = String::from("digraph plan {\n    rankdir=LR;\n    start [shape=circle];\n");
+        let steps = self.step_costs(registry, start_world);
+        for (index, (name, _)) in steps.iter().enumerate() {
+            dot += &format!("    n{index} [shape=box, label={name:?}];\n");
+        }
+        let mut previous = "start".to_string();
+        for (index, (_, cost)) in steps.iter().enumerate() {
+            let label = cost.map_or("?".to_string(), |c| c.to_string());
+            dot += &format!("    {previous} -> n{index} [label=\"{label}\"];\n");
+            previous = format!("n{index}");
+        }
+        dot + "}\n"
+    }
+
+    /// The plan as a Mermaid flowchart, laid out like [`Plan::to_dot`]
+    pub fn to_mermaid(&self, registry: &TaskRegistry, start_world: &WorldState) -> String {
+        let mut mermaid = String::from("graph LR\n    start((start))\n");
+        let mut previous = "start".to_string();
+        for (index, (name, cost)) in self
+            .step_costs(registry, start_world)
+            .into_iter()
+            .enumerate()
+        {
+            let label = cost.map_or("?".to_string(), |c| c.to_string());
+            mermaid += &format!("    {previous} -->|{label}| n{index}[{name:?}]\n");
+            previous = format!("n{index}");
+        }
+        mermaid
+    }
+
+    /// The primitive tasks of this plan in the order they will be executed. Note that `tasks` is stored leaf-first, which is the reverse of this
+    pub fn execution_order(&self) -> Vec<String> {
+        self.tasks
+            .iter()
+            .rev()
+            .flat_map(|t| t.decompose())
+            .collect()
+    }
+
+    /// Simulates the plan against a starting world, ensuring each step's preconditions hold when it would be run and that the goal is met at the end.
+    /// On failure, returns the index (in execution order) of the step whose preconditions were not met, or the number of steps if the goal was not met.
+    pub fn validate_against(
+        &self,
+        registry: &TaskRegistry,
+        start_world: &WorldState,
+        goal: &Goal,
+    ) -> Result<(), usize> {
+        let steps = self.execution_order();
+        let mut world = start_world.clone();
+        for (index, name) in steps.iter().enumerate() {
+            let Some(data) = registry.get_named(name) else {
+                return Err(index);
+            };
+            if !data.preconditions().validate(&world) {
+                return Err(index);
+            }
+            world = world.concat(&data.postconditions_for(&world));
+        }
+        if !goal.is_satisfied(&world) {
+            return Err(steps.len());
+        }
+        Ok(())
+    }
+
+    /// Walks the plan from a starting world like [`Plan::validate_against`], describing at each step which preconditions the task relied on, what it changed, and which of the goal's requirements it met.
+    /// Entries are sorted by name. The walk stops early at a step which isn't in the registry
+    pub fn explain(
+        &self,
+        registry: &TaskRegistry,
+        start_world: &WorldState,
+        goal: &Goal,
+    ) -> Vec<StepExplanation> {
+        let sorted = |mut keys: Vec<UniqueName>| {
+            keys.sort_by_key(|k| k.as_str());
+            keys
+        };
+        let mut explanations = Vec::new();
+        let mut world = start_world.clone();
+        for name in self.execution_order() {
+            let Some(data) = registry.get_named(&name) else {
+                break;
+            };
+            let mut unmet_preconditions = data.preconditions().explain(&world);
+            unmet_preconditions.sort_by_key(|(k, _)| k.as_str());
+            let satisfied_preconditions = sorted(
+                data.preconditions()
+                    .keys()
+                    .filter(|k| unmet_preconditions.iter().all(|(u, _)| u != *k))
+                    .cloned()
+                    .collect(),
+            );
+
+            let unmet_before = goal.requires.explain(&world);
+            let next_world = world.concat(&data.postconditions_for(&world));
+            let unmet_after = goal.requires.explain(&next_world);
+
+            let mut effects: Vec<(UniqueName, Variant)> = next_world
+                .keys()
+                .filter(|k| world.get((*k).clone()) != next_world.get((*k).clone()))
+                .map(|k| (k.clone(), next_world.get(k.clone()).unwrap_or_default()))
+                .collect();
+            effects.sort_by_key(|(k, _)| k.as_str());
+            let advanced_goal = sorted(
+                unmet_before
+                    .into_iter()
+                    .map(|(k, _)| k)
+                    .filter(|k| unmet_after.iter().all(|(u, _)| u != k))
+                    .collect(),
+            );
+
+            explanations.push(StepExplanation {
+                task: name,
+                satisfied_preconditions,
+                unmet_preconditions,
+                effects,
+                advanced_goal,
+            });
+            world = next_world;
+        }
+        explanations
+    }
+
+    /// Removes adjacent pairs of primitive tasks whose combined effect is a no-op, such as opening and then closing a door.
+    /// A pair is a no-op when every key either task writes ends up back at the value the first task's preconditions pin it to with `req_equals`.
+    /// Pairs are removed repeatedly, so nested pairs collapse too. Macros and unregistered tasks are always kept.
+    /// The remaining steps are replayed from `start_world` with the `planner` that made the plan, so the cost includes its multipliers, tag preferences,
+    /// cost aggregation and goal distance penalty. Plans whose remaining steps can't be replayed are returned unchanged
+    pub fn simplify(
+        &self,
+        registry: &TaskRegistry,
+        planner: &TimeSlicedTreeGen,
+        start_world: &WorldState,
+        goal: &Goal,
+    ) -> Plan {
+        // in execution order
+        let mut kept: Vec<Task> = Vec::with_capacity(self.tasks.len());
+        for task in self.tasks.iter().rev() {
+            if let (Some(Task::Primitive(prev)), Task::Primitive(next)) = (kept.last(), task) {
+                if is_noop_pair(registry, prev, next) {
+                    kept.pop();
+                    continue;
+                }
+            }
+            kept.push(task.clone());
+        }
+        if kept.len() == self.tasks.len() {
+            return self.clone();
+        }
+        match planner.replay(&kept, goal, registry, start_world) {
+            Some(leaf) => Plan {
+                partial: self.partial,
+                ..TimeSlicedTreeGen::unravel_plan(&leaf)
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// One closure per step, in execution order, which inserts that step's task component onto an entity using [`TaskData::add`](crate::tasks::TaskData::add).
+    /// This allows a plan to be previewed or applied manually, outside of the usual execution systems. Unregistered steps are skipped
+    pub fn into_task_components(&self, registry: &TaskRegistry) -> Vec<TaskInsertion> {
+        self.execution_order()
+            .into_iter()
+            .filter_map(|name| {
+                let data = registry.get_named(&name)?.clone();
+                Some(Box::new(move |entity: &mut EntityCommands| data.add(entity)) as TaskInsertion)
+            })
+            .collect()
+    }
+
+    /// Relaxes the plan into a [`PartialPlan`], where only steps that depend on each other (see [`TaskRegistry::are_independent`]) keep their relative order
+    pub fn partial_order(&self, registry: &TaskRegistry) -> PartialPlan {
+        let steps = self.execution_order();
+        let mut before = Vec::new();
+        for (i, a) in steps.iter().enumerate() {
+            for (j, b) in steps.iter().enumerate().skip(i + 1) {
+                if !registry.are_independent(a, b) {
+                    before.push((i, j));
+                }
+            }
+        }
+        PartialPlan { steps, before }
+    }
+}
+
+/// A plan whose steps only need to respect the orderings in `before`. Steps with no ordering between them can be run in either order, or at the same time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PartialPlan {
+    /// the primitive tasks of the plan, in the order of the sequential plan it was made from
+    pub steps: Vec<String>,
+    /// pairs of step indices `(a, b)` where step `a` must finish before step `b` starts
+    pub before: Vec<(usize, usize)>,
+}
+
+impl PartialPlan {
+    /// Whether step `a` must finish before step `b`, either directly or through other steps
+    pub fn is_ordered(&self, a: usize, b: usize) -> bool {
+        let mut open = vec![a];
+        let mut seen = vec![false; self.steps.len()];
+        while let Some(step) = open.pop() {
+            for &(_, next) in self.before.iter().filter(|(from, _)| *from == step) {
+                if next == b {
+                    return true;
+                }
+                if !seen[next] {
+                    seen[next] = true;
+                    open.push(next);
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether steps `a` and `b` may run in either order
+    pub fn is_unordered(&self, a: usize, b: usize) -> bool {
+        a != b && !self.is_ordered(a, b) && !self.is_ordered(b, a)
+    }
+
+    /// The steps which haven't been completed yet, but whose predecessors all have
+    pub fn ready(&self, completed: &[usize]) -> Vec<usize> {
+        (0..self.steps.len())
+            .filter(|step| !completed.contains(step))
+            .filter(|step| {
+                self.before
+                    .iter()
+                    .filter(|(_, next)| next == step)
+                    .all(|(prev, _)| completed.contains(prev))
+            })
+            .collect()
+    }
+}
+
+/// Inserts a single task's component onto an entity. See [`Plan::into_task_components`]
+pub type TaskInsertion = Box<dyn FnOnce(&mut EntityCommands) + Send + Sync>;
+
+impl WorldState {
+    /// Projects the world that would result from executing the plan, without modifying this world.
+    /// Preconditions are not checked (see [`Plan::validate_against`]), and unregistered tasks are skipped
+    pub fn apply_plan(&self, plan: &Plan, registry: &TaskRegistry) -> WorldState {
+        self.project_plan(plan, registry, false)
+    }
+
+    /// Like [`WorldState::apply_plan`], but tasks with outcomes set through [`TaskRegistry::set_outcomes`] apply their highest-weight outcome
+    /// in place of their registered effects, giving the most likely resulting world
+    pub fn apply_plan_most_likely(&self, plan: &Plan, registry: &TaskRegistry) -> WorldState {
+        self.project_plan(plan, registry, true)
+    }
+
+    fn project_plan(&self, plan: &Plan, registry: &TaskRegistry, most_likely: bool) -> WorldState {
+        let mut projected = self.clone();
+        for name in plan.execution_order() {
+            let Some(data) = registry.get_named(&name) else {
+                continue;
+            };
+            match most_likely
+                .then(|| registry.most_likely_outcome(&name))
+                .flatten()
+            {
+                Some(outcome) => projected.append(outcome),
+                None => {
+                    let effects = data.postconditions_for(&projected);
+                    projected.append(&effects);
+                }
+            }
+        }
+        projected
+    }
+}
+
+/// The world implied by a set of requirements, made of every key pinned to an exact value
+fn pinned_world(requirements: &Requirements) -> WorldState {
+    let mut world = WorldState::new();
+    for key in requirements.keys() {
+        if let Some(Predicate::Equals(value)) = requirements.get(key.clone()) {
+            world.insert(key.clone(), value.clone());
+        }
+    }
+    world
+}
+
+fn is_noop_pair(registry: &TaskRegistry, first: &str, second: &str) -> bool {
+    let (Some(first), Some(second)) = (registry.tasks.get(first), registry.tasks.get(second))
+    else {
+        return false;
+    };
+    let before = pinned_world(first.preconditions());
+    let first_effects = first.postconditions_for(&before);
+    let between = before.concat(&first_effects);
+    let second_effects = second.postconditions_for(&between);
+    let after = between.concat(&second_effects);
+    let restored = |key: &UniqueName| {
+        before.get(key.clone()).is_some() && before.get(key.clone()) == after.get(key.clone())
+    };
+    first_effects.keys().all(restored) && second_effects.keys().all(restored)
+}
+
+/// Why a single step of a plan was taken, as reported by [`Plan::explain`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepExplanation {
+    pub task: String,
+    /// the preconditions that held when the step was run
+    pub satisfied_preconditions: Vec<UniqueName>,
+    /// the preconditions that did not hold, meaning the plan is no longer valid from this step on
+    pub unmet_preconditions: Vec<(UniqueName, PredicateFailure)>,
+    /// the world entries the step changed, with their new values
+    pub effects: Vec<(UniqueName, Variant)>,
+    /// the goal requirements that this step met, which were not met before it
+    pub advanced_goal: Vec<UniqueName>,
 }
 
 impl Debug for Plan {
@@ -44,31 +387,209 @@ impl Debug for Plan {
     }
 }
 
-#[derive(Component, Default)]
+/// Generates plans for an agent's goals a slice at a time.
+///
+/// Generation is deterministic: the same tasks (in any order), world, and goals always produce the same plans.
+/// Candidate tasks are always explored in name order, so when several plans tie for the lowest cost the same one is kept every time
+#[derive(Component)]
 pub struct TimeSlicedTreeGen {
     pub active_nodes: VecDeque<Arc<Node<PlanNode>>>,
-    pub valid_nodes: Vec<Arc<Node<PlanNode>>>,
+    /// nodes which meet the goal, waiting to be emitted as plans. The cheapest is emitted first, so stopping early still yields the best plan found so far
+    pub valid_nodes: BinaryHeap<ValidNode>,
     pub goals: Vec<Goal>,
     pub plans: HashMap<String, Plan>,
     pub available_tasks: Vec<Task>,
+    /// when each task was last executed by the agent, used to exclude tasks that are still on cooldown
+    pub cooldowns: HashMap<String, Duration>,
+    /// the [`Time`] elapsed since startup, which cooldowns are checked against. Kept in sync by [`system_update_time_sliced_tree_gen`]
+    pub now: Duration,
+    /// the settings used while generating. Kept in sync with the [`HtnSettings`] resource by [`system_update_time_sliced_tree_gen`]
+    pub settings: HtnSettings,
+    /// searched for when a full search for the top goal fails to produce a plan. Kept in sync with [`HtnAgent::fallback_goal`]
+    pub fallback_goal: Option<Goal>,
+    /// searched for in place of the top goal while the agent restores a violated maintenance goal. Kept in sync with [`HtnAgentRestoring`]
+    pub restore_goal: Option<Goal>,
+    /// the goal the agent picked, searched for in place of the top goal. Set with [`TimeSlicedTreeGen::request_goal`]
+    pub target_goal: Option<Goal>,
+    /// the candidate targets of targeted tasks. Kept in sync with the agent's [`HtnBlackboard`] by [`system_update_time_sliced_tree_gen`]
+    pub target_candidates: TargetCandidates,
+    /// Plans this returns false for are discarded when found, letting the search settle on the next best plan instead. Useful for vetoing plans that are optimal but undesirable
+    pub plan_filter: Option<fn(&Plan) -> bool>,
+    /// scales the cost of every task. Kept in sync with [`HtnAgent::cost_multiplier`]
+    pub cost_multiplier: f32,
+    /// lowers the cost of tasks by the weight of their tags. Kept in sync with [`HtnAgent::tag_preferences`]
+    pub tag_preferences: HashMap<String, f32>,
+    primary_exhausted: bool,
+    /// whether the world has changed since the last full search. Used by [`system_update_time_sliced_tree_gen`] to skip agents with nothing new to plan for
+    dirty: bool,
+    pub stats: HtnAgentPlanStats,
+    search_started: Option<Instant>,
+    /// the number of valid nodes found so far, used to order nodes of equal cost
+    valid_found: u64,
+    /// When `Some`, every task excluded from the search is recorded along with why. Disabled by default as it grows with every node expanded, so clear it between searches
+    pub prune_log: Option<Vec<(String, PruneReason)>>,
+    /// the node nearest the goal in the current search, with its number of unmet goal requirements. Starts out holding the starting world's count and no node
+    closest: (usize, Option<Arc<Node<PlanNode>>>),
+}
+
+/// Why a task was excluded from a search, as recorded in [`TimeSlicedTreeGen::prune_log`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneReason {
+    /// the task's preconditions aren't met by the world it would run in
+    Preconditions,
+    /// the task was executed too recently
+    OnCooldown,
+    /// the task would repeat an A-B-A-B pattern, so the branch is not expanded further
+    Recursion,
+    /// the branch reached the maximum node depth without meeting the goal
+    DepthLimit,
+    /// the branch was evicted to keep the search within [`HtnSettings::max_open_nodes`]
+    OpenNodeLimit,
+    /// applying the task reaches one of [`HtnSettings::forbidden_states`]
+    ForbiddenState,
+    /// the branch already has a task from one of the task's exclusion groups, see [`TaskRegistry::add_exclusion_group`]
+    Excluded,
+}
+
+/// A node which meets the goal. Ordered so that a [`BinaryHeap`] yields the cheapest node first, then the shallowest (with [`HtnSettings::prefer_shorter_plans`]), then the fastest, then the most recently found
+#[derive(Clone)]
+pub struct ValidNode {
+    pub node: Arc<Node<PlanNode>>,
+    order: u64,
+    /// the node's depth when shorter plans are preferred, otherwise 0
+    depth_rank: u32,
+}
+
+impl PartialEq for ValidNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ValidNode {}
+
+impl PartialOrd for ValidNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ValidNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .node
+            .value
+            .cost
+            .total_cmp(&self.node.value.cost)
+            .then(other.depth_rank.cmp(&self.depth_rank))
+            .then(other.node.value.duration.cmp(&self.node.value.duration))
+            .then(self.order.cmp(&other.order))
+    }
+}
+
+/// Statistics about how much work planning has taken for an agent. Add this component to an agent to have it kept up to date by [`system_update_time_sliced_tree_gen`]
+#[derive(Component, Default, Clone, Debug, PartialEq)]
+pub struct HtnAgentPlanStats {
+    /// the total number of nodes processed across all searches
+    pub nodes_expanded: u64,
+    /// the total number of plans stored, including those that replaced a worse plan for the same goal
+    pub plans_emitted: u64,
+    pub last_plan_cost: f32,
+    /// how long after the search began that the last plan was emitted
+    pub last_plan_time: Duration,
+}
+
+/// Planner performance summed over every agent, for tuning [`HtnSettings`] to the scale of a scene. Kept up to date by [`system_update_time_sliced_tree_gen`] when the resource is present
+#[derive(Resource, Default, Clone, Debug, PartialEq)]
+pub struct HtnMetrics {
+    pub nodes_expanded: u64,
+    pub plans_emitted: u64,
+    /// how many times a finished search was started over, because the world or the agent changed
+    pub replans: u64,
+    /// how many frames planning has run for
+    pub frames: u64,
+    /// the time spent planning across all frames
+    pub planning_time: Duration,
+}
+
+/// The [`HtnMetrics`] at a point in time, with the per-frame average worked out
+#[derive(Clone, Debug, PartialEq)]
+pub struct HtnMetricsSnapshot {
+    pub nodes_expanded: u64,
+    pub plans_emitted: u64,
+    pub replans: u64,
+    pub average_frame_time: Duration,
+}
+
+impl HtnMetrics {
+    pub fn average_frame_time(&self) -> Duration {
+        match u32::try_from(self.frames) {
+            Ok(0) => Duration::ZERO,
+            Ok(frames) => self.planning_time / frames,
+            Err(_) => self.planning_time.div_f64(self.frames as f64),
+        }
+    }
+
+    pub fn snapshot(&self) -> HtnMetricsSnapshot {
+        HtnMetricsSnapshot {
+            nodes_expanded: self.nodes_expanded,
+            plans_emitted: self.plans_emitted,
+            replans: self.replans,
+            average_frame_time: self.average_frame_time(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PlanNode {
     pub task: Option<Task>,
     pub world: WorldState,
+    /// the cost used to order the search, which is `aggregate + penalty`
     pub cost: f32,
+    /// the step costs folded together with [`HtnSettings::cost_aggregation`]
+    pub aggregate: f32,
+    /// the goal distance penalty summed over every step, see [`HtnSettings::goal_distance_weight`]
+    pub penalty: f32,
     pub depth: u32,
+    /// the estimated time to run every task from the root to this node
+    pub duration: Duration,
+}
+
+impl Default for TimeSlicedTreeGen {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TimeSlicedTreeGen {
     pub fn new() -> Self {
         Self {
             active_nodes: VecDeque::new(),
-            valid_nodes: Vec::new(),
+            valid_nodes: BinaryHeap::new(),
             goals: Vec::new(),
             plans: HashMap::new(),
             available_tasks: Vec::new(),
+            cooldowns: HashMap::new(),
+            now: Duration::ZERO,
+            settings: HtnSettings::default(),
+            fallback_goal: None,
+            restore_goal: None,
+            target_goal: None,
+            target_candidates: TargetCandidates::new(),
+            plan_filter: None,
+            cost_multiplier: 1.0,
+            tag_preferences: HashMap::new(),
+            primary_exhausted: false,
+            dirty: true,
+            stats: HtnAgentPlanStats::default(),
+            search_started: None,
+            valid_found: 0,
+            prune_log: None,
+            closest: (0, None),
         }
     }
 
@@ -77,10 +598,114 @@ impl TimeSlicedTreeGen {
         sorted_goals.sort_by(|a, b| a.utility.total_cmp(&b.utility));
         Self {
             active_nodes: VecDeque::new(),
-            valid_nodes: Vec::new(),
+            valid_nodes: BinaryHeap::new(),
             goals: sorted_goals,
             plans: HashMap::new(),
             available_tasks: tasks,
+            cooldowns: HashMap::new(),
+            now: Duration::ZERO,
+            settings: HtnSettings::default(),
+            fallback_goal: None,
+            restore_goal: None,
+            target_goal: None,
+            target_candidates: TargetCandidates::new(),
+            plan_filter: None,
+            cost_multiplier: 1.0,
+            tag_preferences: HashMap::new(),
+            primary_exhausted: false,
+            dirty: true,
+            stats: HtnAgentPlanStats::default(),
+            search_started: None,
+            valid_found: 0,
+            prune_log: None,
+            closest: (0, None),
+        }
+    }
+
+    /// Clears any in-progress search and emitted plans so the generator can be reused with a new set of tasks and goals.
+    /// Settings and cooldowns are kept as-is.
+    pub fn reset(&mut self, tasks: Vec<Task>, goals: Vec<Goal>) {
+        let mut sorted_goals = goals;
+        sorted_goals.sort_by(|a, b| a.utility.total_cmp(&b.utility));
+        self.active_nodes.clear();
+        self.valid_nodes.clear();
+        self.plans.clear();
+        self.target_goal = None;
+        self.primary_exhausted = false;
+        self.dirty = true;
+        self.goals = sorted_goals;
+        self.available_tasks = tasks;
+    }
+
+    /// Discards any in-progress search and found plans, which were made against a world that is now outdated, and flags the generator to search again
+    pub fn mark_dirty(&mut self) {
+        self.active_nodes.clear();
+        self.valid_nodes.clear();
+        self.plans.clear();
+        self.primary_exhausted = false;
+        self.dirty = true;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Searches for `goal` from now on, such as when the agent picked a goal (by urgency, score, or commitment) other than the top goal.
+    /// Any search in progress is restarted unless it is already for `goal`. Plans already found are kept, as they are still valid for the current world
+    pub fn request_goal(&mut self, goal: &Goal) {
+        if self
+            .target_goal
+            .as_ref()
+            .is_some_and(|t| t.name == goal.name)
+        {
+            return;
+        }
+        self.target_goal = Some(goal.clone());
+        self.active_nodes.clear();
+        self.valid_nodes.clear();
+        self.primary_exhausted = false;
+        self.dirty = true;
+    }
+
+    /// Whether plans found by this generator only depend on its goals, tasks, and world, and so can be shared through an [`HtnPlanCache`].
+    /// Generators with agent specific modifiers, cooldowns, filters, or a goal being restored are never cached
+    pub fn is_cacheable(&self) -> bool {
+        self.cost_multiplier == 1.0
+            && self.tag_preferences.is_empty()
+            && self.cooldowns.is_empty()
+            && self.plan_filter.is_none()
+            && self.restore_goal.is_none()
+            && self.target_candidates.is_empty()
+    }
+
+    /// Fills in the plan for the goal being searched for from the cache, finishing the search without generating anything. Returns false, leaving the generator untouched, when it isn't cached
+    pub fn restore_from_cache(&mut self, cache: &mut HtnPlanCache, world: &WorldState) -> bool {
+        if !self.is_cacheable() || !self.active_nodes.is_empty() {
+            return false;
+        }
+        let Some(goal) = self.search_goal() else {
+            return false;
+        };
+        let key = PlanCacheKey::new(&goal, world, &self.available_tasks, cache.generation());
+        let Some(plan) = cache.get(&key) else {
+            return false;
+        };
+        self.plans.insert(goal.name, plan);
+        self.valid_nodes.clear();
+        self.dirty = false;
+        true
+    }
+
+    /// Shares the plans found for this generator's goals through the cache
+    pub fn store_in_cache(&self, cache: &mut HtnPlanCache, world: &WorldState) {
+        if !self.is_cacheable() {
+            return;
+        }
+        for goal in self.goals.iter().chain(self.target_goal.iter()) {
+            if let Some(plan) = self.plans.get(&goal.name) {
+                let key = PlanCacheKey::new(goal, world, &self.available_tasks, cache.generation());
+                cache.insert(key, plan.clone());
+            }
         }
     }
 
@@ -91,11 +716,23 @@ impl TimeSlicedTreeGen {
         duration: Option<Duration>,
         max_node_depth: Option<u32>,
     ) {
-        let Some(goal) = self.goals.last().cloned() else {
+        with_number_epsilon(self.settings.number_epsilon, || {
+            self.generate_with_settings(registry, current_world, duration, max_node_depth)
+        });
+    }
+
+    fn generate_with_settings(
+        &mut self,
+        registry: &TaskRegistry,
+        current_world: &WorldState,
+        duration: Option<Duration>,
+        max_node_depth: Option<u32>,
+    ) {
+        let Some(mut goal) = self.search_goal() else {
             return;
         };
         let timer = Instant::now();
-        self.try_seed_active_nodes(registry, current_world);
+        self.try_seed_active_nodes(&goal, registry, current_world);
 
         loop {
             self.generate_single(&goal, registry, max_node_depth);
@@ -107,7 +744,15 @@ impl TimeSlicedTreeGen {
                 }
             }
             if self.active_nodes.is_empty() {
-                break;
+                if !self.finish_search(&goal) {
+                    self.try_emit_partial(&goal);
+                    break;
+                }
+                let Some(next_goal) = self.search_goal() else {
+                    break;
+                };
+                goal = next_goal;
+                self.try_seed_active_nodes(&goal, registry, current_world);
             }
         }
     }
@@ -119,55 +764,113 @@ impl TimeSlicedTreeGen {
         current_world: &WorldState,
         max_node_depth: Option<u32>,
     ) {
-        let Some(goal) = self.goals.last().cloned() else {
-            return;
-        };
-        self.try_seed_active_nodes(registry, current_world);
-
-        loop {
-            self.generate_single(&goal, registry, max_node_depth);
-            self.try_emit_single(&goal);
+        self.generate_for_duration(registry, current_world, None, max_node_depth);
+    }
 
-            if self.active_nodes.is_empty() {
-                break;
+    /// The goal currently being searched for. This is the restore goal, target goal, or top goal, in that order, unless a full search for it failed and there is a fallback goal to try instead
+    fn search_goal(&self) -> Option<Goal> {
+        if self.primary_exhausted {
+            if let Some(fallback) = &self.fallback_goal {
+                return Some(fallback.clone());
             }
         }
+        self.restore_goal
+            .as_ref()
+            .or(self.target_goal.as_ref())
+            .or(self.goals.last())
+            .cloned()
+    }
+
+    /// Called once the active nodes for a goal have been drained. Returns true when a new search (for the fallback goal) should begin
+    fn finish_search(&mut self, goal: &Goal) -> bool {
+        if self.primary_exhausted {
+            // fallback has been searched, so give the top goal another chance next time around
+            self.primary_exhausted = false;
+            return false;
+        }
+        if self.fallback_goal.is_none() || self.plans.contains_key(&goal.name) {
+            return false;
+        }
+        self.primary_exhausted = true;
+        true
     }
 
-    fn try_seed_active_nodes(&mut self, registry: &TaskRegistry, current_world: &WorldState) {
+    fn try_seed_active_nodes(
+        &mut self,
+        goal: &Goal,
+        registry: &TaskRegistry,
+        current_world: &WorldState,
+    ) {
         if !self.active_nodes.is_empty() {
             return;
         }
-        let seeds = self.possible_tasks(current_world, registry);
+        let _span = debug_span!("htn_seed", goal = %goal.name).entered();
+        self.search_started = Some(Instant::now());
+        self.closest = (Self::goal_distance(goal, current_world), None);
+        let seeds = self.possible_tasks(current_world, registry, None);
+        trace!("Seeding {} nodes", seeds.len());
         for s in seeds {
-            let Some(data) = registry.get_task(&s) else {
-                continue;
-            };
-            self.active_nodes.push_back(Arc::new(Node {
-                value: PlanNode {
-                    task: Some(s),
-                    world: current_world.clone().concat(data.postconditions()),
-                    cost: data.cost(current_world),
-                    depth: 0,
-                },
-                parent: None,
-            }));
+            if let Some(node) = self.make_node(None, &s, goal, registry, current_world) {
+                self.active_nodes.push_back(Arc::new(node));
+            }
         }
+        self.evict_open_nodes();
     }
 
     pub fn try_emit_single(&mut self, goal: &Goal) {
-        let Some(valid) = self.valid_nodes.pop() else {
+        let Some(ValidNode { node: valid, .. }) = self.valid_nodes.pop() else {
             return;
         };
+        let _span = debug_span!("htn_emit", goal = %goal.name, depth = valid.value.depth).entered();
         let plan = Self::unravel_plan(&valid);
+        if self.plan_filter.is_some_and(|accept| !accept(&plan)) {
+            trace!("Plan rejected by filter: {:?}", plan.execution_order());
+            return;
+        }
 
-        if let Some(prev_plan) = self.plans.get(&goal.name) {
-            // ensure the plan we made is actually better than what was available
-            if plan.cost > prev_plan.cost {
+        if let Some(prev_plan) = self.plans.get(&goal.name).filter(|p| !p.partial) {
+            // ensure the plan we made is actually better than what was available. Equal-cost plans are compared by length (when preferred), then duration
+            let (steps, prev_steps) = (plan.tasks.len(), prev_plan.tasks.len());
+            let worse = match plan.cost.partial_cmp(&prev_plan.cost) {
+                Some(Ordering::Equal) if self.prefers_shorter() && steps != prev_steps => {
+                    steps > prev_steps
+                }
+                Some(Ordering::Equal) => plan.estimated_duration > prev_plan.estimated_duration,
+                cmp => cmp == Some(Ordering::Greater),
+            };
+            if worse {
                 return;
             }
         }
 
+        self.stats.plans_emitted += 1;
+        self.stats.last_plan_cost = plan.cost;
+        self.stats.last_plan_time = self
+            .search_started
+            .map(|started| started.elapsed())
+            .unwrap_or_default();
+        self.plans.insert(goal.name.clone(), plan);
+    }
+
+    /// Stores a partial plan to the node closest to the goal, when enabled and the search found no complete plan
+    fn try_emit_partial(&mut self, goal: &Goal) {
+        if !self.settings.partial_plans.unwrap_or_default()
+            || self.plans.get(&goal.name).is_some_and(|p| !p.partial)
+        {
+            return;
+        }
+        let Some(closest) = self.closest.1.take() else {
+            return;
+        };
+        let plan = Plan {
+            partial: true,
+            ..Self::unravel_plan(&closest)
+        };
+        if self.plan_filter.is_some_and(|accept| !accept(&plan)) {
+            return;
+        }
+        trace!("Storing partial plan: {:?}", plan.execution_order());
+        self.stats.plans_emitted += 1;
         self.plans.insert(goal.name.clone(), plan);
     }
 
@@ -181,23 +884,94 @@ impl TimeSlicedTreeGen {
         let Some(node) = self.active_nodes.pop_front() else {
             return;
         };
-        if goal.requires.validate(&node.value.world) {
+        let _span =
+            trace_span!("htn_generate", goal = %goal.name, depth = node.value.depth).entered();
+        self.stats.nodes_expanded += 1;
+        if goal.is_satisfied(&node.value.world) {
             // found a leaf! stop processing it
-            eprintln!("Found Leaf Node: {:#?}", node.value);
-            self.valid_nodes.push(node);
+            trace!("Found Leaf Node: {:#?}", node.value);
+            let depth_rank = if self.prefers_shorter() {
+                node.value.depth
+            } else {
+                0
+            };
+            self.valid_nodes.push(ValidNode {
+                node,
+                order: self.valid_found,
+                depth_rank,
+            });
+            self.valid_found += 1;
+            return;
+        }
+        if self.settings.partial_plans.unwrap_or_default() {
+            let distance = Self::goal_distance(goal, &node.value.world);
+            let closer = match &self.closest {
+                (closest, _) if distance < *closest => true,
+                (closest, Some(prev)) => distance == *closest && node.value.cost < prev.value.cost,
+                (_, None) => false,
+            };
+            if closer {
+                self.closest = (distance, Some(node.clone()));
+            }
+        }
+        if node.value.depth >= max_node_depth.unwrap_or(u32::MAX) {
+            self.record_prune(&node, PruneReason::DepthLimit);
             return;
         }
-        if node.value.depth >= max_node_depth.unwrap_or(u32::MAX) || self.has_recursion(&node) {
+        if self.has_recursion(&node) {
+            self.record_prune(&node, PruneReason::Recursion);
             return;
         }
-        let tasks = self.possible_tasks(&node.value.world, task_registry);
+        let tasks = self.possible_tasks(&node.value.world, task_registry, Some(&node));
         for t in tasks {
-            if let Some(new_node) = Self::make_node(node.clone(), &t, task_registry) {
-                self.active_nodes.push_front(Arc::new(new_node));
+            if let Some(new_node) = self.make_node(
+                Some(node.clone()),
+                &t,
+                goal,
+                task_registry,
+                &node.value.world,
+            ) {
+                // depth first by default, breadth first when shorter plans are preferred
+                if self.prefers_shorter() {
+                    self.active_nodes.push_back(Arc::new(new_node));
+                } else {
+                    self.active_nodes.push_front(Arc::new(new_node));
+                }
+            }
+        }
+        self.evict_open_nodes();
+    }
+
+    fn prefers_shorter(&self) -> bool {
+        self.settings.prefer_shorter_plans.unwrap_or_default()
+    }
+
+    /// Drops the most expensive open nodes until the open set is within [`HtnSettings::max_open_nodes`]
+    fn evict_open_nodes(&mut self) {
+        let Some(max_open) = self.settings.max_open_nodes else {
+            return;
+        };
+        while self.active_nodes.len() > max_open {
+            let Some((index, _)) = self
+                .active_nodes
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.value.cost.total_cmp(&b.value.cost))
+            else {
+                break;
+            };
+            if let Some(node) = self.active_nodes.remove(index) {
+                self.record_prune(&node, PruneReason::OpenNodeLimit);
             }
         }
     }
 
+    fn record_prune(&mut self, node: &Node<PlanNode>, reason: PruneReason) {
+        if let (Some(log), Some(task)) = (&mut self.prune_log, &node.value.task) {
+            log.push((task.name(), reason));
+        }
+    }
+
     fn unravel_plan(leaf: &Arc<Node<PlanNode>>) -> Plan {
         let mut curr = leaf.clone();
         let mut sequence = Vec::<Task>::new();
@@ -217,6 +991,8 @@ impl TimeSlicedTreeGen {
         Plan {
             tasks: sequence.into(),
             cost: leaf.value.cost,
+            estimated_duration: leaf.value.duration,
+            partial: false,
         }
     }
 
@@ -241,76 +1017,364 @@ impl TimeSlicedTreeGen {
         t0 == t2 && t1 == t4
     }
 
-    fn possible_tasks(&self, world: &WorldState, task_registry: &TaskRegistry) -> Vec<Task> {
+    fn goal_distance_penalty(&self, goal: &Goal, world: &WorldState) -> f32 {
+        let Some(weight) = self.settings.goal_distance_weight else {
+            return 0.0;
+        };
+        weight * Self::goal_distance(goal, world) as f32
+    }
+
+    /// The number of requirements unmet by `world`, measured to whichever of the goal's target states is closest
+    fn goal_distance(goal: &Goal, world: &WorldState) -> usize {
+        goal.targets()
+            .map(|target| target.unmet_requirements(world).len())
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// The tasks which could follow `branch` (or start a plan, without a branch) in `world`
+    fn possible_tasks(
+        &mut self,
+        world: &WorldState,
+        task_registry: &TaskRegistry,
+        branch: Option<&Node<PlanNode>>,
+    ) -> Vec<Task> {
         // self.available_tasks
         //     .clone()
         //     .into_iter()
         //     .filter(|p| task_registry.precon(p).unwrap_or_default().validate(world))
         //     .collect()
+        let mut planned = Vec::new();
+        if !task_registry.exclusion_groups.is_empty() {
+            let mut curr = branch;
+            while let Some(node) = curr {
+                planned.extend(node.value.task.iter());
+                curr = node.parent.as_deref();
+            }
+        }
         let mut n_vec = Vec::new();
-        for task in self.available_tasks.iter() {
+        let candidates = self
+            .available_tasks
+            .iter()
+            .flat_map(|task| task_registry.expand_targeted(task, &self.target_candidates));
+        for task in candidates {
+            if task_registry.is_on_cooldown(
+                &task.name(),
+                self.cooldowns.get(&task.name()),
+                self.now,
+            ) {
+                if let Some(log) = &mut self.prune_log {
+                    log.push((task.name(), PruneReason::OnCooldown));
+                }
+                continue;
+            }
+            if planned.iter().any(|p| task_registry.excludes(p, task)) {
+                if let Some(log) = &mut self.prune_log {
+                    log.push((task.name(), PruneReason::Excluded));
+                }
+                continue;
+            }
             let Some(precon) = task_registry.precon(task) else {
                 continue;
             };
             if precon.validate(world) {
                 n_vec.push(task.clone());
+            } else if let Some(log) = &mut self.prune_log {
+                log.push((task.name(), PruneReason::Preconditions));
             }
         }
+        // sorted so the search doesn't depend on the order tasks were given in
+        n_vec.sort_by_key(|t| t.name());
         n_vec
     }
+    /// Creates a node for applying the task (primitive or macro) to `world`, which is the parent's world or the current world for seed nodes
     fn make_node(
-        parent: Arc<Node<PlanNode>>,
+        &mut self,
+        parent: Option<Arc<Node<PlanNode>>>,
         task: &Task,
+        goal: &Goal,
         registry: &TaskRegistry,
+        world: &WorldState,
     ) -> Option<Node<PlanNode>> {
-        let data = registry.get_task(task)?;
+        let (virtual_world, task_cost) = registry.simulate(task, world)?;
+        if self
+            .settings
+            .forbidden_states
+            .iter()
+            .any(|forbidden| forbidden.validate(&virtual_world))
+        {
+            trace!("Pruned {} for reaching a forbidden state", task.name());
+            if let Some(log) = &mut self.prune_log {
+                log.push((task.name(), PruneReason::ForbiddenState));
+            }
+            return None;
+        }
+        Some(self.child_node(
+            parent,
+            task,
+            goal,
+            registry,
+            world,
+            virtual_world,
+            task_cost,
+        ))
+    }
 
-        let virtual_world = parent.value.world.concat(data.postconditions());
-        Some(Node::<PlanNode> {
+    /// Applies each task in turn from `world` the same way the search does, returning the last node. `None` if a task can't be applied
+    fn replay(
+        &self,
+        tasks: &[Task],
+        goal: &Goal,
+        registry: &TaskRegistry,
+        world: &WorldState,
+    ) -> Option<Arc<Node<PlanNode>>> {
+        let _epsilon = NumberEpsilonGuard::new(self.settings.number_epsilon);
+        let mut leaf: Option<Arc<Node<PlanNode>>> = None;
+        for task in tasks {
+            let world = leaf.as_ref().map_or(world, |node| &node.value.world);
+            let (virtual_world, task_cost) = registry.simulate(task, world)?;
+            let node = self.child_node(
+                leaf.clone(),
+                task,
+                goal,
+                registry,
+                world,
+                virtual_world,
+                task_cost,
+            );
+            leaf = Some(Arc::new(node));
+        }
+        leaf
+    }
+
+    /// The node for a task applied to `world`, resulting in `virtual_world` at the cost of `task_cost` before any agent modifiers
+    #[allow(clippy::too_many_arguments)]
+    fn child_node(
+        &self,
+        parent: Option<Arc<Node<PlanNode>>>,
+        task: &Task,
+        goal: &Goal,
+        registry: &TaskRegistry,
+        world: &WorldState,
+        virtual_world: WorldState,
+        task_cost: f32,
+    ) -> Node<PlanNode> {
+        let (parent_aggregate, parent_penalty, parent_duration, depth) = match &parent {
+            Some(p) => (
+                p.value.aggregate,
+                p.value.penalty,
+                p.value.duration,
+                p.value.depth + 1,
+            ),
+            None => (0.0, 0.0, Duration::ZERO, 0),
+        };
+        // preferred tags can make a task cheaper, but never free up cost for the rest of the plan
+        let step_cost = (task_cost * self.cost_multiplier / registry.success_prob(task)
+            - registry.tag_bias(task, &self.tag_preferences))
+        .max(0.0);
+        let aggregate = self
+            .settings
+            .cost_aggregation
+            .aggregate(parent_aggregate, step_cost);
+        // kept out of the aggregation, where an aggregation like CostAgg::Max would absorb it
+        let penalty = parent_penalty + self.goal_distance_penalty(goal, &virtual_world);
+        Node::<PlanNode> {
             value: PlanNode {
                 task: Some(task.clone()),
-                cost: parent.value.cost + data.cost(&virtual_world),
+                cost: aggregate + penalty,
+                aggregate,
+                penalty,
                 world: virtual_world,
-                depth: parent.value.depth + 1,
+                depth,
+                duration: parent_duration + registry.estimated_duration(task, world),
             },
-            parent: Some(parent),
-        })
+            parent,
+        }
     }
 }
 
+/// Plans for each `(world, tasks, goal)` problem in turn without involving the ECS, returning `None` where no plan was found.
+/// Intended for profiling and for regression testing the planner across many scenarios
+pub fn plan_batch(
+    registry: &TaskRegistry,
+    problems: &[(WorldState, Vec<Task>, Goal)],
+    max_depth: Option<u32>,
+) -> Vec<Option<Plan>> {
+    problems
+        .iter()
+        .map(|(world, tasks, goal)| {
+            let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+            gen.generate_to_completion(registry, world, max_depth);
+            gen.plans.remove(&goal.name)
+        })
+        .collect()
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
 pub fn system_update_time_sliced_tree_gen(
-    mut query: Query<(&mut TimeSlicedTreeGen, Option<&HtnAgentWorld>), With<HtnAgent>>,
+    mut query: Query<(
+        &mut TimeSlicedTreeGen,
+        &HtnAgent,
+        Option<Ref<HtnAgentWorld>>,
+        Option<Ref<HtnAgentCooldowns>>,
+        Option<&mut HtnAgentPlanStats>,
+        Option<&HtnAgentRestoring>,
+        Option<Ref<HtnAgentGroup>>,
+        Option<Ref<HtnBlackboard>>,
+    )>,
     settings: Res<HtnSettings>,
     registry: Res<TaskRegistry>,
     world: Res<WorldState>,
+    shared: Option<Res<HtnSharedWorld>>,
+    time: Res<Time>,
+    mut cache: Option<ResMut<HtnPlanCache>>,
+    mut metrics: Option<ResMut<HtnMetrics>>,
 ) {
     let timer = Instant::now();
-    for (mut sliced, agent_world) in query.iter_mut() {
-        let active_world = match agent_world {
-            Some(c) => world.concat(&c.0),
-            None => world.to_owned(),
-        };
-        sliced.generate_for_duration(
-            &registry,
-            &active_world,
-            settings.frame_processing_limit,
-            settings.node_branch_limit,
-        );
-
-        if let Some(duration_limit) = settings.frame_processing_limit {
-            if timer.elapsed() > duration_limit {
+    if let Some(cache) = cache.as_deref_mut() {
+        if settings.is_changed() || registry.is_changed() {
+            // plans found with different tasks or settings may no longer be valid, or the best
+            cache.invalidate();
+        }
+    }
+    for (mut sliced, agent, agent_world, cooldowns, stats, restoring, group, blackboard) in
+        query.iter_mut()
+    {
+        let was_dirty = sliced.is_dirty();
+        let fallback_name = agent.fallback_goal.as_ref().map(|g| &g.name);
+        if sliced.fallback_goal.as_ref().map(|g| &g.name) != fallback_name {
+            sliced.fallback_goal = agent.fallback_goal.clone();
+        }
+        if sliced.cost_multiplier != agent.cost_multiplier {
+            sliced.cost_multiplier = agent.cost_multiplier;
+            sliced.mark_dirty();
+        }
+        if sliced.tag_preferences != agent.tag_preferences {
+            sliced.tag_preferences = agent.tag_preferences.clone();
+            sliced.mark_dirty();
+        }
+        let restore_name = restoring.map(|r| &r.0.name);
+        if sliced.restore_goal.as_ref().map(|g| &g.name) != restore_name {
+            sliced.restore_goal = restoring.map(|r| r.0.clone());
+            // the goal being searched for has changed, so any search in progress is for the wrong goal
+            sliced.mark_dirty();
+        }
+        // synced on change rather than compared, as settings holding function pointers can't be compared reliably
+        if settings.is_changed() || sliced.is_added() {
+            sliced.settings = settings.clone();
+        }
+        if cooldowns
+            .as_ref()
+            .map_or(!sliced.cooldowns.is_empty(), |c| c.is_changed())
+        {
+            sliced.cooldowns = cooldowns.map(|c| c.0.clone()).unwrap_or_default();
+            sliced.mark_dirty();
+        }
+        // a task coming off cooldown may allow a plan where the last search found none
+        let (last, now) = (sliced.now, time.elapsed());
+        let cooldown_ended = sliced.cooldowns.iter().any(|(task, executed)| {
+            registry.cooldown(task).is_some_and(|cooldown| {
+                let ends = *executed + cooldown;
+                last < ends && ends <= now
+            })
+        });
+        sliced.now = now;
+        if cooldown_ended {
+            sliced.mark_dirty();
+        }
+        if registry.is_changed()
+            || sliced.is_added()
+            || blackboard.as_ref().is_some_and(|b| b.is_changed())
+        {
+            let candidates: TargetCandidates = registry
+                .targeted
+                .values()
+                .filter_map(|targeted| {
+                    let key = &targeted.candidates_key;
+                    let found = blackboard.as_ref()?.get::<Vec<Entity>>(key)?;
+                    Some((key.clone(), found.clone()))
+                })
+                .collect();
+            if sliced.target_candidates != candidates {
+                sliced.target_candidates = candidates;
+                sliced.mark_dirty();
+            }
+        }
+        let group_changed = group.as_ref().is_some_and(|g| {
+            g.is_changed() || shared.as_ref().is_some_and(|shared| shared.is_changed())
+        });
+        let world_changed = world.is_changed()
+            || sliced.is_added()
+            || group_changed
+            || agent_world.as_ref().is_some_and(|w| w.is_changed());
+        if world_changed {
+            sliced.mark_dirty();
+        }
+        if let (Some(metrics), false, true) = (metrics.as_deref_mut(), was_dirty, sliced.is_dirty())
+        {
+            metrics.replans += 1;
+        }
+        if !sliced.is_dirty() {
+            // nothing has changed since the last search finished, so there's nothing new to find
+            continue;
+        }
+        let active_world = compose_agent_world(
+            &world,
+            shared.as_deref(),
+            group.as_deref(),
+            agent_world.as_deref(),
+        );
+        if let Some(cache) = cache.as_deref_mut() {
+            if sliced.restore_from_cache(cache, &active_world) {
+                continue;
+            }
+        }
+        let before = (sliced.stats.nodes_expanded, sliced.stats.plans_emitted);
+        sliced.generate_for_duration(
+            &registry,
+            &active_world,
+            settings.frame_processing_limit,
+            settings.node_branch_limit,
+        );
+        if let Some(metrics) = metrics.as_deref_mut() {
+            metrics.nodes_expanded += sliced.stats.nodes_expanded - before.0;
+            metrics.plans_emitted += sliced.stats.plans_emitted - before.1;
+        }
+        if sliced.active_nodes.is_empty() {
+            sliced.dirty = false;
+            if let Some(cache) = cache.as_deref_mut() {
+                sliced.store_in_cache(cache, &active_world);
+            }
+        }
+        if let Some(mut stats) = stats {
+            if *stats != sliced.stats {
+                *stats = sliced.stats.clone();
+            }
+        }
+
+        if let Some(duration_limit) = settings.frame_processing_limit {
+            if timer.elapsed() > duration_limit {
                 break;
             }
         }
     }
+    if let Some(metrics) = metrics.as_deref_mut() {
+        metrics.frames += 1;
+        metrics.planning_time += timer.elapsed();
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::time::Duration;
+
     use bevy::prelude::Component;
     use goals::Goal;
-    use plan_data::TimeSlicedTreeGen;
+    use plan_data::{
+        plan_batch, system_update_time_sliced_tree_gen, HtnAgentPlanStats, HtnMetrics, Plan,
+        PruneReason, TimeSlicedTreeGen,
+    };
 
     use crate::prelude::*;
 
@@ -412,4 +1476,2133 @@ mod tests {
         assert_eq!(plan.tasks.len(), 3);
         assert_eq!(plan.cost, 3.0);
     }
+
+    #[test]
+    fn cooldown_excludes_task() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "eat_slowly",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            2.,
+        );
+        registry.set_cooldown("eat", Duration::from_secs(60));
+
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+        let tasks = vec![Task::primitive("eat"), Task::primitive("eat_slowly")];
+        let world = WorldState::new().add("hungry", true).build();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.decompose_tasks(), vec!["eat".to_string()]);
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+        gen.cooldowns.insert("eat".into(), gen.now);
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.decompose_tasks(), vec!["eat_slowly".to_string()]);
+    }
+
+    #[test]
+    fn cooldown_ending_replans() {
+        use bevy::prelude::*;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        registry.set_cooldown("eat", Duration::from_secs(5));
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.insert_resource(Time::<()>::default());
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("hungry", true).build());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentCooldowns([("eat".to_string(), Duration::ZERO)].into()),
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("eat")],
+                    vec![goal.clone()],
+                ),
+            ))
+            .id();
+        let has_plan = |app: &App| {
+            app.world()
+                .get::<TimeSlicedTreeGen>(entity)
+                .unwrap()
+                .plans
+                .contains_key(&goal.name)
+        };
+        let advance = |app: &mut App, secs: u64| {
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs(secs));
+            app.update();
+        };
+
+        app.update();
+        assert!(!has_plan(&app));
+        advance(&mut app, 2);
+        assert!(!has_plan(&app));
+        // the world is unchanged, but eating is possible again
+        advance(&mut app, 3);
+        assert!(has_plan(&app));
+    }
+
+    #[test]
+    fn goal_distance_weighted_planning() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "wander",
+            Requirements::new().req_equals("done", false).build(),
+            WorldState::new().add("wandered", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "finish_all",
+            Requirements::new().req_equals("wandered", true).build(),
+            WorldState::new()
+                .add("done", true)
+                .add("tidy", true)
+                .build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "tidy",
+            Requirements::new().req_equals("tidy", false).build(),
+            WorldState::new().add("tidy", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "finish",
+            Requirements::new()
+                .req_equals("tidy", true)
+                .req_equals("done", false)
+                .build(),
+            WorldState::new().add("done", true).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Done and tidy",
+            Requirements::new()
+                .req_equals("done", true)
+                .req_equals("tidy", true)
+                .build(),
+            1.0,
+        );
+        let tasks = vec![
+            Task::primitive("tidy"),
+            Task::primitive("finish"),
+            Task::primitive("wander"),
+            Task::primitive("finish_all"),
+        ];
+        let world = WorldState::new()
+            .add("done", false)
+            .add("tidy", false)
+            .build();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &world, Some(4));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.cost, 2.0);
+        assert_eq!(plan.simple_print_tasks(), vec!["finish_all", "wander"]);
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+        gen.settings.goal_distance_weight = Some(1.0);
+        gen.generate_to_completion(&registry, &world, Some(4));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        // tidy leaves 1 requirement unmet, wander leaves 2
+        assert_eq!(plan.cost, 3.0);
+        assert_eq!(plan.simple_print_tasks(), vec!["finish", "tidy"]);
+    }
+
+    #[test]
+    fn reset_tree_gen() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "sleep",
+            Requirements::new().req_equals("tired", true).build(),
+            WorldState::new().add("tired", false).build(),
+            1.,
+        );
+        let eat_goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+        let sleep_goal = Goal::new(
+            "Be Rested",
+            Requirements::new().req_equals("tired", false).build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("hungry", true)
+            .add("tired", true)
+            .build();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("eat")],
+            vec![eat_goal.clone()],
+        );
+        gen.generate_to_completion(&registry, &world, Some(8));
+        assert!(gen.plans.contains_key(&eat_goal.name));
+
+        gen.reset(vec![Task::primitive("sleep")], vec![sleep_goal.clone()]);
+        assert!(gen.plans.is_empty());
+        assert!(gen.active_nodes.is_empty());
+        assert!(gen.valid_nodes.is_empty());
+
+        gen.generate_to_completion(&registry, &world, Some(8));
+        assert!(!gen.plans.contains_key(&eat_goal.name));
+        let plan = gen
+            .plans
+            .get(&sleep_goal.name)
+            .expect("Failed to find a plan");
+        assert_eq!(plan.decompose_tasks(), vec!["sleep".to_string()]);
+    }
+
+    #[test]
+    fn mark_dirty_discards_stale_plans() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat_snack",
+            Requirements::new().req_equals("has_snack", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "cook",
+            Requirements::new(),
+            WorldState::new().add("hungry", false).build(),
+            5.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("eat_snack"), Task::primitive("cook")],
+            vec![goal.clone()],
+        );
+        let with_snack = WorldState::new()
+            .add("hungry", true)
+            .add("has_snack", true)
+            .build();
+        gen.generate_to_completion(&registry, &with_snack, Some(4));
+        assert_eq!(gen.plans[&goal.name].execution_order(), vec!["eat_snack"]);
+
+        // the snack is gone, so the cheaper plan is no longer valid and must not block the new one
+        gen.mark_dirty();
+        assert!(gen.plans.is_empty());
+        let without_snack = WorldState::new()
+            .add("hungry", true)
+            .add("has_snack", false)
+            .build();
+        gen.generate_to_completion(&registry, &without_snack, Some(4));
+        assert_eq!(gen.plans[&goal.name].execution_order(), vec!["cook"]);
+    }
+
+    #[test]
+    fn regenerate_only_on_world_change() {
+        use bevy::prelude::*;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentWorld(WorldState::new().add("hungry", true).build()),
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("eat")],
+                    vec![goal.clone()],
+                ),
+            ))
+            .id();
+        app.update();
+
+        let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen.plans.contains_key(&goal.name));
+        assert!(!gen.is_dirty());
+
+        // discard the plan. Since nothing changed, it should not be regenerated
+        app.world_mut()
+            .get_mut::<TimeSlicedTreeGen>(entity)
+            .unwrap()
+            .plans
+            .clear();
+        app.update();
+        app.update();
+        let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen.plans.is_empty());
+
+        // changing the agent's world triggers a new search
+        app.world_mut()
+            .get_mut::<HtnAgentWorld>(entity)
+            .unwrap()
+            .0
+            .insert("tired", true);
+        app.update();
+        let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen.plans.contains_key(&goal.name));
+
+        // as does changing the global world
+        app.world_mut()
+            .get_mut::<TimeSlicedTreeGen>(entity)
+            .unwrap()
+            .plans
+            .clear();
+        app.world_mut()
+            .resource_mut::<WorldState>()
+            .insert("raining", true);
+        app.update();
+        let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen.plans.contains_key(&goal.name));
+    }
+
+    #[test]
+    fn world_dependent_effects() {
+        use bevy::ecs::system::EntityCommands;
+
+        /// only kills the enemy if it is weak enough to go down in one hit
+        struct Attack {
+            precon: Requirements,
+            postcon: WorldState,
+        }
+        impl TaskData for Attack {
+            fn preconditions(&self) -> &Requirements {
+                &self.precon
+            }
+            fn postconditions(&self) -> &WorldState {
+                &self.postcon
+            }
+            fn postconditions_for(&self, world: &WorldState) -> WorldState {
+                match world.get("enemy_hp") {
+                    Some(Variant::Number(hp)) if hp <= 10. => self.postcon.clone(),
+                    _ => WorldState::new(),
+                }
+            }
+            fn add(&self, entity: &mut EntityCommands) {
+                entity.insert(TaskStub);
+            }
+            fn remove(&self, entity: &mut EntityCommands) {
+                entity.remove::<TaskStub>();
+            }
+            fn cost(&self, _: &WorldState) -> f32 {
+                1.0
+            }
+        }
+
+        let mut registry = TaskRegistry::new();
+        registry.custom_task(
+            "attack",
+            Box::new(Attack {
+                precon: Requirements::new().req_equals("enemy_dead", false).build(),
+                postcon: WorldState::new().add("enemy_dead", true).build(),
+            }),
+        );
+        let goal = Goal::new(
+            "Kill enemy",
+            Requirements::new().req_equals("enemy_dead", true).build(),
+            1.0,
+        );
+        let data = registry.get_named(&"attack".to_string()).unwrap();
+        let weak = WorldState::new()
+            .add("enemy_dead", false)
+            .add("enemy_hp", 5.)
+            .build();
+        let strong = WorldState::new()
+            .add("enemy_dead", false)
+            .add("enemy_hp", 50.)
+            .build();
+        assert_eq!(
+            data.postconditions_for(&weak).get("enemy_dead"),
+            Some(true.into())
+        );
+        assert_eq!(data.postconditions_for(&strong).get("enemy_dead"), None);
+
+        let mut gen =
+            TimeSlicedTreeGen::new_initialized(vec![Task::primitive("attack")], vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &weak, Some(4));
+        assert!(gen.plans.contains_key(&goal.name));
+
+        let mut gen =
+            TimeSlicedTreeGen::new_initialized(vec![Task::primitive("attack")], vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &strong, Some(4));
+        assert!(!gen.plans.contains_key(&goal.name));
+    }
+
+    #[test]
+    fn agent_plan_stats() {
+        use bevy::prelude::*;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            2.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentWorld(WorldState::new().add("hungry", true).build()),
+                HtnAgentPlanStats::default(),
+                TimeSlicedTreeGen::new_initialized(vec![Task::primitive("eat")], vec![goal]),
+            ))
+            .id();
+        app.update();
+
+        let stats = app
+            .world()
+            .get::<HtnAgentPlanStats>(entity)
+            .unwrap()
+            .clone();
+        assert!(stats.nodes_expanded > 0);
+        assert_eq!(stats.plans_emitted, 1);
+        assert_eq!(stats.last_plan_cost, 2.0);
+
+        // searching again continues to accumulate
+        app.world_mut()
+            .get_mut::<HtnAgentWorld>(entity)
+            .unwrap()
+            .0
+            .insert("tired", true);
+        app.update();
+        let new_stats = app.world().get::<HtnAgentPlanStats>(entity).unwrap();
+        assert!(new_stats.nodes_expanded > stats.nodes_expanded);
+        assert_eq!(new_stats.plans_emitted, 2);
+    }
+
+    #[test]
+    fn plan_dry_run_validation() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .build();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("open_door"), Task::primitive("goto_b")],
+            vec![goal.clone()],
+        );
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec!["open_door", "goto_b"]);
+        assert_eq!(plan.validate_against(&registry, &world, &goal), Ok(()));
+
+        // walking through a closed door fails on the first step
+        let broken = Plan {
+            tasks: vec![Task::primitive("open_door"), Task::primitive("goto_b")].into(),
+            cost: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(broken.validate_against(&registry, &world, &goal), Err(0));
+
+        // every step is valid, but the goal is never reached
+        let incomplete = Plan {
+            tasks: vec![Task::primitive("open_door")].into(),
+            cost: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            incomplete.validate_against(&registry, &world, &goal),
+            Err(1)
+        );
+    }
+
+    #[test]
+    fn macro_task_planning() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            2.,
+        );
+        let go_through_door = Task::macro_(
+            vec![Task::primitive("open_door"), Task::primitive("goto_b")].into_iter(),
+            "go_through_door".into(),
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .build();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(vec![go_through_door], vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &world, Some(4));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.simple_print_tasks(), vec!["go_through_door"]);
+        assert_eq!(plan.cost, 3.0);
+        assert_eq!(plan.execution_order(), vec!["open_door", "goto_b"]);
+        assert_eq!(plan.validate_against(&registry, &world, &goal), Ok(()));
+    }
+
+    #[test]
+    fn open_node_cap() {
+        let mut registry = TaskRegistry::new();
+        let mut tasks = Vec::new();
+        for (i, name) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new(),
+                WorldState::new().add(name, true).build(),
+                i as f32 + 1.,
+            );
+            tasks.push(Task::primitive(name));
+        }
+        // unreachable, so the whole tree is searched
+        let goal = Goal::new(
+            "Impossible",
+            Requirements::new().req_equals("never", true).build(),
+            1.0,
+        );
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+        gen.settings.max_open_nodes = Some(8);
+        gen.try_seed_active_nodes(&goal, &registry, &WorldState::new());
+        assert!(gen.active_nodes.len() <= 8);
+        let mut max_seen = 0;
+        while !gen.active_nodes.is_empty() {
+            gen.generate_single(&goal, &registry, Some(6));
+            max_seen = max_seen.max(gen.active_nodes.len());
+            assert!(gen.active_nodes.len() <= 8);
+        }
+        assert_eq!(max_seen, 8);
+    }
+
+    #[test]
+    fn number_epsilon_planning() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "aim",
+            Requirements::new(),
+            WorldState::new().add("angle", 45.001).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Aimed",
+            Requirements::new().req_equals("angle", 45.).build(),
+            1.0,
+        );
+
+        let mut gen =
+            TimeSlicedTreeGen::new_initialized(vec![Task::primitive("aim")], vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &WorldState::new(), Some(2));
+        assert!(!gen.plans.contains_key(&goal.name));
+
+        gen.settings.number_epsilon = 0.01;
+        gen.mark_dirty();
+        gen.generate_to_completion(&registry, &WorldState::new(), Some(2));
+        assert!(gen.plans.contains_key(&goal.name));
+    }
+
+    #[test]
+    fn deterministic_planning() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "goto_door",
+            Requirements::new().req_equals("near_door", false).build(),
+            WorldState::new().add("near_door", true).build(),
+            1.,
+        );
+        // two equally good ways through the door
+        for name in ["open_door", "kick_door"] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new()
+                    .req_equals("near_door", true)
+                    .req_equals("door_open", false)
+                    .build(),
+                WorldState::new().add("door_open", true).build(),
+                1.,
+            );
+        }
+        registry.task::<TaskStub, _>(
+            "walk_thru_door",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let initial_world = WorldState::new()
+            .add("room", "A")
+            .add("near_door", false)
+            .add("door_open", false)
+            .build();
+
+        let names = ["goto_door", "open_door", "kick_door", "walk_thru_door"];
+        let run = |reverse: bool| {
+            let mut tasks: Vec<Task> = names.iter().map(|n| Task::primitive(*n)).collect();
+            if reverse {
+                tasks.reverse();
+            }
+            let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+            gen.generate_to_completion(&registry, &initial_world, Some(8));
+            gen.plans.get(&goal.name).cloned().expect("Failed to plan")
+        };
+
+        let first = run(false);
+        for reverse in [false, true] {
+            let other = run(reverse);
+            assert_eq!(first.execution_order(), other.execution_order());
+            assert_eq!(first.cost, other.cost);
+        }
+        assert_eq!(
+            first.execution_order(),
+            vec!["goto_door", "kick_door", "walk_thru_door"]
+        );
+    }
+
+    #[test]
+    fn plan_filter_veto() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "cut_through_c",
+            Requirements::new().req_equals("room", "A").build(),
+            WorldState::new().add("room", "C").build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "leave_c",
+            Requirements::new().req_equals("room", "C").build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "long_way_round",
+            Requirements::new().req_equals("room", "A").build(),
+            WorldState::new().add("room", "B").build(),
+            5.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let tasks = vec![
+            Task::primitive("cut_through_c"),
+            Task::primitive("leave_c"),
+            Task::primitive("long_way_round"),
+        ];
+        let world = WorldState::new().add("room", "A").build();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &world, Some(4));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.cost, 2.0);
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+        gen.plan_filter = Some(|plan| !plan.execution_order().iter().any(|t| t == "cut_through_c"));
+        gen.generate_to_completion(&registry, &world, Some(4));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.cost, 5.0);
+        assert_eq!(plan.execution_order(), vec!["long_way_round"]);
+    }
+
+    #[test]
+    fn agent_cost_multiplier() {
+        use bevy::prelude::*;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "walk",
+            Requirements::new().req_equals("room", "A").build(),
+            WorldState::new().add("room", "B").build(),
+            4.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("room", "A").build());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let mut spawn_agent = |multiplier: f32| {
+            let agent = HtnAgent {
+                cost_multiplier: multiplier,
+                ..Default::default()
+            };
+            app.world_mut()
+                .spawn((
+                    agent,
+                    TimeSlicedTreeGen::new_initialized(
+                        vec![Task::primitive("walk")],
+                        vec![goal.clone()],
+                    ),
+                ))
+                .id()
+        };
+        let normal = spawn_agent(1.0);
+        let fast = spawn_agent(0.5);
+        app.update();
+
+        let cost = |entity: Entity| {
+            app.world().get::<TimeSlicedTreeGen>(entity).unwrap().plans[&goal.name].cost
+        };
+        assert_eq!(cost(normal), 4.0);
+        assert_eq!(cost(fast), 2.0);
+    }
+
+    #[test]
+    fn plan_cache_shared_between_agents() {
+        use bevy::prelude::*;
+
+        use crate::planning::cache::HtnPlanCache;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new());
+        app.insert_resource(HtnSettings::default());
+        app.insert_resource(HtnPlanCache::new(8));
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let spawn_agent = |app: &mut App, world: WorldState, tasks: Vec<Task>| {
+            app.world_mut()
+                .spawn((
+                    HtnAgent::new(),
+                    HtnAgentWorld(world),
+                    TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]),
+                ))
+                .id()
+        };
+        let room_a = || {
+            WorldState::new()
+                .add("room", "A")
+                .add("door_open", false)
+                .build()
+        };
+        let both_tasks = || vec![Task::primitive("open_door"), Task::primitive("goto_b")];
+        let first = spawn_agent(&mut app, room_a(), both_tasks());
+        app.update();
+
+        // the same situation, built and listed in a different order
+        let second = spawn_agent(
+            &mut app,
+            WorldState::new()
+                .add("door_open", false)
+                .add("room", "A")
+                .build(),
+            vec![Task::primitive("goto_b"), Task::primitive("open_door")],
+        );
+        app.update();
+
+        let gen = |entity: Entity| app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen(first).stats.nodes_expanded > 0);
+        assert_eq!(gen(second).stats.nodes_expanded, 0);
+        assert!(!gen(second).is_dirty());
+        assert_eq!(
+            gen(second).plans[&goal.name].execution_order(),
+            vec!["open_door", "goto_b"]
+        );
+        let cache = app.world().resource::<HtnPlanCache>();
+        assert_eq!((cache.hits, cache.len()), (1, 1));
+
+        // a goal of the same name with different requirements is a different search
+        let other_goal = Goal::new(
+            goal.name.clone(),
+            Requirements::new().req_equals("door_open", true).build(),
+            1.0,
+        );
+        let third = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                HtnAgentWorld(room_a()),
+                TimeSlicedTreeGen::new_initialized(both_tasks(), vec![other_goal.clone()]),
+            ))
+            .id();
+        app.update();
+        let gen = |entity: Entity| app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen(third).stats.nodes_expanded > 0);
+        assert_eq!(
+            gen(third).plans[&other_goal.name].execution_order(),
+            vec!["open_door"]
+        );
+
+        // as is any search after the tasks change
+        app.world_mut()
+            .resource_mut::<TaskRegistry>()
+            .task::<TaskStub, _>(
+                "goto_b",
+                Requirements::new(),
+                WorldState::new().add("room", "B").build(),
+                1.,
+            );
+        let fourth = spawn_agent(&mut app, room_a(), both_tasks());
+        app.update();
+        let gen = |entity: Entity| app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert!(gen(fourth).stats.nodes_expanded > 0);
+        assert_eq!(
+            gen(fourth).plans[&goal.name].execution_order(),
+            vec!["goto_b"]
+        );
+    }
+
+    #[test]
+    fn tag_preferences_bias_plans() {
+        use bevy::prelude::*;
+
+        let mut registry = TaskRegistry::new();
+        for (name, cost) in [("charge", 2.), ("sneak", 3.)] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals("room", "A").build(),
+                WorldState::new().add("room", "B").build(),
+                cost,
+            );
+        }
+        registry.set_tags("sneak", ["stealthy"]);
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("room", "A").build());
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let mut spawn_agent = |tag_preferences: &[(&str, f32)]| {
+            let agent = HtnAgent {
+                tag_preferences: tag_preferences
+                    .iter()
+                    .map(|(tag, weight)| (tag.to_string(), *weight))
+                    .collect(),
+                ..Default::default()
+            };
+            app.world_mut()
+                .spawn((
+                    agent,
+                    TimeSlicedTreeGen::new_initialized(
+                        vec![Task::primitive("charge"), Task::primitive("sneak")],
+                        vec![goal.clone()],
+                    ),
+                ))
+                .id()
+        };
+        let brute = spawn_agent(&[]);
+        let stealthy = spawn_agent(&[("stealthy", 2.0)]);
+        app.update();
+
+        let plan = |entity: Entity| {
+            let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+            let plan = &gen.plans[&goal.name];
+            (plan.execution_order(), plan.cost)
+        };
+        assert_eq!(plan(brute), (vec!["charge".to_string()], 2.0));
+        assert_eq!(plan(stealthy), (vec!["sneak".to_string()], 1.0));
+    }
+
+    #[test]
+    fn group_shared_world() {
+        use bevy::prelude::*;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "flank",
+            Requirements::new()
+                .req_equals("enemy_spotted", true)
+                .req_equals("flanking", false)
+                .build(),
+            WorldState::new().add("flanking", true).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Flank",
+            Requirements::new().req_equals("flanking", true).build(),
+            1.0,
+        );
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("flanking", false).build());
+        app.insert_resource(HtnSettings::default());
+        app.insert_resource(HtnSharedWorld::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let mut spawn_agent = |group: Option<GroupId>| {
+            let mut entity = app.world_mut().spawn((
+                HtnAgent::new(),
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("flank")],
+                    vec![goal.clone()],
+                ),
+            ));
+            if let Some(group) = group {
+                entity.insert(HtnAgentGroup(group));
+            }
+            entity.id()
+        };
+        let squad_member = spawn_agent(Some(1));
+        let other_squad = spawn_agent(Some(2));
+        let loner = spawn_agent(None);
+        app.update();
+        let has_plan = |app: &App, entity: Entity| {
+            app.world()
+                .get::<TimeSlicedTreeGen>(entity)
+                .unwrap()
+                .plans
+                .contains_key(&goal.name)
+        };
+        assert!(!has_plan(&app, squad_member));
+
+        // one squad spots the enemy, which only its members know about
+        app.world_mut()
+            .resource_mut::<HtnSharedWorld>()
+            .group_mut(1)
+            .insert("enemy_spotted", true);
+        app.update();
+        assert!(has_plan(&app, squad_member));
+        assert!(!has_plan(&app, other_squad));
+        assert!(!has_plan(&app, loner));
+    }
+
+    #[test]
+    fn plan_explanation() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "goto_door",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("near_door", false)
+                .build(),
+            WorldState::new().add("near_door", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new()
+                .req_equals("near_door", true)
+                .req_equals("door_open", false)
+                .build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "walk_thru_door",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .req_equals("near_door", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let initial_world = WorldState::new()
+            .add("room", "A")
+            .add("near_door", false)
+            .add("door_open", false)
+            .build();
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![
+                Task::primitive("goto_door"),
+                Task::primitive("open_door"),
+                Task::primitive("walk_thru_door"),
+            ],
+            vec![goal.clone()],
+        );
+        gen.generate_to_completion(&registry, &initial_world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+
+        let steps = plan.explain(&registry, &initial_world, &goal);
+        let tasks: Vec<&str> = steps.iter().map(|s| s.task.as_str()).collect();
+        assert_eq!(tasks, vec!["goto_door", "open_door", "walk_thru_door"]);
+        assert!(steps.iter().all(|s| s.unmet_preconditions.is_empty()));
+
+        assert_eq!(
+            steps[1].satisfied_preconditions,
+            vec![UniqueName::new("door_open"), UniqueName::new("near_door")]
+        );
+        assert_eq!(
+            steps[1].effects,
+            vec![(UniqueName::new("door_open"), true.into())]
+        );
+        assert!(steps[1].advanced_goal.is_empty());
+
+        assert_eq!(
+            steps[2].effects,
+            vec![(UniqueName::new("room"), "B".into())]
+        );
+        assert_eq!(steps[2].advanced_goal, vec![UniqueName::new("room")]);
+    }
+
+    #[test]
+    fn simplify_removes_noop_pairs() {
+        let mut registry = TaskRegistry::new();
+        for (open, close, key) in [
+            ("open_door", "close_door", "door_open"),
+            ("open_window", "close_window", "window_open"),
+        ] {
+            registry.task::<TaskStub, _>(
+                open,
+                Requirements::new().req_equals(key, false).build(),
+                WorldState::new().add(key, true).build(),
+                1.,
+            );
+            registry.task::<TaskStub, _>(
+                close,
+                Requirements::new().req_equals(key, true).build(),
+                WorldState::new().add(key, false).build(),
+                1.,
+            );
+        }
+        registry.task::<TaskStub, _>(
+            "walk",
+            Requirements::new().req_equals("room", "A").build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+
+        let execution = [
+            "open_window",
+            "open_door",
+            "close_door",
+            "close_window",
+            "open_door",
+            "walk",
+        ];
+        let plan = Plan {
+            tasks: execution
+                .iter()
+                .rev()
+                .map(|t| Task::primitive(*t))
+                .collect(),
+            cost: execution.len() as f32,
+            ..Default::default()
+        };
+
+        let world = WorldState::new()
+            .add("door_open", false)
+            .add("window_open", false)
+            .add("room", "A")
+            .build();
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let mut planner = TimeSlicedTreeGen::new_initialized(
+            execution.iter().map(|t| Task::primitive(*t)).collect(),
+            vec![goal.clone()],
+        );
+        let simplified = plan.simplify(&registry, &planner, &world, &goal);
+        assert_eq!(simplified.execution_order(), vec!["open_door", "walk"]);
+        assert_eq!(simplified.cost, 2.0);
+
+        // costs are worked out the way the planner would, including the agent's multiplier
+        planner.cost_multiplier = 2.0;
+        assert_eq!(plan.simplify(&registry, &planner, &world, &goal).cost, 4.0);
+        // the remaining steps can't run from a world where the door is already open
+        let open_world = world.concat(&WorldState::new().add("door_open", true).build());
+        assert_eq!(
+            plan.simplify(&registry, &planner, &open_world, &goal)
+                .execution_order(),
+            plan.execution_order()
+        );
+
+        assert_eq!(
+            world.apply_plan(&plan, &registry),
+            world.apply_plan(&simplified, &registry)
+        );
+    }
+
+    #[test]
+    fn cheapest_valid_node_emitted_first() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "cheap",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "expensive",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            5.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+        let world = WorldState::new().add("hungry", true).build();
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("expensive"), Task::primitive("cheap")],
+            vec![goal.clone()],
+        );
+        gen.try_seed_active_nodes(&goal, &registry, &world);
+        while !gen.active_nodes.is_empty() {
+            gen.generate_single(&goal, &registry, Some(8));
+        }
+        assert_eq!(gen.valid_nodes.len(), 2);
+
+        // only enough budget left for a single emission
+        gen.try_emit_single(&goal);
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec!["cheap"]);
+        assert_eq!(plan.cost, 1.0);
+    }
+
+    #[test]
+    fn interleaved_emission_keeps_global_minimum() {
+        let mut registry = TaskRegistry::new();
+        let mut tasks = Vec::new();
+        for (name, cost) in [("walk", 4.), ("bus", 2.), ("cycle", 3.), ("teleport", 1.)] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals("at_work", false).build(),
+                WorldState::new().add("at_work", true).build(),
+                cost,
+            );
+            tasks.push(Task::primitive(name));
+        }
+        let goal = Goal::new(
+            "Get To Work",
+            Requirements::new().req_equals("at_work", true).build(),
+            1.0,
+        );
+        let world = WorldState::new().add("at_work", false).build();
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+        gen.try_seed_active_nodes(&goal, &registry, &world);
+
+        // emit at most one plan per node generated, like a frame budget running out mid-search
+        let mut best_so_far = f32::INFINITY;
+        while !gen.active_nodes.is_empty() || !gen.valid_nodes.is_empty() {
+            gen.generate_single(&goal, &registry, Some(8));
+            let pending = gen
+                .valid_nodes
+                .iter()
+                .map(|v| v.node.value.cost)
+                .fold(f32::INFINITY, f32::min);
+            best_so_far = best_so_far.min(pending);
+            gen.try_emit_single(&goal);
+            let stored = gen.plans.get(&goal.name).map(|plan| plan.cost);
+            assert!(stored.is_some_and(|cost| cost <= pending));
+            assert!(stored.is_some_and(|cost| cost == best_so_far));
+        }
+        assert_eq!(
+            gen.plans.get(&goal.name).unwrap().execution_order(),
+            vec!["teleport"]
+        );
+    }
+
+    #[test]
+    fn unreliable_task_avoided() {
+        let mut registry = TaskRegistry::new();
+        registry.probabilistic_task::<TaskStub, _>(
+            "pick_lock",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+            0.2,
+        );
+        registry.task::<TaskStub, _>(
+            "use_key",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            2.,
+        );
+        let goal = Goal::new(
+            "Open the door",
+            Requirements::new().req_equals("door_open", true).build(),
+            1.0,
+        );
+        let world = WorldState::new().add("door_open", false).build();
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![Task::primitive("pick_lock"), Task::primitive("use_key")],
+            vec![goal.clone()],
+        );
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec!["use_key"]);
+        assert_eq!(plan.cost, 2.0);
+
+        // once reliable enough, the cheaper task wins out again
+        registry.set_success_prob("pick_lock", 0.8);
+        gen.reset(
+            vec![Task::primitive("pick_lock"), Task::primitive("use_key")],
+            vec![goal.clone()],
+        );
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec!["pick_lock"]);
+    }
+
+    #[test]
+    fn plan_task_components() {
+        use bevy::prelude::World;
+
+        #[derive(Component, Default)]
+        struct OpenDoor;
+
+        let mut registry = TaskRegistry::new();
+        registry.task::<OpenDoor, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "walk",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let plan = Plan {
+            tasks: vec![Task::primitive("walk"), Task::primitive("open_door")].into(),
+            cost: 2.0,
+            ..Default::default()
+        };
+
+        let mut insertions = plan.into_task_components(&registry);
+        assert_eq!(insertions.len(), 2);
+
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        let first = insertions.remove(0);
+        first(&mut world.commands().entity(entity));
+        world.flush();
+        assert!(world.get::<OpenDoor>(entity).is_some());
+        assert!(world.get::<TaskStub>(entity).is_none());
+    }
+
+    #[test]
+    fn faster_plan_breaks_cost_tie() {
+        let mut registry = TaskRegistry::new();
+        registry.timed_task::<TaskStub, _>(
+            "drive",
+            Requirements::new().req_equals("room", "A").build(),
+            WorldState::new().add("room", "B").build(),
+            2.,
+            Duration::from_secs(3),
+        );
+        registry.timed_task::<TaskStub, _>(
+            "walk",
+            Requirements::new().req_equals("room", "A").build(),
+            WorldState::new().add("room", "B").build(),
+            2.,
+            Duration::from_secs(10),
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let world = WorldState::new().add("room", "A").build();
+        for tasks in [["drive", "walk"], ["walk", "drive"]] {
+            let mut gen = TimeSlicedTreeGen::new_initialized(
+                tasks.iter().map(|t| Task::primitive(*t)).collect(),
+                vec![goal.clone()],
+            );
+            gen.generate_to_completion(&registry, &world, Some(8));
+            let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+            assert_eq!(plan.execution_order(), vec!["drive"]);
+            assert_eq!(plan.cost, 2.0);
+            assert_eq!(plan.estimated_duration, Duration::from_secs(3));
+        }
+    }
+
+    #[test]
+    fn prune_log_records_unmet_preconditions() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .build();
+        let new_gen = || {
+            TimeSlicedTreeGen::new_initialized(
+                vec![Task::primitive("open_door"), Task::primitive("goto_b")],
+                vec![goal.clone()],
+            )
+        };
+
+        let mut gen = new_gen();
+        gen.generate_to_completion(&registry, &world, Some(8));
+        assert!(gen.prune_log.is_none());
+
+        let mut gen = new_gen();
+        gen.prune_log = Some(Vec::new());
+        gen.generate_to_completion(&registry, &world, Some(8));
+        assert!(gen.plans.contains_key(&goal.name));
+        assert_eq!(
+            gen.prune_log.unwrap(),
+            vec![
+                // the door is closed to begin with
+                ("goto_b".to_string(), PruneReason::Preconditions),
+                // and can't be opened twice
+                ("open_door".to_string(), PruneReason::Preconditions),
+            ]
+        );
+    }
+
+    #[test]
+    fn any_of_goal_targets() {
+        let mut registry = TaskRegistry::new();
+        let mut tasks = Vec::new();
+        for (name, key, cost) in [
+            ("take_cover", "in_cover", 3.),
+            ("flee", "fled", 2.),
+            ("fight", "enemy_dead", 5.),
+        ] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals(key, false).build(),
+                WorldState::new().add(key, true).build(),
+                cost,
+            );
+            tasks.push(Task::primitive(name));
+        }
+        let goal = Goal::any_of(
+            "Be Safe",
+            ["in_cover", "fled", "enemy_dead"]
+                .map(|key| Requirements::new().req_equals(key, true).build()),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("in_cover", false)
+            .add("fled", false)
+            .add("enemy_dead", false)
+            .build();
+        assert!(!goal.is_satisfied(&world));
+        assert!(goal.is_satisfied(&world.clone().add("enemy_dead", true).build()));
+
+        // any one target will do, so the cheapest of them is chosen
+        let plans = plan_batch(&registry, &[(world.clone(), tasks.clone(), goal)], Some(4));
+        assert_eq!(plans[0].as_ref().unwrap().execution_order(), vec!["flee"]);
+
+        // without fleeing as a target, cover is the next cheapest
+        let goal = Goal::new(
+            "Be Safe",
+            Requirements::new().req_equals("in_cover", true).build(),
+            1.0,
+        )
+        .or(Requirements::new().req_equals("enemy_dead", true).build());
+        let plans = plan_batch(&registry, &[(world, tasks, goal)], Some(4));
+        assert_eq!(
+            plans[0].as_ref().unwrap().execution_order(),
+            vec!["take_cover"]
+        );
+    }
+
+    #[test]
+    fn metrics_accumulate_over_frames() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "eat",
+            Requirements::new().req_equals("hungry", true).build(),
+            WorldState::new().add("hungry", false).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Be Not Hungry",
+            Requirements::new().req_equals("hungry", false).build(),
+            1.0,
+        );
+
+        let mut app = bevy::app::App::new();
+        app.add_plugins(bevy::MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(WorldState::new().add("hungry", true).build());
+        app.insert_resource(HtnSettings::default());
+        app.init_resource::<HtnMetrics>();
+        app.add_systems(bevy::app::Update, system_update_time_sliced_tree_gen);
+        for _ in 0..2 {
+            let mut agent = HtnAgent::new();
+            agent.add_goal(goal.name.clone(), goal.requires.clone(), 1.0);
+            app.world_mut().spawn((
+                agent,
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("eat")],
+                    vec![goal.clone()],
+                ),
+            ));
+        }
+
+        app.update();
+        let first = app.world().resource::<HtnMetrics>().snapshot();
+        assert!(first.nodes_expanded >= 2);
+        assert_eq!(first.plans_emitted, 2);
+        assert_eq!(first.replans, 0);
+
+        // nothing changed, so there is nothing to search for
+        app.update();
+        assert_eq!(
+            app.world().resource::<HtnMetrics>().nodes_expanded,
+            first.nodes_expanded
+        );
+
+        for _ in 0..3 {
+            app.world_mut()
+                .resource_mut::<WorldState>()
+                .insert("hungry", true);
+            app.update();
+        }
+        let metrics = app.world().resource::<HtnMetrics>();
+        assert_eq!(metrics.frames, 5);
+        assert_eq!(metrics.replans, 6);
+        assert_eq!(metrics.plans_emitted, 8);
+        assert!(metrics.nodes_expanded > first.nodes_expanded);
+    }
+
+    #[test]
+    fn exclusion_groups_never_share_a_plan() {
+        let mut registry = TaskRegistry::new();
+        for (name, weapon) in [("equip_sword", "sword"), ("equip_bow", "bow")] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals(weapon, false).build(),
+                WorldState::new().add(weapon, true).build(),
+                1.,
+            );
+        }
+        registry.task::<TaskStub, _>(
+            "train",
+            Requirements::new()
+                .req_equals("sword", true)
+                .req_equals("bow", true)
+                .build(),
+            WorldState::new().add("trained", true).build(),
+            1.,
+        );
+        let tasks = ["equip_sword", "equip_bow", "train"].map(Task::primitive);
+        let goal = Goal::new(
+            "Train",
+            Requirements::new().req_equals("trained", true).build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("sword", false)
+            .add("bow", false)
+            .add("trained", false)
+            .build();
+
+        let plans = plan_batch(
+            &registry,
+            &[(world.clone(), tasks.to_vec(), goal.clone())],
+            Some(4),
+        );
+        let order = plans[0].as_ref().unwrap().execution_order();
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[2], "train");
+
+        registry.add_exclusion_group("weapon", ["equip_sword", "equip_bow"]);
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks.to_vec(), vec![goal.clone()]);
+        gen.prune_log = Some(Vec::new());
+        gen.generate_to_completion(&registry, &world, Some(4));
+        // training needs both weapons, which can't be equipped in one plan
+        assert!(!gen.plans.contains_key(&goal.name));
+        let log = gen.prune_log.unwrap();
+        assert!(log.contains(&("equip_bow".to_string(), PruneReason::Excluded)));
+        assert!(log.contains(&("equip_sword".to_string(), PruneReason::Excluded)));
+    }
+
+    #[test]
+    fn plan_graph_output() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            2.5,
+        );
+        let plan = Plan {
+            tasks: ["goto_b", "open_door"].map(Task::primitive).into(),
+            cost: 3.5,
+            ..Default::default()
+        };
+        let world = WorldState::new().add("door_open", false).build();
+
+        let dot = plan.to_dot(&registry, &world);
+        assert!(dot.starts_with("digraph plan {"));
+        assert!(dot.contains("\"open_door\"") && dot.contains("\"goto_b\""));
+        assert_eq!(dot.matches(" -> ").count(), 2);
+        assert!(dot.contains("start -> n0 [label=\"1\"]"));
+        assert!(dot.contains("n0 -> n1 [label=\"2.5\"]"));
+
+        let mermaid = plan.to_mermaid(&registry, &world);
+        assert_eq!(mermaid.matches("-->").count(), 2);
+        assert!(mermaid.contains("n0 -->|2.5| n1[\"goto_b\"]"));
+
+        // the door is already open, so the plan can't be followed and costs are unknown
+        let open = WorldState::new().add("door_open", true).build();
+        assert!(plan
+            .to_dot(&registry, &open)
+            .contains("n0 -> n1 [label=\"?\"]"));
+    }
+
+    #[test]
+    fn prefer_shorter_plans_at_equal_cost() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "step_one",
+            Requirements::new().req_equals("position", 0.).build(),
+            WorldState::new().add("position", 1.).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "step_two",
+            Requirements::new().req_equals("position", 1.).build(),
+            WorldState::new().add("position", 2.).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "dash",
+            Requirements::new().req_equals("position", 0.).build(),
+            WorldState::new().add("position", 2.).build(),
+            2.,
+        );
+        let tasks = ["step_one", "step_two", "dash"].map(Task::primitive);
+        let goal = Goal::new(
+            "Arrive",
+            Requirements::new().req_equals("position", 2.).build(),
+            1.0,
+        );
+        let world = WorldState::new().add("position", 0.).build();
+        let plan_length = |prefer_shorter| {
+            let mut gen = TimeSlicedTreeGen::new_initialized(tasks.to_vec(), vec![goal.clone()]);
+            gen.settings.prefer_shorter_plans = Some(prefer_shorter);
+            gen.generate_to_completion(&registry, &world, Some(4));
+            let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+            assert_eq!(plan.cost, 2.0);
+            plan.execution_order().len()
+        };
+
+        assert_eq!(plan_length(false), 2);
+        assert_eq!(plan_length(true), 1);
+    }
+
+    #[test]
+    fn cost_override_changes_plan() {
+        let mut registry = TaskRegistry::new();
+        for (name, cost) in [("walk", 3.), ("ride", 2.)] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals("arrived", false).build(),
+                WorldState::new().add("arrived", true).build(),
+                cost,
+            );
+        }
+        let tasks = vec![Task::primitive("walk"), Task::primitive("ride")];
+        let goal = Goal::new(
+            "Arrive",
+            Requirements::new().req_equals("arrived", true).build(),
+            1.0,
+        );
+        let world = WorldState::new().add("arrived", false).build();
+        let plan = |registry: &TaskRegistry| {
+            plan_batch(
+                registry,
+                &[(world.clone(), tasks.clone(), goal.clone())],
+                Some(4),
+            )
+            .remove(0)
+            .expect("Failed to find a plan")
+        };
+
+        assert_eq!(plan(&registry).execution_order(), vec!["ride"]);
+
+        // the horse went lame
+        registry.set_cost_override("ride", Some(10.));
+        let overridden = plan(&registry);
+        assert_eq!(overridden.execution_order(), vec!["walk"]);
+        assert_eq!(overridden.cost, 3.0);
+
+        registry.set_cost_override("ride", None);
+        assert_eq!(plan(&registry).execution_order(), vec!["ride"]);
+    }
+
+    #[test]
+    fn targeted_task_picks_cheapest_target() {
+        use bevy::prelude::*;
+        let (near, far) = (Entity::from_raw(1), Entity::from_raw(2));
+        let hidden = Entity::from_raw(3);
+        let mut registry = TaskRegistry::new();
+        registry.targeted_task::<TaskStub>(
+            "attack",
+            "enemies",
+            [far, near, hidden],
+            Requirements::new()
+                .req_equals("entity_{target}_visible", true)
+                .req_equals("entity_{target}_dead", false)
+                .build(),
+            WorldState::new()
+                .add("entity_{target}_dead", true)
+                .add("threat_cleared", true)
+                .build(),
+            |target| if target == near { 2. } else { 5. },
+        );
+        let goal = Goal::new(
+            "Clear Threat",
+            Requirements::new()
+                .req_equals("threat_cleared", true)
+                .build(),
+            1.0,
+        );
+        let mut world = WorldState::new();
+        for target in [near, far, hidden] {
+            world.insert(
+                UniqueName::from_string(format!("entity_{target}_visible")),
+                true,
+            );
+            world.insert(
+                UniqueName::from_string(format!("entity_{target}_dead")),
+                false,
+            );
+        }
+        world.insert("threat_cleared", false);
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(registry);
+        app.insert_resource(world);
+        app.insert_resource(HtnSettings::default());
+        app.add_systems(Update, system_update_time_sliced_tree_gen);
+        let mut blackboard = HtnBlackboard::new();
+        // the hidden target is registered but isn't a candidate, so only two variants are planned with
+        blackboard.set("enemies", vec![far, near]);
+        let entity = app
+            .world_mut()
+            .spawn((
+                HtnAgent::new(),
+                blackboard,
+                TimeSlicedTreeGen::new_initialized(
+                    vec![Task::primitive("attack")],
+                    vec![goal.clone()],
+                ),
+            ))
+            .id();
+        app.update();
+
+        let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        assert_eq!(gen.target_candidates["enemies"], vec![far, near]);
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        let order = plan.execution_order();
+        assert_eq!(order, vec![format!("attack_{near}")]);
+        assert_eq!(plan.cost, 2.0);
+        let registry = app.world().resource::<TaskRegistry>();
+        assert_eq!(registry.target_of(&order[0]), Some(near));
+
+        // once the near target is no longer a candidate, the other variant is planned instead
+        app.world_mut()
+            .get_mut::<HtnBlackboard>(entity)
+            .unwrap()
+            .set("enemies", vec![far]);
+        app.update();
+        let gen = app.world().get::<TimeSlicedTreeGen>(entity).unwrap();
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec![format!("attack_{far}")]);
+        assert_eq!(plan.cost, 5.0);
+    }
+
+    #[test]
+    fn partial_plan_for_unreachable_goal() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "walk_to_work",
+            Requirements::new().req_equals("at_work", false).build(),
+            WorldState::new().add("at_work", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "walk_home",
+            Requirements::new().req_equals("at_work", true).build(),
+            WorldState::new().add("at_work", false).build(),
+            1.,
+        );
+        // nothing hands out badges, so the goal can't be fully met
+        let goal = Goal::new(
+            "Start Shift",
+            Requirements::new()
+                .req_equals("at_work", true)
+                .req_equals("has_badge", true)
+                .build(),
+            1.0,
+        );
+        let tasks = vec![
+            Task::primitive("walk_to_work"),
+            Task::primitive("walk_home"),
+        ];
+        let world = WorldState::new()
+            .add("at_work", false)
+            .add("has_badge", false)
+            .build();
+        let search = |partial_plans| {
+            let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+            gen.settings.partial_plans = Some(partial_plans);
+            gen.generate_to_completion(&registry, &world, Some(4));
+            gen.plans.remove(&goal.name)
+        };
+
+        assert!(search(false).is_none());
+        let plan = search(true).expect("Failed to find a partial plan");
+        assert!(plan.partial);
+        assert_eq!(plan.execution_order(), vec!["walk_to_work"]);
+
+        // a reachable goal is never partial
+        let goal = Goal::new(
+            "Arrive",
+            Requirements::new().req_equals("at_work", true).build(),
+            1.0,
+        );
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+        gen.settings.partial_plans = Some(true);
+        gen.generate_to_completion(&registry, &world, Some(4));
+        assert!(!gen.plans[&goal.name].partial);
+    }
+
+    #[test]
+    fn batch_planning() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new().req_equals("door_open", true).build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        let tasks = vec![Task::primitive("open_door"), Task::primitive("goto_b")];
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let problems = [
+            (
+                WorldState::new()
+                    .add("room", "A")
+                    .add("door_open", false)
+                    .build(),
+                tasks.clone(),
+                goal.clone(),
+            ),
+            (
+                WorldState::new()
+                    .add("room", "A")
+                    .add("door_open", true)
+                    .build(),
+                tasks.clone(),
+                goal.clone(),
+            ),
+            // the door can't be opened without knowing it is closed
+            (WorldState::new().add("room", "A").build(), tasks, goal),
+        ];
+        let lengths: Vec<_> = plan_batch(&registry, &problems, Some(8))
+            .iter()
+            .map(|plan| plan.as_ref().map(|p| p.execution_order().len()))
+            .collect();
+        assert_eq!(lengths, vec![Some(2), Some(1), None]);
+    }
+
+    #[test]
+    fn capacity_limits_carried_items() {
+        let mut registry = TaskRegistry::new();
+        let items = ["axe", "bow", "rope"];
+        for item in items {
+            registry.task_templated::<TaskStub>(
+                "carry_{item}",
+                &[("item", item)],
+                Requirements::new()
+                    .req_equals("carrying_{item}", false)
+                    .req_at_least("capacity", 2.0)
+                    .build(),
+                WorldState::new()
+                    .add("carrying_{item}", true)
+                    .consume_amount("capacity", 2.0)
+                    .build(),
+                1.,
+            );
+        }
+        let tasks: Vec<_> = items
+            .iter()
+            .map(|item| Task::primitive(format!("carry_{item}")))
+            .collect();
+        let world_with_capacity = |capacity: f32| {
+            WorldState::new()
+                .add("carrying_axe", false)
+                .add("carrying_bow", false)
+                .add("carrying_rope", false)
+                .add("capacity", capacity)
+                .build()
+        };
+        let carry = |wanted: &[&'static str]| {
+            let mut requires = Requirements::new();
+            for item in wanted {
+                requires.req_equals(UniqueName::from_string(format!("carrying_{item}")), true);
+            }
+            Goal::new("Carry", requires.build(), 1.0)
+        };
+        let plan_len = |capacity: f32, wanted: &[&'static str]| {
+            plan_batch(
+                &registry,
+                &[(world_with_capacity(capacity), tasks.clone(), carry(wanted))],
+                Some(8),
+            )[0]
+            .as_ref()
+            .map(|plan| plan.execution_order().len())
+        };
+
+        assert_eq!(plan_len(5.0, &["axe", "bow"]), Some(2));
+        // a third item would need 6 capacity
+        assert_eq!(plan_len(5.0, &["axe", "bow", "rope"]), None);
+        assert_eq!(plan_len(6.0, &["axe", "bow", "rope"]), Some(3));
+    }
+
+    #[test]
+    fn max_cost_aggregation() {
+        let mut registry = TaskRegistry::new();
+        for (name, from, to, cost) in [
+            ("climb", "A", "ledge", 3.),
+            ("jump", "ledge", "B", 3.),
+            ("swim", "A", "B", 5.),
+        ] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals("room", from).build(),
+                WorldState::new().add("room", to).build(),
+                cost,
+            );
+        }
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let world = WorldState::new().add("room", "A").build();
+        let plan_with = |cost_aggregation| {
+            let mut gen = TimeSlicedTreeGen::new_initialized(
+                ["climb", "jump", "swim"].map(Task::primitive).to_vec(),
+                vec![goal.clone()],
+            );
+            gen.settings.cost_aggregation = cost_aggregation;
+            gen.generate_to_completion(&registry, &world, Some(8));
+            gen.plans
+                .get(&goal.name)
+                .expect("Failed to find a plan")
+                .clone()
+        };
+
+        let plan = plan_with(CostAgg::Sum);
+        assert_eq!(plan.execution_order(), vec!["swim"]);
+        assert_eq!(plan.cost, 5.0);
+        // the two step route never has a step as expensive as swimming
+        let plan = plan_with(CostAgg::Max);
+        assert_eq!(plan.execution_order(), vec!["climb", "jump"]);
+        assert_eq!(plan.cost, 3.0);
+    }
+
+    #[test]
+    fn goal_distance_penalty_not_absorbed_by_max() {
+        let mut registry = TaskRegistry::new();
+        for (name, from, to, cost) in [("climb", "A", "ledge", 1.), ("jump", "ledge", "B", 5.)] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals("room", from).build(),
+                WorldState::new().add("room", to).build(),
+                cost,
+            );
+        }
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            ["climb", "jump"].map(Task::primitive).to_vec(),
+            vec![goal.clone()],
+        );
+        gen.settings.cost_aggregation = CostAgg::Max;
+        gen.settings.goal_distance_weight = Some(1.0);
+        gen.generate_to_completion(
+            &registry,
+            &WorldState::new().add("room", "A").build(),
+            Some(8),
+        );
+
+        // standing on the ledge is one requirement short of the goal, which is added on top of the most expensive step
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec!["climb", "jump"]);
+        assert_eq!(plan.cost, 6.0);
+    }
+
+    #[test]
+    fn forbidden_states_avoided() {
+        let mut registry = TaskRegistry::new();
+        for (name, from, to, on_fire) in [
+            ("enter_fire", "A", "fire", true),
+            ("leave_fire", "fire", "B", false),
+            ("enter_hall", "A", "hall", false),
+            ("cross_hall", "hall", "stairs", false),
+            ("leave_stairs", "stairs", "B", false),
+        ] {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new().req_equals("room", from).build(),
+                WorldState::new()
+                    .add("room", to)
+                    .add("on_fire", on_fire)
+                    .build(),
+                1.,
+            );
+        }
+        let goal = Goal::new(
+            "Be in room B",
+            Requirements::new().req_equals("room", "B").build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("on_fire", false)
+            .build();
+        let tasks: Vec<Task> = [
+            "enter_fire",
+            "leave_fire",
+            "enter_hall",
+            "cross_hall",
+            "leave_stairs",
+        ]
+        .into_iter()
+        .map(Task::primitive)
+        .collect();
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks.clone(), vec![goal.clone()]);
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+        assert_eq!(plan.execution_order(), vec!["enter_fire", "leave_fire"]);
+
+        let mut gen = TimeSlicedTreeGen::new_initialized(tasks, vec![goal.clone()]);
+        gen.settings.forbidden_states =
+            vec![Requirements::new().req_equals("on_fire", true).build()];
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen
+            .plans
+            .get(&goal.name)
+            .expect("Failed to find a safe plan");
+        assert_eq!(
+            plan.execution_order(),
+            vec!["enter_hall", "cross_hall", "leave_stairs"]
+        );
+    }
+
+    #[test]
+    fn partial_order_plan() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "gather_wood",
+            Requirements::new().req_equals("has_wood", false).build(),
+            WorldState::new().add("has_wood", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "gather_stone",
+            Requirements::new().req_equals("has_stone", false).build(),
+            WorldState::new().add("has_stone", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "build",
+            Requirements::new()
+                .req_equals("has_wood", true)
+                .req_equals("has_stone", true)
+                .build(),
+            WorldState::new().add("house_built", true).build(),
+            1.,
+        );
+        let goal = Goal::new(
+            "Build a house",
+            Requirements::new().req_equals("house_built", true).build(),
+            1.0,
+        );
+        let world = WorldState::new()
+            .add("has_wood", false)
+            .add("has_stone", false)
+            .build();
+        let mut gen = TimeSlicedTreeGen::new_initialized(
+            vec![
+                Task::primitive("gather_wood"),
+                Task::primitive("gather_stone"),
+                Task::primitive("build"),
+            ],
+            vec![goal.clone()],
+        );
+        gen.generate_to_completion(&registry, &world, Some(8));
+        let plan = gen.plans.get(&goal.name).expect("Failed to find a plan");
+
+        let partial = plan.partial_order(&registry);
+        assert_eq!(partial.steps.len(), 3);
+        let index = |name: &str| partial.steps.iter().position(|s| s == name).unwrap();
+        let (wood, stone, build) = (index("gather_wood"), index("gather_stone"), index("build"));
+        assert!(partial.is_unordered(wood, stone));
+        assert!(partial.is_ordered(wood, build));
+        assert!(partial.is_ordered(stone, build));
+
+        let mut ready = partial.ready(&[]);
+        ready.sort();
+        let mut gathers = vec![wood, stone];
+        gathers.sort();
+        assert_eq!(ready, gathers);
+        assert_eq!(partial.ready(&[wood]), vec![stone]);
+        assert_eq!(partial.ready(&[wood, stone]), vec![build]);
+    }
 }
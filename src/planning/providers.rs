@@ -1,3 +1,8 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use bevy::{app::App, prelude::Component};
 
 use crate::tasks::Task;
@@ -10,45 +15,122 @@ pub(crate) fn plugin(app: &mut App) {
     app.register_component_as::<dyn GoalProvider, StaticGoalProvider>();
 }
 
+static NEXT_PROVIDER_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// A version number that has never been handed out before. Useful for implementing `version` on providers
+pub fn next_provider_version() -> u64 {
+    NEXT_PROVIDER_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
 #[bevy_trait_query::queryable]
 /// Implement this trait on a component to allow it to provide a set of tasks to an HTN agent
 pub trait TaskProvider {
     fn tasks(&self) -> Vec<Task>;
+    /// The tasks to give an agent with the given [`HtnAgentRole`], or no role. Defaults to [`TaskProvider::tasks`] whatever the role
+    fn tasks_for_role(&self, role: Option<&str>) -> Vec<Task> {
+        let _ = role;
+        self.tasks()
+    }
+    /// Changes whenever the provided tasks change. While every provider on an agent reports the same version as last frame, collection is skipped.
+    /// Defaults to a new version on every call, meaning tasks are always collected
+    fn version(&self) -> u64 {
+        next_provider_version()
+    }
+    /// Where this provider's tasks go among an agent's collected tasks. Tasks are sorted by order, lowest first, then by name,
+    /// so the order a provider lists its tasks in is not kept, even for an agent with a single provider
+    fn order(&self) -> i32 {
+        0
+    }
 }
 
 #[bevy_trait_query::queryable]
 /// Implement this trait on a component to allow it to provide a set of goals to an HTN agent
 pub trait GoalProvider {
     fn goals(&self) -> Vec<Goal>;
+    /// See [`TaskProvider::tasks_for_role`]
+    fn goals_for_role(&self, role: Option<&str>) -> Vec<Goal> {
+        let _ = role;
+        self.goals()
+    }
+    /// See [`TaskProvider::version`]
+    fn version(&self) -> u64 {
+        next_provider_version()
+    }
 }
 
+/// The role an agent plays, such as "guard" or "patroller", which selects the tasks and goals its providers give it.
+/// Changing the role makes the agent collect its tasks and goals again
+#[derive(Component, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HtnAgentRole(pub String);
+
+/// Provides a fixed set of tasks, plus any tasks registered for the agent's role. Replacing the component gives it a new version, so the agent picks up the new tasks.
+/// Like any provider's, the tasks are collected sorted by name rather than in the order given (see [`TaskProvider::order`])
 #[derive(Component)]
-pub struct StaticTaskProvider(Vec<Task>);
+pub struct StaticTaskProvider(Vec<Task>, u64, HashMap<String, Vec<Task>>);
 
+/// Provides a fixed set of goals, plus any goals registered for the agent's role. Replacing the component gives it a new version, so the agent picks up the new goals
 #[derive(Component)]
-pub struct StaticGoalProvider(Vec<Goal>);
+pub struct StaticGoalProvider(Vec<Goal>, u64, HashMap<String, Vec<Goal>>);
 
 impl TaskProvider for StaticTaskProvider {
     fn tasks(&self) -> Vec<Task> {
         self.0.clone()
     }
+
+    fn tasks_for_role(&self, role: Option<&str>) -> Vec<Task> {
+        let role_tasks = role.and_then(|role| self.2.get(role));
+        self.0
+            .iter()
+            .chain(role_tasks.into_iter().flatten())
+            .cloned()
+            .collect()
+    }
+
+    fn version(&self) -> u64 {
+        self.1
+    }
 }
 
 impl GoalProvider for StaticGoalProvider {
     fn goals(&self) -> Vec<Goal> {
         self.0.clone()
     }
+
+    fn goals_for_role(&self, role: Option<&str>) -> Vec<Goal> {
+        let role_goals = role.and_then(|role| self.2.get(role));
+        self.0
+            .iter()
+            .chain(role_goals.into_iter().flatten())
+            .cloned()
+            .collect()
+    }
+
+    fn version(&self) -> u64 {
+        self.1
+    }
 }
 
 impl StaticTaskProvider {
     pub fn new(tasks: Vec<Task>) -> Self {
-        Self(tasks)
+        Self(tasks, next_provider_version(), HashMap::new())
+    }
+
+    /// Adds tasks only given to agents with the matching [`HtnAgentRole`]
+    pub fn with_role(mut self, role: impl Into<String>, tasks: Vec<Task>) -> Self {
+        self.2.entry(role.into()).or_default().extend(tasks);
+        self
     }
 }
 
 impl StaticGoalProvider {
     pub fn new(goals: Vec<Goal>) -> Self {
-        Self(goals)
+        Self(goals, next_provider_version(), HashMap::new())
+    }
+
+    /// Adds goals only given to agents with the matching [`HtnAgentRole`]
+    pub fn with_role(mut self, role: impl Into<String>, goals: Vec<Goal>) -> Self {
+        self.2.entry(role.into()).or_default().extend(goals);
+        self
     }
 }
 
@@ -120,6 +202,150 @@ mod test {
         assert_eq!(data.goals[2].name, "C");
     }
 
+    #[test]
+    fn role_selects_provided_tasks() {
+        use crate::planning::system_collect_agent_tasks_from_static_provider;
+
+        let provider = || {
+            StaticTaskProvider::new(vec![Task::primitive("eat")])
+                .with_role("guard", vec![Task::primitive("stand_watch")])
+                .with_role("patroller", vec![Task::primitive("walk_route")])
+        };
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        super::plugin(&mut app);
+        app.add_systems(
+            Update,
+            (
+                system_collect_agent_tasks_from_providers,
+                system_collect_agent_tasks_from_static_provider,
+            )
+                .chain(),
+        );
+        let agent = app
+            .world_mut()
+            .spawn((
+                HtnAgent::default(),
+                provider(),
+                HtnAgentRole("guard".into()),
+            ))
+            .id();
+        let roleless = app
+            .world_mut()
+            .spawn((HtnAgent::default(), provider()))
+            .id();
+        let tasks = |app: &App, entity: Entity| {
+            app.world()
+                .get::<HtnAgent>(entity)
+                .unwrap()
+                .available_tasks
+                .clone()
+        };
+
+        let unsorted = app
+            .world_mut()
+            .spawn((
+                HtnAgent::default(),
+                StaticTaskProvider::new(vec![Task::primitive("walk"), Task::primitive("eat")]),
+            ))
+            .id();
+        app.update();
+        assert_eq!(
+            tasks(&app, agent),
+            vec![Task::primitive("eat"), Task::primitive("stand_watch")]
+        );
+        assert_eq!(tasks(&app, roleless), vec![Task::primitive("eat")]);
+        // the fast path sorts by name, the same as every other provider
+        assert_eq!(
+            tasks(&app, unsorted),
+            vec![Task::primitive("eat"), Task::primitive("walk")]
+        );
+
+        app.world_mut()
+            .entity_mut(agent)
+            .insert(HtnAgentRole("patroller".into()));
+        app.update();
+        assert_eq!(
+            tasks(&app, agent),
+            vec![Task::primitive("eat"), Task::primitive("walk_route")]
+        );
+    }
+
+    #[test]
+    fn provider_order_sorts_collected_tasks() {
+        use bevy_trait_query::RegisterExt;
+
+        #[derive(Component)]
+        struct LateProvider;
+        impl TaskProvider for LateProvider {
+            fn tasks(&self) -> Vec<Task> {
+                vec![Task::primitive("b_late"), Task::primitive("a_late")]
+            }
+            fn order(&self) -> i32 {
+                10
+            }
+        }
+
+        #[derive(Component)]
+        struct EarlyProvider;
+        impl TaskProvider for EarlyProvider {
+            fn tasks(&self) -> Vec<Task> {
+                vec![Task::primitive("z_early"), Task::primitive("y_early")]
+            }
+            fn order(&self) -> i32 {
+                -1
+            }
+        }
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        super::plugin(&mut app);
+        app.add_systems(Update, system_collect_agent_tasks_from_providers);
+        app.register_component_as::<dyn TaskProvider, LateProvider>();
+        app.register_component_as::<dyn TaskProvider, EarlyProvider>();
+        let agents = [
+            app.world_mut()
+                .spawn((HtnAgent::default(), LateProvider, EarlyProvider))
+                .id(),
+            app.world_mut()
+                .spawn((
+                    HtnAgent::default(),
+                    EarlyProvider,
+                    StaticTaskProvider::new(vec![Task::primitive("m_static")]),
+                    LateProvider,
+                ))
+                .id(),
+        ];
+        app.update();
+
+        let tasks = |entity: Entity| {
+            app.world()
+                .get::<HtnAgent>(entity)
+                .unwrap()
+                .available_tasks
+                .clone()
+        };
+        assert_eq!(
+            tasks(agents[0]),
+            vec![
+                Task::primitive("y_early"),
+                Task::primitive("z_early"),
+                Task::primitive("a_late"),
+                Task::primitive("b_late"),
+            ]
+        );
+        assert_eq!(
+            tasks(agents[1]),
+            vec![
+                Task::primitive("y_early"),
+                Task::primitive("z_early"),
+                Task::primitive("m_static"),
+                Task::primitive("a_late"),
+                Task::primitive("b_late"),
+            ]
+        );
+    }
+
     #[test]
     fn test_custom_provider_tasks() {
         use bevy_trait_query::RegisterExt;
@@ -177,4 +403,113 @@ mod test {
             vec![Goal::new("something", Requirements::new(), 1.0)]
         }
     }
+
+    #[test]
+    fn static_fast_path_matches_trait_query() {
+        use crate::planning::{
+            system_collect_agent_goals_from_static_provider,
+            system_collect_agent_tasks_from_static_provider,
+        };
+
+        fn spawn_agents(app: &mut App) -> Vec<Entity> {
+            (0..50)
+                .map(|i| {
+                    let tasks = (0..i % 7)
+                        .map(|t| Task::primitive(format!("task_{t}")))
+                        .collect();
+                    let goals = (0..i % 3)
+                        .map(|g| Goal::new(format!("goal_{g}"), Requirements::new(), 1.0))
+                        .collect();
+                    app.world_mut()
+                        .spawn((
+                            HtnAgent::default(),
+                            StaticTaskProvider::new(tasks),
+                            StaticGoalProvider::new(goals),
+                        ))
+                        .id()
+                })
+                .collect()
+        }
+
+        let mut trait_app = App::new();
+        trait_app.add_plugins(MinimalPlugins);
+        super::plugin(&mut trait_app);
+        trait_app.add_systems(
+            Update,
+            (
+                system_collect_agent_tasks_from_providers,
+                system_collect_agent_goals_from_providers,
+            ),
+        );
+        let trait_agents = spawn_agents(&mut trait_app);
+        trait_app.update();
+
+        let mut fast_app = App::new();
+        fast_app.add_plugins(MinimalPlugins);
+        fast_app.add_systems(
+            Update,
+            (
+                system_collect_agent_tasks_from_static_provider,
+                system_collect_agent_goals_from_static_provider,
+            ),
+        );
+        let fast_agents = spawn_agents(&mut fast_app);
+        fast_app.update();
+
+        for (a, b) in trait_agents.into_iter().zip(fast_agents) {
+            let a = trait_app.world().get::<HtnAgent>(a).unwrap();
+            let b = fast_app.world().get::<HtnAgent>(b).unwrap();
+            assert_eq!(a.available_tasks, b.available_tasks);
+            assert_eq!(a.goals.len(), b.goals.len());
+        }
+    }
+
+    #[test]
+    fn stable_version_skips_collection() {
+        use bevy_trait_query::RegisterExt;
+        use std::sync::atomic::AtomicUsize;
+
+        static VERSIONED_CALLS: AtomicUsize = AtomicUsize::new(0);
+        static UNVERSIONED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Component)]
+        struct VersionedProvider;
+        impl TaskProvider for VersionedProvider {
+            fn tasks(&self) -> Vec<Task> {
+                VERSIONED_CALLS.fetch_add(1, Ordering::Relaxed);
+                vec![Task::primitive("versioned")]
+            }
+            fn version(&self) -> u64 {
+                7
+            }
+        }
+
+        #[derive(Component)]
+        struct UnversionedProvider;
+        impl TaskProvider for UnversionedProvider {
+            fn tasks(&self) -> Vec<Task> {
+                UNVERSIONED_CALLS.fetch_add(1, Ordering::Relaxed);
+                vec![Task::primitive("unversioned")]
+            }
+        }
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.add_systems(Update, system_collect_agent_tasks_from_providers);
+        app.register_component_as::<dyn TaskProvider, VersionedProvider>();
+        app.register_component_as::<dyn TaskProvider, UnversionedProvider>();
+        let versioned = app
+            .world_mut()
+            .spawn((HtnAgent::default(), VersionedProvider))
+            .id();
+        app.world_mut()
+            .spawn((HtnAgent::default(), UnversionedProvider));
+        for _ in 0..3 {
+            app.update();
+        }
+        assert_eq!(VERSIONED_CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(UNVERSIONED_CALLS.load(Ordering::Relaxed), 3);
+        let data = app.world().get::<HtnAgent>(versioned).unwrap();
+        assert_eq!(data.available_tasks, vec![Task::primitive("versioned")]);
+    }
 }
@@ -1,6 +1,10 @@
-use crate::data::{Requirements, WorldState};
-use bevy::{ecs::system::EntityCommands, prelude::*, utils::HashMap};
-use std::{fmt::Debug, marker::PhantomData, sync::Arc};
+use crate::data::{Requirements, UniqueName, WorldState};
+use bevy::{
+    ecs::system::EntityCommands,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use std::{fmt::Debug, marker::PhantomData, sync::Arc, time::Duration};
 
 pub(crate) fn plugin(app: &mut App) {
     app.insert_resource(TaskRegistry::default());
@@ -9,16 +13,121 @@ pub(crate) fn plugin(app: &mut App) {
 pub trait TaskData: Sync + Send {
     fn preconditions(&self) -> &Requirements;
     fn postconditions(&self) -> &WorldState;
+    /// The effect of this task when applied to the given world. Override this for tasks whose outcome depends on the world they are applied to
+    fn postconditions_for(&self, _world: &WorldState) -> WorldState {
+        self.postconditions().clone()
+    }
     fn add(&self, entity: &mut EntityCommands);
     fn remove(&self, entity: &mut EntityCommands);
     fn cost(&self, world: &WorldState) -> f32;
+    /// How long the task is expected to take when run in the given world. Unlike cost, this is reported on plans rather than minimized, and only breaks ties between equal-cost plans
+    fn expected_duration(&self, _world: &WorldState) -> Duration {
+        Duration::ZERO
+    }
 }
 
 /// We store tasks in an atomic ref-counted box. This means they are thread-safe dynamic allocations that are explicitly read-only.
 pub type TaskStorage = Arc<Box<dyn TaskData>>;
 
+/// How a running task is reported once its abort condition is met, see [`TaskRegistry::set_abort_condition`]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbortOutcome {
+    /// the task failed, abandoning the plan
+    #[default]
+    Failure,
+    /// the task is no longer needed, so the plan carries on as if it had finished
+    Success,
+}
+
+/// Why [`TaskRegistry::get_task_result`] couldn't find a task
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskLookupError {
+    /// No task is registered under this name
+    NotFound(String),
+    /// The task is a macro, which is never registered itself. Look up its subtasks instead
+    NotPrimitive,
+}
+
+impl std::fmt::Display for TaskLookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskLookupError::NotFound(name) => write!(f, "No task is registered as '{}'", name),
+            TaskLookupError::NotPrimitive => {
+                write!(f, "Macro tasks are not registered, only primitives are")
+            }
+        }
+    }
+}
+
+/// A problem with a task definition found by [`TaskRegistry::audit`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskAuditWarning {
+    /// The task requires keys that no task produces and the initial world doesn't have, so it can never run
+    MissingKeys { task: String, keys: Vec<UniqueName> },
+    /// Every key the task requires is produced somewhere, but only by tasks that can never run themselves
+    Unreachable { task: String, keys: Vec<UniqueName> },
+}
+
+impl std::fmt::Display for TaskAuditWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (task, keys, reason) = match self {
+            TaskAuditWarning::MissingKeys { task, keys } => {
+                (task, keys, "which are never produced")
+            }
+            TaskAuditWarning::Unreachable { task, keys } => {
+                (task, keys, "which are only produced by unreachable tasks")
+            }
+        };
+        let keys: Vec<_> = keys.iter().map(UniqueName::as_str).collect();
+        write!(f, "Task '{}' requires {:?}, {}", task, keys, reason)
+    }
+}
+
+fn audited_task(warning: &TaskAuditWarning) -> &String {
+    match warning {
+        TaskAuditWarning::MissingKeys { task, .. } | TaskAuditWarning::Unreachable { task, .. } => {
+            task
+        }
+    }
+}
+
+/// The candidate targets of targeted tasks, by blackboard key. See [`TaskRegistry::targeted_task`]
+pub type TargetCandidates = std::collections::HashMap<String, Vec<Entity>>;
+
+/// A task registered with [`TaskRegistry::targeted_task`], which agents plan with once per candidate target
+#[derive(Clone, Debug, Default)]
+pub struct TargetedTask {
+    /// the [`HtnBlackboard`](crate::execution::HtnBlackboard) key holding the agent's candidate targets as a `Vec<Entity>`
+    pub candidates_key: String,
+    /// the registered instance for each target
+    pub instances: HashMap<Entity, Task>,
+}
+
+/// Every task agents can plan with, along with per-task metadata such as cooldowns and durations.
 #[derive(Resource, Default)]
-pub struct TaskRegistry(pub HashMap<String, TaskStorage>);
+pub struct TaskRegistry {
+    pub tasks: HashMap<String, TaskStorage>,
+    /// how long a task is unavailable for after an agent has executed it
+    pub cooldowns: HashMap<String, Duration>,
+    /// how long a task takes to run. Timed tasks succeed automatically once their duration has elapsed
+    pub durations: HashMap<String, Duration>,
+    /// how likely a task is to succeed when run. Planning divides a task's cost by this, biasing plans toward reliable tasks
+    pub success_probs: HashMap<String, f32>,
+    /// descriptive labels for tasks, such as "stealthy". Agents can prefer tasks with certain tags through [`HtnAgent::tag_preferences`](crate::planning::HtnAgent::tag_preferences)
+    pub tags: HashMap<String, HashSet<String>>,
+    /// conditions under which a running task is ended early, checked every frame by [`system_abort_tasks`](crate::execution::system_abort_tasks)
+    pub abort_conditions: HashMap<String, (Requirements, AbortOutcome)>,
+    /// groups of mutually exclusive tasks, such as equipping one of several weapons. Once a task from a group is in a plan, no other task from the group is planned after it
+    pub exclusion_groups: HashMap<String, HashSet<String>>,
+    /// costs used in place of what a task reports, for runtime tweaks such as buffs or difficulty. See [`TaskRegistry::set_cost_override`]
+    pub cost_overrides: HashMap<String, f32>,
+    /// the tasks registered with [`TaskRegistry::targeted_task`]
+    pub targeted: HashMap<String, TargetedTask>,
+    /// the entity each targeted task instance acts on
+    pub targets: HashMap<String, Entity>,
+    /// weighted effects for tasks which can end in one of several ways. See [`TaskRegistry::set_outcomes`]
+    pub outcomes: HashMap<String, Vec<(f32, WorldState)>>,
+}
 
 impl TaskRegistry {
     pub fn new() -> Self {
@@ -28,13 +137,24 @@ impl TaskRegistry {
         let Task::Primitive(name) = task else {
             return None;
         };
-        if let Some(task) = self.0.get(name) {
+        if let Some(task) = self.tasks.get(name) {
             return Some(task);
         }
         None
     }
+
+    /// Like [`TaskRegistry::get_task`], but reports why the lookup failed
+    pub fn get_task_result(&self, task: &Task) -> Result<&TaskStorage, TaskLookupError> {
+        let Task::Primitive(name) = task else {
+            return Err(TaskLookupError::NotPrimitive);
+        };
+        self.tasks
+            .get(name)
+            .ok_or_else(|| TaskLookupError::NotFound(name.clone()))
+    }
+
     pub fn get_named(&self, task: &String) -> Option<&TaskStorage> {
-        self.0.get(task)
+        self.tasks.get(task)
     }
 
     pub fn task<C, S>(&mut self, name: S, precon: Requirements, postcon: WorldState, cost: f32)
@@ -43,7 +163,179 @@ impl TaskRegistry {
         C: Component + Default,
     {
         let comp = SimpleTaskData::<C>::new(precon, postcon, cost);
-        self.0.insert(name.into(), Arc::new(Box::new(comp)));
+        self.tasks.insert(name.into(), Arc::new(Box::new(comp)));
+    }
+
+    /// Registers a task which automatically succeeds once `duration` has passed since it started, without needing a system of its own
+    pub fn timed_task<C, S>(
+        &mut self,
+        name: S,
+        precon: Requirements,
+        postcon: WorldState,
+        cost: f32,
+        duration: Duration,
+    ) where
+        S: Into<String>,
+        C: Component + Default,
+    {
+        let name = name.into();
+        self.set_duration(name.clone(), duration);
+        self.task::<C, _>(name, precon, postcon, cost);
+    }
+
+    /// Registers a task which only succeeds some of the time. When planning, its cost is treated as the expected cost `cost / success_prob`
+    pub fn probabilistic_task<C, S>(
+        &mut self,
+        name: S,
+        precon: Requirements,
+        postcon: WorldState,
+        cost: f32,
+        success_prob: f32,
+    ) where
+        S: Into<String>,
+        C: Component + Default,
+    {
+        let name = name.into();
+        self.set_success_prob(name.clone(), success_prob);
+        self.task::<C, _>(name, precon, postcon, cost);
+    }
+
+    /// Registers a task whose effect is one of several weighted `outcomes`. The planner assumes the highest-weight outcome happens,
+    /// so that outcome is registered as the task's postconditions. Without any outcome of positive weight the task is registered with no effects
+    pub fn outcome_task<C, S>(
+        &mut self,
+        name: S,
+        precon: Requirements,
+        outcomes: Vec<(f32, WorldState)>,
+        cost: f32,
+    ) where
+        S: Into<String>,
+        C: Component + Default,
+    {
+        let name = name.into();
+        self.set_outcomes(name.clone(), outcomes);
+        let postcon = self.most_likely_outcome(&name).cloned().unwrap_or_default();
+        self.task::<C, _>(name, precon, postcon, cost);
+    }
+
+    /// Registers a task from templates, replacing each `{param}` token in the name and world keys with its value from `params`.
+    /// This allows one task definition to be instantiated for many items, e.g. `pickup_{item}` setting `carrying_{item}`. Returns the resolved task name
+    pub fn task_templated<C>(
+        &mut self,
+        name_template: &str,
+        params: &[(&str, &str)],
+        precon_template: Requirements,
+        postcon_template: WorldState,
+        cost: f32,
+    ) -> String
+    where
+        C: Component + Default,
+    {
+        let resolve_key = |key: &UniqueName| {
+            let resolved = fill_template(key.as_str(), params);
+            if resolved == key.as_str() {
+                key.clone()
+            } else {
+                UniqueName::from_string(resolved)
+            }
+        };
+        let name = fill_template(name_template, params);
+        self.task::<C, _>(
+            name.clone(),
+            precon_template.map_keys(resolve_key),
+            postcon_template.map_keys(resolve_key),
+            cost,
+        );
+        name
+    }
+
+    /// Registers one instance of a task for each target entity, named `{name}_{target}`, replacing `{target}` in the world keys with the entity (e.g. `entity_{target}_dead`).
+    /// Agents are given the task by its plain name. At planning time the candidates are read from the agent's [`HtnBlackboard`](crate::execution::HtnBlackboard) under `candidates_key`
+    /// as a `Vec<Entity>`, and the planner branches on every registered candidate whose preconditions hold so the cheapest target wins.
+    /// Registering more targets for the same name adds to the existing instances. Instances stay registered until removed with [`TaskRegistry::remove_target`],
+    /// so remove targets once they are despawned
+    pub fn targeted_task<C>(
+        &mut self,
+        name: impl Into<String>,
+        candidates_key: impl Into<String>,
+        targets: impl IntoIterator<Item = Entity>,
+        precon_template: Requirements,
+        postcon_template: WorldState,
+        cost: impl Fn(Entity) -> f32,
+    ) where
+        C: Component + Default,
+    {
+        let name = name.into();
+        let candidates_key = candidates_key.into();
+        for target in targets {
+            let instance = self.task_templated::<C>(
+                &format!("{name}_{{target}}"),
+                &[("target", &target.to_string())],
+                precon_template.clone(),
+                postcon_template.clone(),
+                cost(target),
+            );
+            self.targets.insert(instance.clone(), target);
+            let targeted = self.targeted.entry(name.clone()).or_default();
+            targeted.candidates_key.clone_from(&candidates_key);
+            targeted.instances.insert(target, Task::primitive(instance));
+        }
+    }
+
+    /// Unregisters the instance of a targeted task for the given target, along with anything set for the instance such as a cooldown.
+    /// The instance's interned world keys stay interned. Returns true if there was such an instance
+    pub fn remove_target(&mut self, name: &str, target: Entity) -> bool {
+        let Some(Task::Primitive(instance)) = self
+            .targeted
+            .get_mut(name)
+            .and_then(|targeted| targeted.instances.remove(&target))
+        else {
+            return false;
+        };
+        self.tasks.remove(&instance);
+        self.targets.remove(&instance);
+        self.cooldowns.remove(&instance);
+        self.durations.remove(&instance);
+        self.success_probs.remove(&instance);
+        self.tags.remove(&instance);
+        self.abort_conditions.remove(&instance);
+        self.cost_overrides.remove(&instance);
+        self.outcomes.remove(&instance);
+        for group in self.exclusion_groups.values_mut() {
+            group.remove(&instance);
+        }
+        true
+    }
+
+    /// The entity a task instance registered with [`TaskRegistry::targeted_task`] acts on, such as the one to attack
+    pub fn target_of(&self, name: &String) -> Option<Entity> {
+        self.targets.get(name).copied()
+    }
+
+    /// The instances of a targeted task for each of its candidates, keyed by blackboard key, or just the task itself for any other task.
+    /// Candidates without a registered instance are skipped
+    pub fn expand_targeted<'a>(
+        &'a self,
+        task: &'a Task,
+        candidates: &'a TargetCandidates,
+    ) -> impl Iterator<Item = &'a Task> + 'a {
+        let targeted = match task {
+            Task::Primitive(name) => self.targeted.get(name),
+            Task::Macro(..) => None,
+        };
+        let instances = targeted.map(|targeted| {
+            candidates
+                .get(&targeted.candidates_key)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|target| targeted.instances.get(target))
+        });
+        targeted
+            .is_none()
+            .then_some(task)
+            .into_iter()
+            .chain(instances.into_iter().flatten())
     }
 
     /// utility to more easily get both pre and post conditions for situations where both are needed
@@ -65,16 +357,13 @@ impl TaskRegistry {
                 None
             }
             Task::Macro(tasks, _) => {
+                task.try_decompose()?;
                 let mut req = Requirements::new();
                 for t in tasks
                     .iter()
                     .map(|t| t.decompose())
                     .rev()
-                    .reduce(|agg, item| {
-                        agg.into_iter()
-                            .chain(item.into_iter())
-                            .collect::<Vec<String>>()
-                    })
+                    .reduce(|agg, item| agg.into_iter().chain(item).collect::<Vec<String>>())
                     .unwrap_or_default()
                 {
                     let data = self.get_named(&t)?;
@@ -86,6 +375,22 @@ impl TaskRegistry {
         }
     }
 
+    /// The given tasks whose preconditions are met in `world`, in the order given. Unregistered tasks are left out, and targeted tasks are expanded into their instances for the given candidates.
+    /// Unlike the planner, this ignores cooldowns as they belong to agents
+    pub fn applicable_tasks(
+        &self,
+        tasks: &[Task],
+        world: &WorldState,
+        candidates: &TargetCandidates,
+    ) -> Vec<Task> {
+        tasks
+            .iter()
+            .flat_map(|task| self.expand_targeted(task, candidates))
+            .filter(|task| self.precon(task).is_some_and(|req| req.validate(world)))
+            .cloned()
+            .collect()
+    }
+
     pub fn postcon(&self, task: &Task) -> Option<WorldState> {
         match task {
             Task::Primitive(name) => {
@@ -95,16 +400,13 @@ impl TaskRegistry {
                 None
             }
             Task::Macro(tasks, _) => {
+                task.try_decompose()?;
                 let mut context = WorldState::new();
                 for t in tasks
                     .iter()
                     .map(|t| t.decompose())
                     .rev()
-                    .reduce(|agg, item| {
-                        agg.into_iter()
-                            .chain(item.into_iter())
-                            .collect::<Vec<String>>()
-                    })
+                    .reduce(|agg, item| agg.into_iter().chain(item).collect::<Vec<String>>())
                     .unwrap_or_default()
                 {
                     let data = self.get_named(&t)?;
@@ -116,12 +418,330 @@ impl TaskRegistry {
         }
     }
 
+    /// Two tasks are independent when neither one's effects touch the keys the other reads or writes, meaning they can be run in any order or at the same time.
+    /// Unregistered tasks are never independent
+    pub fn are_independent(&self, a: &String, b: &String) -> bool {
+        let (Some(a), Some(b)) = (self.get_named(a), self.get_named(b)) else {
+            return false;
+        };
+        let touches = |effects: &WorldState, data: &TaskStorage| {
+            effects.keys().any(|key| {
+                data.preconditions().keys().any(|k| k == key)
+                    || data.postconditions().keys().any(|k| k == key)
+            })
+        };
+        !touches(a.postconditions(), b) && !touches(b.postconditions(), a)
+    }
+
+    /// Applies the task (or each subtask of a macro, in order) to the world. Returns the resulting world and the total cost of the task.
+    /// Returns `None` if any task is not registered or a subtask's preconditions are not met at the point it would run
+    pub fn simulate(&self, task: &Task, world: &WorldState) -> Option<(WorldState, f32)> {
+        let mut world = world.clone();
+        let mut cost = 0.0;
+        for name in task.try_decompose()? {
+            let data = self.get_named(&name)?;
+            if !data.preconditions().validate(&world) {
+                return None;
+            }
+            cost += self.task_cost(&name, &world)?;
+            world = world.concat(&data.postconditions_for(&world));
+        }
+        Some((world, cost))
+    }
+
     pub fn custom_task<S>(&mut self, name: S, data: Box<dyn TaskData>)
     where
         S: Into<String>,
     {
-        self.0.insert(name.into(), Arc::new(data));
+        self.tasks.insert(name.into(), Arc::new(data));
+    }
+
+    /// Replaces the cost the named task reports while planning, or restores it when given `None`. Agents only pick this up when they next replan
+    pub fn set_cost_override(&mut self, name: impl Into<String>, cost: Option<f32>) {
+        let name = name.into();
+        match cost {
+            Some(cost) => self.cost_overrides.insert(name, cost),
+            None => self.cost_overrides.remove(&name),
+        };
+    }
+
+    /// The cost of the named task in the given world, respecting any override
+    pub fn task_cost(&self, name: &String, world: &WorldState) -> Option<f32> {
+        if let Some(cost) = self.cost_overrides.get(name) {
+            return Some(*cost);
+        }
+        self.get_named(name).map(|data| data.cost(world))
+    }
+
+    /// Ends the named task early whenever `condition` is met while it runs, such as a "flee" task once `safe` is true
+    pub fn set_abort_condition(
+        &mut self,
+        name: impl Into<String>,
+        condition: Requirements,
+        outcome: AbortOutcome,
+    ) {
+        self.abort_conditions
+            .insert(name.into(), (condition, outcome));
+    }
+
+    pub fn abort_condition(&self, name: &String) -> Option<&(Requirements, AbortOutcome)> {
+        self.abort_conditions.get(name)
+    }
+
+    /// Once an agent executes the named task, it will not be used in new plans for that agent until the cooldown has elapsed
+    pub fn set_cooldown(&mut self, name: impl Into<String>, cooldown: Duration) {
+        self.cooldowns.insert(name.into(), cooldown);
+    }
+
+    pub fn cooldown(&self, name: &String) -> Option<Duration> {
+        self.cooldowns.get(name).copied()
+    }
+
+    pub fn set_duration(&mut self, name: impl Into<String>, duration: Duration) {
+        self.durations.insert(name.into(), duration);
+    }
+
+    pub fn duration(&self, name: &String) -> Option<Duration> {
+        self.durations.get(name).copied()
+    }
+
+    /// How long the task (or each subtask of a macro, in order) is expected to take. The duration of a timed task is used over [`TaskData::expected_duration`], and unregistered tasks take no time
+    pub fn estimated_duration(&self, task: &Task, world: &WorldState) -> Duration {
+        let step_duration = |name: &String, data: &TaskStorage, world: &WorldState| {
+            self.duration(name)
+                .unwrap_or_else(|| data.expected_duration(world))
+        };
+        if let Task::Primitive(name) = task {
+            return self
+                .get_named(name)
+                .map_or(Duration::ZERO, |data| step_duration(name, data, world));
+        }
+        // each subtask runs in the world left by the ones before it
+        let mut world = world.clone();
+        let mut total = Duration::ZERO;
+        for name in task.decompose() {
+            let Some(data) = self.get_named(&name) else {
+                continue;
+            };
+            total += step_duration(&name, data, &world);
+            world = world.concat(&data.postconditions_for(&world));
+        }
+        total
+    }
+
+    /// Sets how likely the named task is to succeed. Clamped so that it is above zero and at most one
+    pub fn set_success_prob(&mut self, name: impl Into<String>, success_prob: f32) {
+        self.success_probs
+            .insert(name.into(), success_prob.clamp(f32::EPSILON, 1.0));
+    }
+
+    /// How likely the task is to succeed, which is one unless set with [`TaskRegistry::set_success_prob`]. A macro only succeeds if all of its subtasks do
+    pub fn success_prob(&self, task: &Task) -> f32 {
+        task.decompose()
+            .iter()
+            .map(|name| self.success_probs.get(name).copied().unwrap_or(1.0))
+            .product()
+    }
+
+    /// Sets the weighted effects the named task can end with, replacing any set before. Weights are relative and need not sum to one.
+    /// Outcomes without a positive weight are dropped, and an empty distribution clears the task's outcomes
+    pub fn set_outcomes(&mut self, name: impl Into<String>, outcomes: Vec<(f32, WorldState)>) {
+        let name = name.into();
+        let outcomes: Vec<_> = outcomes
+            .into_iter()
+            .filter(|(weight, _)| *weight > 0.0)
+            .collect();
+        if outcomes.is_empty() {
+            self.outcomes.remove(&name);
+        } else {
+            self.outcomes.insert(name, outcomes);
+        }
+    }
+
+    /// The highest-weight outcome of the named task, if it has outcomes. Ties go to the outcome listed first
+    pub fn most_likely_outcome(&self, name: &str) -> Option<&WorldState> {
+        self.outcomes
+            .get(name)?
+            .iter()
+            .fold(
+                None,
+                |best: Option<&(f32, WorldState)>, outcome| match best {
+                    Some(best) if best.0 >= outcome.0 => Some(best),
+                    _ => Some(outcome),
+                },
+            )
+            .map(|(_, effect)| effect)
+    }
+
+    /// Edges from task A to task B wherever A's effects satisfy at least one of B's preconditions, sorted by name. A task is never linked to itself.
+    /// Only the registered effects are considered, so effects from [`TaskData::postconditions_for`] or [`MergeStrategy::SumNumbers`](crate::data::MergeStrategy::SumNumbers) may be missed
+    pub fn dependency_graph(&self) -> Vec<(String, String)> {
+        let mut edges = Vec::new();
+        for (from, from_data) in self.tasks.iter() {
+            for (to, to_data) in self.tasks.iter() {
+                let preconditions = to_data.preconditions();
+                if from == to || preconditions.is_empty() {
+                    continue;
+                }
+                let unmet = preconditions.unmet_requirements(from_data.postconditions());
+                if unmet.len() < preconditions.len() {
+                    edges.push((from.clone(), to.clone()));
+                }
+            }
+        }
+        edges.sort();
+        edges
+    }
+
+    /// Finds tasks that can never run, because their preconditions need keys that neither the initial world nor any runnable task provides.
+    /// Only keys are tracked, not values, so a task may still be unplannable without a warning. Effects are the registered postconditions plus any
+    /// [`TaskRegistry::set_outcomes`], so effects only from [`TaskData::postconditions_for`] are missed. Warnings are sorted by task name
+    pub fn audit(&self, initial_world: &WorldState) -> Vec<TaskAuditWarning> {
+        let effects = |name: &String, data: &TaskStorage| -> HashSet<UniqueName> {
+            let outcomes = self.outcomes.get(name).into_iter().flatten();
+            data.postconditions()
+                .keys()
+                .chain(outcomes.flat_map(|(_, effect)| effect.keys()))
+                .cloned()
+                .collect()
+        };
+        let mut available: HashSet<UniqueName> = initial_world.keys().cloned().collect();
+        let mut pending: Vec<_> = self.tasks.iter().collect();
+        // a task runs once all its keys are available, which may make more keys available for others
+        loop {
+            let (runnable, blocked): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|(_, data)| data.preconditions().keys().all(|k| available.contains(k)));
+            pending = blocked;
+            if runnable.is_empty() {
+                break;
+            }
+            for (name, data) in runnable {
+                available.extend(effects(name, data));
+            }
+        }
+
+        let produced: HashSet<UniqueName> = self
+            .tasks
+            .iter()
+            .flat_map(|(name, data)| effects(name, data))
+            .collect();
+        let mut warnings: Vec<_> = pending
+            .into_iter()
+            .map(|(name, data)| {
+                let mut missing: Vec<_> = data
+                    .preconditions()
+                    .keys()
+                    .filter(|k| !available.contains(*k))
+                    .cloned()
+                    .collect();
+                missing.sort_by_key(|k| k.as_str());
+                let task = name.clone();
+                let never_produced: Vec<_> = missing
+                    .iter()
+                    .filter(|k| !produced.contains(*k))
+                    .cloned()
+                    .collect();
+                if never_produced.is_empty() {
+                    TaskAuditWarning::Unreachable {
+                        task,
+                        keys: missing,
+                    }
+                } else {
+                    TaskAuditWarning::MissingKeys {
+                        task,
+                        keys: never_produced,
+                    }
+                }
+            })
+            .collect();
+        warnings.sort_by(|a, b| audited_task(a).cmp(audited_task(b)));
+        warnings
+    }
+
+    /// Adds tasks to the named exclusion group, creating it if needed. A task can belong to several groups
+    pub fn add_exclusion_group<T: Into<String>>(
+        &mut self,
+        group: impl Into<String>,
+        tasks: impl IntoIterator<Item = T>,
+    ) {
+        self.exclusion_groups
+            .entry(group.into())
+            .or_default()
+            .extend(tasks.into_iter().map(Into::into));
+    }
+
+    /// Whether `candidate` (or any subtask of it) shares an exclusion group with a different task in `planned`
+    pub fn excludes(&self, planned: &Task, candidate: &Task) -> bool {
+        if self.exclusion_groups.is_empty() {
+            return false;
+        }
+        let planned = planned.decompose();
+        candidate.decompose().iter().any(|c| {
+            planned.iter().any(|p| {
+                p != c
+                    && self
+                        .exclusion_groups
+                        .values()
+                        .any(|group| group.contains(p) && group.contains(c))
+            })
+        })
+    }
+
+    /// Replaces the tags of the named task
+    pub fn set_tags<T: Into<String>>(
+        &mut self,
+        name: impl Into<String>,
+        tags: impl IntoIterator<Item = T>,
+    ) {
+        self.tags
+            .insert(name.into(), tags.into_iter().map(Into::into).collect());
+    }
+
+    pub fn has_tag(&self, name: &String, tag: &str) -> bool {
+        self.tags.get(name).is_some_and(|tags| tags.contains(tag))
+    }
+
+    /// The total weight of every preferred tag on the task (or each subtask of a macro). A task with several preferred tags gets the weight of each
+    pub fn tag_bias(
+        &self,
+        task: &Task,
+        preferences: &std::collections::HashMap<String, f32>,
+    ) -> f32 {
+        if preferences.is_empty() {
+            return 0.0;
+        }
+        task.decompose()
+            .iter()
+            .filter_map(|name| self.tags.get(name))
+            .flat_map(|tags| tags.iter())
+            .filter_map(|tag| preferences.get(tag))
+            .sum()
+    }
+
+    /// checks the time a task was last executed (if at all) against the registered cooldown for that task. Times are the [`Time`] elapsed since startup
+    pub fn is_on_cooldown(
+        &self,
+        name: &String,
+        last_executed: Option<&Duration>,
+        now: Duration,
+    ) -> bool {
+        let Some(cooldown) = self.cooldown(name) else {
+            return false;
+        };
+        let Some(executed) = last_executed else {
+            return false;
+        };
+        now.saturating_sub(*executed) < cooldown
+    }
+}
+
+fn fill_template(template: &str, params: &[(&str, &str)]) -> String {
+    let mut filled = template.to_string();
+    for (param, value) in params {
+        filled = filled.replace(&format!("{{{param}}}"), value);
     }
+    filled
 }
 
 /// For instances where pre and post conditions are static and the task is accomplished through a default instance of a component, this can be used to make creation of new tasks much easier.
@@ -174,7 +794,7 @@ where
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Task {
     Primitive(String),
     Macro(Vec<Task>, String),
@@ -219,4 +839,320 @@ impl Task {
                 .unwrap_or_default(),
         }
     }
+
+    /// Like [`Task::decompose`], but returns `None` if a macro contains itself (a macro of the same name) at any depth, as such a macro could never be expanded
+    pub fn try_decompose(&self) -> Option<Vec<String>> {
+        self.decompose_guarded(&mut Vec::new())
+    }
+
+    fn decompose_guarded(&self, expanding: &mut Vec<String>) -> Option<Vec<String>> {
+        match self {
+            Task::Primitive(name) => Some(vec![name.clone()]),
+            Task::Macro(m, name) => {
+                if expanding.contains(name) {
+                    return None;
+                }
+                expanding.push(name.clone());
+                let mut tasks = Vec::new();
+                for t in m {
+                    tasks.append(&mut t.decompose_guarded(expanding)?);
+                }
+                expanding.pop();
+                Some(tasks)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Component, Default)]
+    struct TaskStub;
+
+    #[test]
+    fn templated_tasks() {
+        let mut registry = TaskRegistry::new();
+        for item in ["sword", "shield"] {
+            registry.task_templated::<TaskStub>(
+                "pickup_{item}",
+                &[("item", item)],
+                Requirements::new()
+                    .req_equals("carrying_{item}", false)
+                    .req_equals("room", "armory")
+                    .build(),
+                WorldState::new().add("carrying_{item}", true).build(),
+                1.,
+            );
+        }
+        assert_eq!(registry.tasks.len(), 2);
+
+        let sword = registry.get_named(&"pickup_sword".to_string()).unwrap();
+        let shield = registry.get_named(&"pickup_shield".to_string()).unwrap();
+        assert_eq!(
+            sword.postconditions().get("carrying_sword"),
+            Some(true.into())
+        );
+        assert_eq!(sword.postconditions().get("carrying_shield"), None);
+        assert_eq!(
+            shield.postconditions().get("carrying_shield"),
+            Some(true.into())
+        );
+
+        let world = WorldState::new()
+            .add("room", "armory")
+            .add("carrying_sword", true)
+            .add("carrying_shield", false)
+            .build();
+        assert!(!sword.preconditions().validate(&world));
+        assert!(shield.preconditions().validate(&world));
+
+        // runtime names are interned like any other
+        assert_eq!(
+            UniqueName::from_string("carrying_sword".to_string()),
+            UniqueName::new("carrying_sword")
+        );
+    }
+
+    #[test]
+    fn self_referential_macro() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "step",
+            Requirements::new(),
+            WorldState::new().add("walked", true).build(),
+            1.,
+        );
+        let walk = Task::macro_(
+            [Task::primitive("step"), Task::primitive("step")].into_iter(),
+            "walk".into(),
+        );
+        // a macro built from a copy of itself, as happens when macros are assembled by name from data
+        let looping = Task::macro_(
+            [Task::primitive("step"), walk.clone()].into_iter(),
+            "walk".into(),
+        );
+        let nested = Task::macro_([walk.clone(), walk.clone()].into_iter(), "patrol".into());
+
+        assert_eq!(
+            walk.try_decompose(),
+            Some(vec!["step".into(), "step".into()])
+        );
+        assert_eq!(nested.try_decompose().map(|t| t.len()), Some(4));
+        assert_eq!(looping.try_decompose(), None);
+
+        assert!(registry.precon(&looping).is_none());
+        assert!(registry.postcon(&looping).is_none());
+        assert!(registry.simulate(&looping, &WorldState::new()).is_none());
+        assert!(registry.simulate(&nested, &WorldState::new()).is_some());
+    }
+
+    #[test]
+    fn audit_flags_unreachable_tasks() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        // nothing ever sets "has_key"
+        registry.task::<TaskStub, _>(
+            "unlock_chest",
+            Requirements::new()
+                .req_equals("room", "B")
+                .req_has("has_key")
+                .build(),
+            WorldState::new().add("chest_open", true).build(),
+            1.,
+        );
+        // "chest_open" is produced, but only by a task that can never run
+        registry.task::<TaskStub, _>(
+            "loot_chest",
+            Requirements::new().req_equals("chest_open", true).build(),
+            WorldState::new().add("gold", 10.).build(),
+            1.,
+        );
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .build();
+
+        let warnings = registry.audit(&world);
+        assert_eq!(
+            warnings,
+            vec![
+                TaskAuditWarning::Unreachable {
+                    task: "loot_chest".into(),
+                    keys: vec!["chest_open".into()],
+                },
+                TaskAuditWarning::MissingKeys {
+                    task: "unlock_chest".into(),
+                    keys: vec!["has_key".into()],
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "Task 'unlock_chest' requires [\"has_key\"], which are never produced"
+        );
+
+        // once the world has the key, the chain is reachable
+        let world = world.concat(&WorldState::new().add("has_key", true).build());
+        assert!(registry.audit(&world).is_empty());
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let mut registry = TaskRegistry::new();
+        for (name, from_open, to_open) in [("open_door", false, true), ("close_door", true, false)]
+        {
+            registry.task::<TaskStub, _>(
+                name,
+                Requirements::new()
+                    .req_equals("door_open", from_open)
+                    .build(),
+                WorldState::new().add("door_open", to_open).build(),
+                1.,
+            );
+        }
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "pickup_item",
+            Requirements::new()
+                .req_equals("room", "B")
+                .req_equals("item_picked_up", false)
+                .build(),
+            WorldState::new().add("item_picked_up", true).build(),
+            1.,
+        );
+
+        // opening the door only covers part of what goto_b needs, which is still a dependency
+        assert_eq!(
+            registry.dependency_graph(),
+            [
+                ("close_door", "open_door"),
+                ("goto_b", "pickup_item"),
+                ("open_door", "close_door"),
+                ("open_door", "goto_b"),
+            ]
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+        );
+    }
+
+    #[test]
+    fn remove_target() {
+        let (kept, gone) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut registry = TaskRegistry::new();
+        registry.targeted_task::<TaskStub>(
+            "attack",
+            "enemies",
+            [kept, gone],
+            Requirements::new()
+                .req_equals("entity_{target}_dead", false)
+                .build(),
+            WorldState::new().add("entity_{target}_dead", true).build(),
+            |_| 1.,
+        );
+        let instance = format!("attack_{gone}");
+        registry.set_cooldown(instance.clone(), Duration::from_secs(1));
+
+        assert!(registry.remove_target("attack", gone));
+        assert!(!registry.remove_target("attack", gone));
+        assert!(registry.get_named(&instance).is_none());
+        assert_eq!(registry.target_of(&instance), None);
+        assert_eq!(registry.cooldown(&instance), None);
+        let candidates = TargetCandidates::from([("enemies".to_string(), vec![kept, gone])]);
+        let attack = Task::primitive("attack");
+        assert_eq!(
+            registry
+                .expand_targeted(&attack, &candidates)
+                .collect::<Vec<_>>(),
+            vec![&Task::primitive(format!("attack_{kept}"))]
+        );
+    }
+
+    #[test]
+    fn applicable_tasks() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>(
+            "open_door",
+            Requirements::new().req_equals("door_open", false).build(),
+            WorldState::new().add("door_open", true).build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "goto_b",
+            Requirements::new()
+                .req_equals("room", "A")
+                .req_equals("door_open", true)
+                .build(),
+            WorldState::new().add("room", "B").build(),
+            1.,
+        );
+        registry.task::<TaskStub, _>(
+            "pickup_item",
+            Requirements::new()
+                .req_equals("room", "B")
+                .req_equals("item_picked_up", false)
+                .build(),
+            WorldState::new().add("item_picked_up", true).build(),
+            1.,
+        );
+        let tasks = ["open_door", "goto_b", "pickup_item", "unregistered"].map(Task::primitive);
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", false)
+            .add("item_picked_up", false)
+            .build();
+
+        assert_eq!(
+            registry.applicable_tasks(&tasks, &world, &TargetCandidates::new()),
+            vec![Task::primitive("open_door")]
+        );
+        let world = WorldState::new()
+            .add("room", "A")
+            .add("door_open", true)
+            .add("item_picked_up", false)
+            .build();
+        assert_eq!(
+            registry.applicable_tasks(&tasks, &world, &TargetCandidates::new()),
+            vec![Task::primitive("goto_b")]
+        );
+    }
+
+    #[test]
+    fn task_lookup_errors() {
+        let mut registry = TaskRegistry::new();
+        registry.task::<TaskStub, _>("eat", Requirements::new(), WorldState::new(), 1.);
+
+        assert!(registry.get_task_result(&Task::primitive("eat")).is_ok());
+        assert_eq!(
+            registry.get_task_result(&Task::primitive("sleep")).err(),
+            Some(TaskLookupError::NotFound("sleep".to_string()))
+        );
+        let meal = Task::macro_([Task::primitive("eat")].into_iter(), "meal".into());
+        assert_eq!(
+            registry.get_task_result(&meal).err(),
+            Some(TaskLookupError::NotPrimitive)
+        );
+    }
 }